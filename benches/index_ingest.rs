@@ -0,0 +1,83 @@
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use lab::app::btree::compare::Natural;
+use lab::app::btree::BTree;
+use lab::app::db::record_index::IndexEngine;
+use lab::app::hash::LinearHash;
+use lab::app::lsm::Lsm;
+use rand::prelude::*;
+
+/// As many as "Generate data" appends.
+const RECORDS: usize = 125_000;
+const LOOKUPS: usize = 20_000;
+/// Distinct keys, so most of them are shared by a few records.
+const KEYS: u64 = 40_000;
+
+fn measure<F: FnMut()>(mut f: F) -> Duration {
+    let start = Instant::now();
+    f();
+    start.elapsed()
+}
+
+fn report(what: &str, ops: usize, elapsed: Duration) {
+    println!(
+        "{:<24} {:>10.1} ns/op  ({:?} total)",
+        what,
+        elapsed.as_nanos() as f64 / ops as f64,
+        elapsed
+    );
+}
+
+fn ingest<I: IndexEngine<u64>>(engine: &mut I, keys: &[u64]) {
+    for (pos, &key) in keys.iter().enumerate() {
+        engine.insert(key, pos as u64).unwrap();
+    }
+}
+
+fn lookup<I: IndexEngine<u64>>(engine: &I, probes: &[u64]) {
+    for &key in probes {
+        let _ = black_box(engine.positions(key));
+    }
+}
+
+fn compare<I: IndexEngine<u64>>(name: &str, mut engine: I, keys: &[u64], probes: &[u64]) {
+    let elapsed = measure(|| ingest(&mut engine, keys));
+    report(&format!("ingest / {}", name), keys.len(), elapsed);
+    let elapsed = measure(|| lookup(&engine, probes));
+    report(&format!("lookup / {}", name), probes.len(), elapsed);
+}
+
+fn main() {
+    let mut rng = StdRng::seed_from_u64(200);
+    let keys: Vec<u64> = (0..RECORDS).map(|_| rng.gen_range(0..KEYS)).collect();
+    let probes: Vec<u64> = (0..LOOKUPS).map(|_| rng.gen_range(0..KEYS)).collect();
+    let dir = std::env::temp_dir().join("index_ingest_bench");
+    let _ = std::fs::remove_dir_all(&dir);
+
+    println!("{} records, {} lookups", RECORDS, LOOKUPS);
+
+    let tree: BTree<u64, Vec<u64>, Natural> = IndexEngine::create().unwrap();
+    compare("BTree", tree, &keys, &probes);
+    let hash: LinearHash<u64, Vec<u64>> = IndexEngine::create().unwrap();
+    compare("LinearHash", hash, &keys, &probes);
+
+    let mut lsm: Lsm<(u64, u64), ()> = Lsm::with(&dir, 16 * 1024).unwrap();
+    let elapsed = measure(|| ingest(&mut lsm, &keys));
+    report("ingest / LSM", RECORDS, elapsed);
+    let elapsed = measure(|| {
+        lsm.flush().unwrap();
+        lsm.wait().unwrap();
+    });
+    println!(
+        "{:<24} {:?} ({} runs left)",
+        "flush and compaction / LSM",
+        elapsed,
+        lsm.runs()
+    );
+    let elapsed = measure(|| lookup(&lsm, &probes));
+    report("lookup / LSM", LOOKUPS, elapsed);
+
+    drop(lsm);
+    let _ = std::fs::remove_dir_all(&dir);
+}
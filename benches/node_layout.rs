@@ -0,0 +1,85 @@
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use lab::app::btree::arena::ArenaBTree;
+use lab::app::btree::compare::Natural;
+use lab::app::btree::fixed::ConstBTree;
+use lab::app::btree::BTree;
+use lab::app::db::DEGREE_OF_TREE;
+use rand::prelude::*;
+
+const RECORDS: usize = 20_000;
+const LOOKUPS: usize = 200_000;
+
+fn measure<F: FnMut()>(mut f: F) -> Duration {
+    let start = Instant::now();
+    f();
+    start.elapsed()
+}
+
+fn report(what: &str, ops: usize, elapsed: Duration) {
+    println!(
+        "{:<24} {:>10.1} ns/op  ({:?} total)",
+        what,
+        elapsed.as_nanos() as f64 / ops as f64,
+        elapsed
+    );
+}
+
+fn main() {
+    let mut rng = StdRng::seed_from_u64(200);
+    let keys: Vec<u64> = (0..RECORDS).map(|_| rng.gen()).collect();
+    let probes: Vec<u64> = (0..LOOKUPS)
+        .map(|_| keys[rng.gen_range(0..RECORDS)])
+        .collect();
+
+    println!(
+        "t = {}, {} records, {} lookups",
+        DEGREE_OF_TREE, RECORDS, LOOKUPS
+    );
+
+    let mut boxed: BTree<u64, u64, Natural> = BTree::with(DEGREE_OF_TREE).unwrap();
+    let elapsed = measure(|| {
+        for &key in &keys {
+            let _ = boxed.insert(key, key);
+        }
+    });
+    report("insert / Vec<Node>", RECORDS, elapsed);
+
+    let mut arena: ArenaBTree<u64, u64, Natural> = ArenaBTree::with(DEGREE_OF_TREE).unwrap();
+    let elapsed = measure(|| {
+        for &key in &keys {
+            let _ = arena.insert(key, key);
+        }
+    });
+    report("insert / arena", RECORDS, elapsed);
+
+    let mut fixed: ConstBTree<u64, u64, Natural, DEGREE_OF_TREE> = ConstBTree::new();
+    let elapsed = measure(|| {
+        for &key in &keys {
+            let _ = fixed.insert(key, key);
+        }
+    });
+    report("insert / const degree", RECORDS, elapsed);
+
+    let elapsed = measure(|| {
+        for &key in &probes {
+            black_box(boxed.search(key).unwrap());
+        }
+    });
+    report("search / Vec<Node>", LOOKUPS, elapsed);
+
+    let elapsed = measure(|| {
+        for &key in &probes {
+            black_box(arena.search(key).unwrap());
+        }
+    });
+    report("search / arena", LOOKUPS, elapsed);
+
+    let elapsed = measure(|| {
+        for &key in &probes {
+            black_box(fixed.search(key).unwrap());
+        }
+    });
+    report("search / const degree", LOOKUPS, elapsed);
+}
@@ -0,0 +1,46 @@
+use std::hint::black_box;
+use std::time::Instant;
+
+use lab::app::btree::compare::Natural;
+use lab::app::btree::search::{Binary, Linear, NodeSearch, Packed};
+use lab::app::btree::BTree;
+use rand::prelude::*;
+
+const RECORDS: usize = 10_000;
+const LOOKUPS: usize = 500_000;
+const DEGREES: [usize; 4] = [2, 8, 32, 200];
+
+fn bench<S: NodeSearch<u64>>(name: &str, t: usize, keys: &[u64], probes: &[u64]) {
+    let mut tree: BTree<u64, u64, Natural, S> = BTree::with(t).unwrap();
+    for &key in keys {
+        let _ = tree.insert(key, key);
+    }
+
+    let start = Instant::now();
+    for &key in probes {
+        black_box(tree.search(key).unwrap());
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "t = {:<4} {:<8} {:>8.1} ns/search",
+        t,
+        name,
+        elapsed.as_nanos() as f64 / probes.len() as f64
+    );
+}
+
+fn main() {
+    let mut rng = StdRng::seed_from_u64(29);
+    let mut keys: Vec<u64> = (0..RECORDS as u64 * 3).step_by(3).collect();
+    keys.shuffle(&mut rng);
+    let probes: Vec<u64> = (0..LOOKUPS)
+        .map(|_| keys[rng.gen_range(0..RECORDS)])
+        .collect();
+
+    for t in DEGREES {
+        bench::<Binary>("binary", t, &keys, &probes);
+        bench::<Linear>("linear", t, &keys, &probes);
+        bench::<Packed>("packed", t, &keys, &probes);
+    }
+}
@@ -0,0 +1,735 @@
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+use crate::app::btree::key_value::{Comparator, KeyValue};
+use crate::app::btree::{after_start, before_end};
+use crate::Error;
+
+/// Monoid over the entries of a tree, combined in key order: `combine` must
+/// be associative with `empty` as its identity.
+pub trait Aggregate<K, V> {
+    type Output: Clone;
+
+    fn empty() -> Self::Output;
+    fn lift(key: &K, value: &V) -> Self::Output;
+    fn combine(lhs: &Self::Output, rhs: &Self::Output) -> Self::Output;
+}
+
+/// Extracts the quantity an aggregate works on from an entry.
+pub trait Projection<K, V> {
+    type Output;
+
+    fn project(key: &K, value: &V) -> Self::Output;
+}
+
+/// Number of entries.
+#[derive(Debug, Clone, Copy)]
+pub struct Count;
+
+impl<K, V> Aggregate<K, V> for Count {
+    type Output = usize;
+
+    fn empty() -> usize {
+        0
+    }
+
+    fn lift(_key: &K, _value: &V) -> usize {
+        1
+    }
+
+    fn combine(lhs: &usize, rhs: &usize) -> usize {
+        lhs + rhs
+    }
+}
+
+/// Sum of a projection.
+#[derive(Debug, Clone, Copy)]
+pub struct Sum<P>(PhantomData<P>);
+
+impl<K, V, P> Aggregate<K, V> for Sum<P>
+where
+    P: Projection<K, V>,
+    P::Output: Clone + Default + std::ops::Add<Output = P::Output>,
+{
+    type Output = P::Output;
+
+    fn empty() -> Self::Output {
+        P::Output::default()
+    }
+
+    fn lift(key: &K, value: &V) -> Self::Output {
+        P::project(key, value)
+    }
+
+    fn combine(lhs: &Self::Output, rhs: &Self::Output) -> Self::Output {
+        lhs.clone() + rhs.clone()
+    }
+}
+
+/// Smallest value of a projection, `None` over an empty range.
+#[derive(Debug, Clone, Copy)]
+pub struct Min<P>(PhantomData<P>);
+
+impl<K, V, P> Aggregate<K, V> for Min<P>
+where
+    P: Projection<K, V>,
+    P::Output: Clone + Ord,
+{
+    type Output = Option<P::Output>;
+
+    fn empty() -> Self::Output {
+        None
+    }
+
+    fn lift(key: &K, value: &V) -> Self::Output {
+        Some(P::project(key, value))
+    }
+
+    fn combine(lhs: &Self::Output, rhs: &Self::Output) -> Self::Output {
+        match (lhs, rhs) {
+            (Some(lhs), Some(rhs)) => Some(std::cmp::min(lhs, rhs).clone()),
+            (Some(only), None) | (None, Some(only)) => Some(only.clone()),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Largest value of a projection, `None` over an empty range.
+#[derive(Debug, Clone, Copy)]
+pub struct Max<P>(PhantomData<P>);
+
+impl<K, V, P> Aggregate<K, V> for Max<P>
+where
+    P: Projection<K, V>,
+    P::Output: Clone + Ord,
+{
+    type Output = Option<P::Output>;
+
+    fn empty() -> Self::Output {
+        None
+    }
+
+    fn lift(key: &K, value: &V) -> Self::Output {
+        Some(P::project(key, value))
+    }
+
+    fn combine(lhs: &Self::Output, rhs: &Self::Output) -> Self::Output {
+        match (lhs, rhs) {
+            (Some(lhs), Some(rhs)) => Some(std::cmp::max(lhs, rhs).clone()),
+            (Some(only), None) | (None, Some(only)) => Some(only.clone()),
+            (None, None) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct AggNode<K: Ord, V, S> {
+    pairs: Vec<KeyValue<K, V>>,
+    children: Vec<AggNode<K, V, S>>,
+    summary: S,
+}
+
+impl<K: Ord, V, S> AggNode<K, V, S> {
+    fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+
+    fn is_full(&self, t: usize) -> bool {
+        self.pairs.len() >= 2 * t - 1
+    }
+}
+
+/// B-tree where every node caches the aggregate `A` of its whole subtree, so
+/// that `aggregate(range)` only walks the two boundary paths of the range.
+#[derive(Debug, Clone)]
+pub struct AggregatedBTree<K, V, C, A>
+where
+    K: Ord,
+    C: Comparator<K>,
+    A: Aggregate<K, V>,
+{
+    root: Option<AggNode<K, V, A::Output>>,
+    t: usize,
+    cmp: PhantomData<C>,
+    agg: PhantomData<A>,
+}
+
+#[allow(dead_code)]
+impl<K, V, C, A> AggregatedBTree<K, V, C, A>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    C: Comparator<K>,
+    A: Aggregate<K, V>,
+{
+    pub fn new() -> Self {
+        AggregatedBTree {
+            root: None,
+            t: 2,
+            cmp: PhantomData,
+            agg: PhantomData,
+        }
+    }
+
+    pub fn with(t: usize) -> Option<Self> {
+        if t < 2 {
+            return None;
+        }
+
+        Some(AggregatedBTree {
+            root: None,
+            t,
+            cmp: PhantomData,
+            agg: PhantomData,
+        })
+    }
+
+    pub fn search(&self, key: K) -> Result<&V, Error> {
+        let mut node = self.root.as_ref().ok_or(Error::KeyWasNotFound)?;
+        loop {
+            match node.pairs.binary_search_by(|k| C::compare(&k.key, &key)) {
+                Ok(index) => return Ok(&node.pairs[index].value),
+                Err(_) if node.is_leaf() => return Err(Error::KeyWasNotFound),
+                Err(index) => node = &node.children[index],
+            }
+        }
+    }
+
+    pub fn contains(&self, key: K) -> bool {
+        self.search(key).is_ok()
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Result<(), Error> {
+        let t = self.t;
+        let mut root = match self.root.take() {
+            Some(root) => root,
+            None => {
+                self.root = Some(Self::leaf(vec![(key, value).into()]));
+                return Ok(());
+            }
+        };
+
+        if root.is_full(t) {
+            let mut new_root = AggNode {
+                pairs: vec![],
+                children: vec![root],
+                summary: A::empty(),
+            };
+            Self::split_child(&mut new_root, 0, t);
+            Self::recompute(&mut new_root);
+            root = new_root;
+        }
+
+        let result = Self::insert_non_full(&mut root, key, value, t);
+        self.root = Some(root);
+        result
+    }
+
+    pub fn remove(&mut self, key: K) -> Result<V, Error> {
+        let mut root = self.root.take().ok_or(Error::KeyWasNotFound)?;
+        let result = Self::remove_node(&mut root, key, self.t);
+        self.root = match root.pairs.is_empty() {
+            true => root.children.pop(),
+            false => Some(root),
+        };
+        result
+    }
+
+    /// Mutates the value stored under `key` in place and refreshes the
+    /// aggregates on the path to it.
+    pub fn update<F>(&mut self, key: K, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut V),
+    {
+        let root = self.root.as_mut().ok_or(Error::KeyWasNotFound)?;
+        Self::update_node(root, key, f)
+    }
+
+    /// Aggregate of the whole tree.
+    pub fn total(&self) -> A::Output {
+        match self.root {
+            Some(ref root) => root.summary.clone(),
+            None => A::empty(),
+        }
+    }
+
+    /// Aggregate of every entry whose key lies in `range`.
+    pub fn aggregate<R>(&self, range: R) -> A::Output
+    where
+        R: RangeBounds<K>,
+    {
+        match self.root {
+            Some(ref root) => Self::aggregate_node(root, &range, false, false),
+            None => A::empty(),
+        }
+    }
+
+    /// Aggregates of consecutive buckets `[edges[i], edges[i + 1])`.
+    pub fn histogram(&self, edges: &[K]) -> Vec<A::Output> {
+        edges
+            .windows(2)
+            .map(|bucket| self.aggregate(bucket[0]..bucket[1]))
+            .collect()
+    }
+
+    /// Visits, in key order, the entries of every subtree whose cached aggregate
+    /// passes `enter`.
+    pub fn visit_pruned<P, F>(&self, enter: P, mut visit: F)
+    where
+        P: Fn(&A::Output) -> bool,
+        F: FnMut(&K, &V),
+    {
+        if let Some(ref root) = self.root {
+            Self::visit_node(root, &enter, &mut visit);
+        }
+    }
+
+    /// Calls `visit` on every entry whose key lies in `range`, in key order.
+    pub fn visit_range<R, F>(&self, range: R, mut visit: F)
+    where
+        R: RangeBounds<K>,
+        F: FnMut(&K, &V),
+    {
+        if let Some(ref root) = self.root {
+            Self::visit_range_node(root, &range, &mut visit);
+        }
+    }
+
+    /// Key ranges over which `self` and `other` aggregate differently, found by
+    /// entering only subtrees whose cached aggregates disagree.
+    pub fn mismatches(&self, other: &Self) -> Vec<(Bound<K>, Bound<K>)>
+    where
+        A::Output: PartialEq,
+    {
+        let mut found = vec![];
+        match self.root {
+            Some(ref root) => Self::mismatch_node(
+                root,
+                (Bound::Unbounded, Bound::Unbounded),
+                other,
+                &mut found,
+            ),
+            None if other.total() != A::empty() => found.push((Bound::Unbounded, Bound::Unbounded)),
+            None => {}
+        }
+        found
+    }
+
+    fn visit_node<P, F>(node: &AggNode<K, V, A::Output>, enter: &P, visit: &mut F)
+    where
+        P: Fn(&A::Output) -> bool,
+        F: FnMut(&K, &V),
+    {
+        if !enter(&node.summary) {
+            return;
+        }
+
+        for (i, pair) in node.pairs.iter().enumerate() {
+            if let Some(child) = node.children.get(i) {
+                Self::visit_node(child, enter, visit);
+            }
+            visit(&pair.key, &pair.value);
+        }
+        if let Some(child) = node.children.get(node.pairs.len()) {
+            Self::visit_node(child, enter, visit);
+        }
+    }
+
+    fn visit_range_node<R, F>(node: &AggNode<K, V, A::Output>, range: &R, visit: &mut F)
+    where
+        R: RangeBounds<K>,
+        F: FnMut(&K, &V),
+    {
+        for i in 0..=node.pairs.len() {
+            let lower = i.checked_sub(1).map(|at| &node.pairs[at].key);
+            let upper = node.pairs.get(i).map(|pair| &pair.key);
+
+            if let Some(child) = node.children.get(i) {
+                let before_start = upper.is_some_and(|key| !after_start::<K, C, _>(range, key));
+                let past_end = lower.is_some_and(|key| !before_end::<K, C, _>(range, key));
+                if !before_start && !past_end {
+                    Self::visit_range_node(child, range, visit);
+                }
+            }
+
+            if let Some(pair) = node.pairs.get(i) {
+                if after_start::<K, C, _>(range, &pair.key)
+                    && before_end::<K, C, _>(range, &pair.key)
+                {
+                    visit(&pair.key, &pair.value);
+                }
+            }
+        }
+    }
+
+    /// `bounds` are the keys the subtree at `node` lies strictly between.
+    fn mismatch_node(
+        node: &AggNode<K, V, A::Output>,
+        bounds: (Bound<K>, Bound<K>),
+        other: &Self,
+        found: &mut Vec<(Bound<K>, Bound<K>)>,
+    ) where
+        A::Output: PartialEq,
+    {
+        if node.summary == other.aggregate(bounds) {
+            return;
+        }
+        if node.is_leaf() {
+            found.push(bounds);
+            return;
+        }
+
+        for i in 0..=node.pairs.len() {
+            let lower = match i.checked_sub(1) {
+                Some(at) => Bound::Excluded(node.pairs[at].key),
+                None => bounds.0,
+            };
+            let upper = match node.pairs.get(i) {
+                Some(pair) => Bound::Excluded(pair.key),
+                None => bounds.1,
+            };
+            Self::mismatch_node(&node.children[i], (lower, upper), other, found);
+
+            if let Some(pair) = node.pairs.get(i) {
+                let key = Bound::Included(pair.key);
+                if A::lift(&pair.key, &pair.value) != other.aggregate((key, key)) {
+                    found.push((key, key));
+                }
+            }
+        }
+    }
+
+    fn leaf(pairs: Vec<KeyValue<K, V>>) -> AggNode<K, V, A::Output> {
+        let mut node = AggNode {
+            pairs,
+            children: vec![],
+            summary: A::empty(),
+        };
+        Self::recompute(&mut node);
+        node
+    }
+
+    fn recompute(node: &mut AggNode<K, V, A::Output>) {
+        let mut summary = A::empty();
+        for (i, pair) in node.pairs.iter().enumerate() {
+            if let Some(child) = node.children.get(i) {
+                summary = A::combine(&summary, &child.summary);
+            }
+            summary = A::combine(&summary, &A::lift(&pair.key, &pair.value));
+        }
+        if let Some(child) = node.children.get(node.pairs.len()) {
+            summary = A::combine(&summary, &child.summary);
+        }
+        node.summary = summary;
+    }
+
+    fn split_child(parent: &mut AggNode<K, V, A::Output>, index: usize, t: usize) {
+        let child = &mut parent.children[index];
+        let mut sibling_pairs = child.pairs.split_off(t - 1);
+        let median = sibling_pairs.remove(0);
+        let sibling_children = if child.is_leaf() {
+            vec![]
+        } else {
+            child.children.split_off(t)
+        };
+        Self::recompute(child);
+
+        let mut sibling = AggNode {
+            pairs: sibling_pairs,
+            children: sibling_children,
+            summary: A::empty(),
+        };
+        Self::recompute(&mut sibling);
+
+        parent.pairs.insert(index, median);
+        parent.children.insert(index + 1, sibling);
+    }
+
+    fn insert_non_full(
+        node: &mut AggNode<K, V, A::Output>,
+        key: K,
+        value: V,
+        t: usize,
+    ) -> Result<(), Error> {
+        let mut index = match node.pairs.binary_search_by(|k| C::compare(&k.key, &key)) {
+            Ok(_) => return Err(Error::KeyAlreadyExists),
+            Err(index) => index,
+        };
+
+        if node.is_leaf() {
+            node.pairs.insert(index, (key, value).into());
+            Self::recompute(node);
+            return Ok(());
+        }
+
+        if node.children[index].is_full(t) {
+            Self::split_child(node, index, t);
+            match C::compare(&key, &node.pairs[index].key) {
+                Ordering::Equal => return Err(Error::KeyAlreadyExists),
+                Ordering::Greater => index += 1,
+                Ordering::Less => {}
+            }
+        }
+
+        let result = Self::insert_non_full(&mut node.children[index], key, value, t);
+        Self::recompute(node);
+        result
+    }
+
+    fn remove_node(node: &mut AggNode<K, V, A::Output>, key: K, t: usize) -> Result<V, Error> {
+        let result = match node.pairs.binary_search_by(|k| C::compare(&k.key, &key)) {
+            Ok(index) if node.is_leaf() => Ok(node.pairs.remove(index).value),
+            Err(_) if node.is_leaf() => return Err(Error::KeyWasNotFound),
+            Ok(index) if node.children[index].pairs.len() >= t => {
+                let pair = Self::remove_edge(&mut node.children[index], t, true);
+                Ok(std::mem::replace(&mut node.pairs[index], pair).value)
+            }
+            Ok(index) if node.children[index + 1].pairs.len() >= t => {
+                let pair = Self::remove_edge(&mut node.children[index + 1], t, false);
+                Ok(std::mem::replace(&mut node.pairs[index], pair).value)
+            }
+            Ok(index) => {
+                Self::merge_children(node, index);
+                Self::remove_node(&mut node.children[index], key, t)
+            }
+            Err(index) => {
+                let index = Self::fill_child(node, index, t);
+                Self::remove_node(&mut node.children[index], key, t)
+            }
+        };
+        Self::recompute(node);
+        result
+    }
+
+    /// Removes the largest (`last`) or smallest pair of a subtree holding at
+    /// least `t` pairs.
+    fn remove_edge(node: &mut AggNode<K, V, A::Output>, t: usize, last: bool) -> KeyValue<K, V> {
+        let pair = match (node.is_leaf(), last) {
+            (true, true) => node.pairs.pop().expect("the node holds t pairs"),
+            (true, false) => node.pairs.remove(0),
+            (false, _) => {
+                let index = if last { node.children.len() - 1 } else { 0 };
+                let index = Self::fill_child(node, index, t);
+                Self::remove_edge(&mut node.children[index], t, last)
+            }
+        };
+        Self::recompute(node);
+        pair
+    }
+
+    /// Makes sure `children[index]` holds at least `t` pairs before descending.
+    /// Returns where the child ends up.
+    fn fill_child(node: &mut AggNode<K, V, A::Output>, index: usize, t: usize) -> usize {
+        if node.children[index].pairs.len() >= t {
+            return index;
+        }
+
+        if index > 0 && node.children[index - 1].pairs.len() >= t {
+            let (left, right) = node.children.split_at_mut(index);
+            let (left, child) = (&mut left[index - 1], &mut right[0]);
+            let pair = left.pairs.pop().expect("the sibling holds t pairs");
+            let separator = std::mem::replace(&mut node.pairs[index - 1], pair);
+            child.pairs.insert(0, separator);
+            if let Some(grandchild) = left.children.pop() {
+                child.children.insert(0, grandchild);
+            }
+            Self::recompute(left);
+            Self::recompute(child);
+            return index;
+        }
+
+        if index + 1 < node.children.len() && node.children[index + 1].pairs.len() >= t {
+            let (left, right) = node.children.split_at_mut(index + 1);
+            let (child, right) = (&mut left[index], &mut right[0]);
+            let pair = right.pairs.remove(0);
+            let separator = std::mem::replace(&mut node.pairs[index], pair);
+            child.pairs.push(separator);
+            if !right.is_leaf() {
+                child.children.push(right.children.remove(0));
+            }
+            Self::recompute(child);
+            Self::recompute(right);
+            return index;
+        }
+
+        let index = if index + 1 < node.children.len() {
+            index
+        } else {
+            index - 1
+        };
+        Self::merge_children(node, index);
+        index
+    }
+
+    /// Moves `pairs[index]` and all of `children[index + 1]` into
+    /// `children[index]`.
+    fn merge_children(node: &mut AggNode<K, V, A::Output>, index: usize) {
+        let right = node.children.remove(index + 1);
+        let separator = node.pairs.remove(index);
+        let left = &mut node.children[index];
+        left.pairs.push(separator);
+        left.pairs.extend(right.pairs);
+        left.children.extend(right.children);
+        Self::recompute(left);
+    }
+
+    fn update_node<F>(node: &mut AggNode<K, V, A::Output>, key: K, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut V),
+    {
+        match node.pairs.binary_search_by(|k| C::compare(&k.key, &key)) {
+            Ok(index) => f(&mut node.pairs[index].value),
+            Err(_) if node.is_leaf() => return Err(Error::KeyWasNotFound),
+            Err(index) => Self::update_node(&mut node.children[index], key, f)?,
+        }
+        Self::recompute(node);
+        Ok(())
+    }
+
+    fn aggregate_node<R>(
+        node: &AggNode<K, V, A::Output>,
+        range: &R,
+        left_free: bool,
+        right_free: bool,
+    ) -> A::Output
+    where
+        R: RangeBounds<K>,
+    {
+        if left_free && right_free {
+            return node.summary.clone();
+        }
+
+        let mut summary = A::empty();
+        for i in 0..=node.pairs.len() {
+            let lower = i.checked_sub(1).map(|at| &node.pairs[at].key);
+            let upper = node.pairs.get(i).map(|pair| &pair.key);
+
+            if let Some(child) = node.children.get(i) {
+                // Every key in the child lies strictly between `lower` and `upper`.
+                let before_start = upper.is_some_and(|key| !after_start::<K, C, _>(range, key));
+                let past_end = lower.is_some_and(|key| !before_end::<K, C, _>(range, key));
+                if !before_start && !past_end {
+                    let child_summary = Self::aggregate_node(
+                        child,
+                        range,
+                        lower.map_or(left_free, |key| after_start::<K, C, _>(range, key)),
+                        upper.map_or(right_free, |key| before_end::<K, C, _>(range, key)),
+                    );
+                    summary = A::combine(&summary, &child_summary);
+                }
+            }
+
+            if let Some(pair) = node.pairs.get(i) {
+                if after_start::<K, C, _>(range, &pair.key)
+                    && before_end::<K, C, _>(range, &pair.key)
+                {
+                    summary = A::combine(&summary, &A::lift(&pair.key, &pair.value));
+                }
+            }
+        }
+        summary
+    }
+}
+
+impl<K, V, C, A> Default for AggregatedBTree<K, V, C, A>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    C: Comparator<K>,
+    A: Aggregate<K, V>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::btree::compare::Natural;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::collections::BTreeMap;
+
+    struct Value;
+
+    impl Projection<u32, u64> for Value {
+        type Output = u64;
+
+        fn project(_key: &u32, value: &u64) -> u64 {
+            *value
+        }
+    }
+
+    type SumTree = AggregatedBTree<u32, u64, Natural, Sum<Value>>;
+
+    /// Checks every cached summary and the fill of every node below the root.
+    fn check(node: &AggNode<u32, u64, u64>, t: usize, is_root: bool) -> u64 {
+        assert!(node.pairs.len() < 2 * t);
+        assert!(is_root || node.pairs.len() >= t - 1);
+        assert!(node.is_leaf() || node.children.len() == node.pairs.len() + 1);
+        let below: u64 = node
+            .children
+            .iter()
+            .map(|child| check(child, t, false))
+            .sum();
+        let sum = below + node.pairs.iter().map(|pair| pair.value).sum::<u64>();
+        assert_eq!(node.summary, sum);
+        sum
+    }
+
+    #[test]
+    fn aggregates_follow_inserts_and_removes() {
+        for t in [2, 3, 4] {
+            let mut rng = StdRng::seed_from_u64(t as u64);
+            let mut tree = SumTree::with(t).unwrap();
+            let mut model = BTreeMap::new();
+            for _ in 0..3_000 {
+                let key = rng.gen_range(0..500);
+                if rng.gen_bool(0.4) {
+                    assert_eq!(tree.remove(key).ok(), model.remove(&key));
+                } else {
+                    let value = rng.gen_range(0..1_000);
+                    assert_eq!(tree.insert(key, value).is_ok(), !model.contains_key(&key));
+                    model.entry(key).or_insert(value);
+                }
+                if let Some(ref root) = tree.root {
+                    check(root, t, true);
+                }
+            }
+
+            assert_eq!(tree.total(), model.values().sum::<u64>());
+            for _ in 0..200 {
+                let lo = rng.gen_range(0..500);
+                let hi = rng.gen_range(lo..=500);
+                let expected: u64 = model.range(lo..hi).map(|(_, value)| value).sum();
+                assert_eq!(tree.aggregate(lo..hi), expected);
+                let expected: u64 = model.range(lo..=hi).map(|(_, value)| value).sum();
+                assert_eq!(tree.aggregate(lo..=hi), expected);
+            }
+
+            while let Some((&key, _)) = model.iter().next() {
+                model.remove(&key);
+                tree.remove(key).unwrap();
+            }
+            assert!(tree.root.is_none());
+            assert!(matches!(tree.remove(1), Err(Error::KeyWasNotFound)));
+        }
+    }
+
+    #[test]
+    fn histogram_counts_each_bucket() {
+        let mut tree: AggregatedBTree<u32, (), Natural, Count> = AggregatedBTree::new();
+        for key in 0..100 {
+            tree.insert(key * 3, ()).unwrap();
+        }
+
+        assert_eq!(
+            tree.histogram(&[0, 30, 31, 150, 1_000]),
+            vec![10, 1, 39, 50]
+        );
+        assert_eq!(tree.histogram(&[5]), Vec::<usize>::new());
+        assert_eq!(tree.aggregate(..), 100);
+        assert_eq!(tree.aggregate(297..), 1);
+    }
+}
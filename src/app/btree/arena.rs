@@ -0,0 +1,225 @@
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+
+use crate::app::btree::key_value::Comparator;
+use crate::Error;
+
+type NodeId = u32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ArenaNode {
+    len: u32,
+    is_leaf: bool,
+}
+
+/// B-tree that keeps every node in one arena, node `id` owning fixed slots of
+/// `keys`, `values` and `children`. Unused slots hold copies of live keys.
+#[derive(Debug, Clone)]
+pub struct ArenaBTree<K, V, C>
+where
+    K: Ord,
+    C: Comparator<K>,
+{
+    nodes: Vec<ArenaNode>,
+    keys: Vec<K>,
+    values: Vec<Option<V>>,
+    children: Vec<NodeId>,
+    root: Option<NodeId>,
+    t: usize,
+    cmp: PhantomData<C>,
+}
+
+#[allow(dead_code)]
+impl<K, V, C> ArenaBTree<K, V, C>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    C: Comparator<K>,
+{
+    pub fn new() -> Self {
+        ArenaBTree {
+            nodes: vec![],
+            keys: vec![],
+            values: vec![],
+            children: vec![],
+            root: None,
+            t: 2,
+            cmp: PhantomData,
+        }
+    }
+
+    pub fn with(t: usize) -> Option<Self> {
+        if t < 2 {
+            return None;
+        }
+
+        Some(ArenaBTree { t, ..Self::new() })
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn search(&self, key: K) -> Result<&V, Error> {
+        let mut id = self.root.ok_or(Error::KeyWasNotFound)?;
+        loop {
+            match self.find(id, &key) {
+                Ok(index) => {
+                    let slot = self.key_base(id) + index;
+                    return self.values[slot].as_ref().ok_or(Error::UnexpectedError);
+                }
+                Err(_) if self.nodes[id as usize].is_leaf => return Err(Error::KeyWasNotFound),
+                Err(index) => id = self.children[self.child_base(id) + index],
+            }
+        }
+    }
+
+    pub fn contains(&self, key: K) -> bool {
+        self.search(key).is_ok()
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Result<(), Error> {
+        let root = match self.root {
+            Some(root) => root,
+            None => {
+                let root = self.alloc(true, key);
+                self.root = Some(root);
+                root
+            }
+        };
+
+        let root = if self.is_full(root) {
+            let new_root = self.alloc(false, key);
+            let slot = self.child_base(new_root);
+            self.children[slot] = root;
+            self.split_child(new_root, 0);
+            self.root = Some(new_root);
+            new_root
+        } else {
+            root
+        };
+
+        self.insert_non_full(root, key, value)
+    }
+
+    fn capacity(&self) -> usize {
+        2 * self.t - 1
+    }
+
+    fn key_base(&self, id: NodeId) -> usize {
+        id as usize * self.capacity()
+    }
+
+    fn child_base(&self, id: NodeId) -> usize {
+        id as usize * (self.capacity() + 1)
+    }
+
+    fn node_keys(&self, id: NodeId) -> &[K] {
+        let base = self.key_base(id);
+        &self.keys[base..base + self.nodes[id as usize].len as usize]
+    }
+
+    fn find(&self, id: NodeId, key: &K) -> Result<usize, usize> {
+        self.node_keys(id).binary_search_by(|k| C::compare(k, key))
+    }
+
+    fn is_full(&self, id: NodeId) -> bool {
+        self.nodes[id as usize].len as usize >= self.capacity()
+    }
+
+    fn alloc(&mut self, is_leaf: bool, filler: K) -> NodeId {
+        let id = self.nodes.len() as NodeId;
+        let capacity = self.capacity();
+
+        self.nodes.push(ArenaNode { len: 0, is_leaf });
+        self.keys.resize(self.keys.len() + capacity, filler);
+        self.values
+            .resize_with(self.values.len() + capacity, || None);
+        self.children.resize(self.children.len() + capacity + 1, 0);
+
+        id
+    }
+
+    /// Opens a gap at `index` in node `id` and fills it.
+    fn put(&mut self, id: NodeId, index: usize, key: K, value: Option<V>) {
+        let base = self.key_base(id);
+        let len = self.nodes[id as usize].len as usize;
+
+        self.keys
+            .copy_within(base + index..base + len, base + index + 1);
+        self.values[base + index..base + len + 1].rotate_right(1);
+        self.keys[base + index] = key;
+        self.values[base + index] = value;
+        self.nodes[id as usize].len += 1;
+    }
+
+    fn split_child(&mut self, parent: NodeId, index: usize) {
+        let t = self.t;
+        let child = self.children[self.child_base(parent) + index];
+        let child_base = self.key_base(child);
+        let median = self.keys[child_base + t - 1];
+
+        let sibling = self.alloc(self.nodes[child as usize].is_leaf, median);
+        let sibling_base = self.key_base(sibling);
+
+        self.keys
+            .copy_within(child_base + t..child_base + 2 * t - 1, sibling_base);
+        for i in 0..t - 1 {
+            self.values[sibling_base + i] = self.values[child_base + t + i].take();
+        }
+        if !self.nodes[child as usize].is_leaf {
+            let (from, to) = (self.child_base(child), self.child_base(sibling));
+            self.children.copy_within(from + t..from + 2 * t, to);
+        }
+        let median_value = self.values[child_base + t - 1].take();
+
+        self.nodes[child as usize].len = (t - 1) as u32;
+        self.nodes[sibling as usize].len = (t - 1) as u32;
+
+        let children_base = self.child_base(parent);
+        let len = self.nodes[parent as usize].len as usize;
+        self.children.copy_within(
+            children_base + index + 1..children_base + len + 1,
+            children_base + index + 2,
+        );
+        self.children[children_base + index + 1] = sibling;
+        self.put(parent, index, median, median_value);
+    }
+
+    fn insert_non_full(&mut self, mut id: NodeId, key: K, value: V) -> Result<(), Error> {
+        loop {
+            let mut index = match self.find(id, &key) {
+                Ok(_) => return Err(Error::KeyAlreadyExists),
+                Err(index) => index,
+            };
+
+            if self.nodes[id as usize].is_leaf {
+                self.put(id, index, key, Some(value));
+                return Ok(());
+            }
+
+            let child = self.children[self.child_base(id) + index];
+            if self.is_full(child) {
+                self.split_child(id, index);
+                match C::compare(&key, &self.keys[self.key_base(id) + index]) {
+                    Ordering::Equal => return Err(Error::KeyAlreadyExists),
+                    Ordering::Greater => index += 1,
+                    Ordering::Less => {}
+                }
+            }
+
+            id = self.children[self.child_base(id) + index];
+        }
+    }
+}
+
+impl<K, V, C> Default for ArenaBTree<K, V, C>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    C: Comparator<K>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
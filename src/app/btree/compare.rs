@@ -0,0 +1,55 @@
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+
+use crate::app::btree::key_value::Comparator;
+
+/// Orders keys by their own `Ord` impl.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Natural;
+
+impl<K: Ord> Comparator<K> for Natural {
+    fn compare(lhs: &K, rhs: &K) -> Ordering {
+        lhs.cmp(rhs)
+    }
+}
+
+/// Flips the order of `C`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reverse<C>(PhantomData<C>);
+
+impl<K, C: Comparator<K>> Comparator<K> for Reverse<C> {
+    fn compare(lhs: &K, rhs: &K) -> Ordering {
+        C::compare(rhs, lhs)
+    }
+}
+
+/// Part of a key that [`ByKey`] orders by.
+pub trait KeyProjection<K> {
+    type Output: Ord;
+
+    fn project(key: &K) -> Self::Output;
+}
+
+/// Orders keys by what `F` projects out of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByKey<F>(PhantomData<F>);
+
+impl<K, F: KeyProjection<K>> Comparator<K> for ByKey<F> {
+    fn compare(lhs: &K, rhs: &K) -> Ordering {
+        F::project(lhs).cmp(&F::project(rhs))
+    }
+}
+
+/// Orders by `C1` and breaks its ties with `C2`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Then<C1, C2>(PhantomData<(C1, C2)>);
+
+impl<K, C1, C2> Comparator<K> for Then<C1, C2>
+where
+    C1: Comparator<K>,
+    C2: Comparator<K>,
+{
+    fn compare(lhs: &K, rhs: &K) -> Ordering {
+        C1::compare(lhs, rhs).then_with(|| C2::compare(lhs, rhs))
+    }
+}
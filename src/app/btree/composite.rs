@@ -0,0 +1,73 @@
+/// Column of a composite key with its lowest and highest value, which bound
+/// the keys starting with a given prefix.
+pub trait Column: Copy + Ord {
+    const MIN: Self;
+    const MAX: Self;
+}
+
+macro_rules! column {
+    ($($ty:ty),*) => {
+        $(impl Column for $ty {
+            const MIN: Self = <$ty>::MIN;
+            const MAX: Self = <$ty>::MAX;
+        })*
+    };
+}
+
+column!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, char);
+
+impl Column for bool {
+    const MIN: Self = false;
+    const MAX: Self = true;
+}
+
+/// Composite key whose leading columns are `P`. `first` and `last` are the
+/// lowest and highest keys that start with a given prefix.
+pub trait Prefix<P>: Sized {
+    fn first(prefix: P) -> Self;
+    fn last(prefix: P) -> Self;
+}
+
+impl<A, B> Prefix<A> for (A, B)
+where
+    A: Copy,
+    B: Column,
+{
+    fn first(a: A) -> Self {
+        (a, B::MIN)
+    }
+
+    fn last(a: A) -> Self {
+        (a, B::MAX)
+    }
+}
+
+impl<A, B, C> Prefix<A> for (A, B, C)
+where
+    A: Copy,
+    B: Column,
+    C: Column,
+{
+    fn first(a: A) -> Self {
+        (a, B::MIN, C::MIN)
+    }
+
+    fn last(a: A) -> Self {
+        (a, B::MAX, C::MAX)
+    }
+}
+
+impl<A, B, C> Prefix<(A, B)> for (A, B, C)
+where
+    A: Copy,
+    B: Copy,
+    C: Column,
+{
+    fn first((a, b): (A, B)) -> Self {
+        (a, b, C::MIN)
+    }
+
+    fn last((a, b): (A, B)) -> Self {
+        (a, b, C::MAX)
+    }
+}
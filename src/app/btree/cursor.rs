@@ -0,0 +1,575 @@
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+use std::ops::Bound;
+use std::ptr::NonNull;
+
+use crate::app::btree::key_value::Comparator;
+use crate::app::btree::node::{Node, NodeType};
+use crate::app::btree::observer::{NoObserver, Observer};
+use crate::app::btree::search::{Binary, NodeSearch};
+use crate::app::btree::BTree;
+use crate::Error;
+
+/// Read-only cursor over a [`BTree`], at an entry or at the "ghost" past
+/// both ends, from which moving wraps around.
+#[derive(Debug, Clone)]
+pub struct Cursor<'a, K, V, C, S = Binary>
+where
+    K: Ord,
+{
+    root: Option<&'a Node<K, V>>,
+    /// Child indexes descended into, then the index of the current pair. Empty
+    /// at the ghost.
+    path: Vec<(&'a Node<K, V>, usize)>,
+    cmp: PhantomData<C>,
+    search: PhantomData<S>,
+}
+
+impl<'a, K, V, C, S> Cursor<'a, K, V, C, S>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    C: Comparator<K>,
+    S: NodeSearch<K>,
+{
+    pub(crate) fn ghost(root: Option<&'a Node<K, V>>) -> Self {
+        Cursor {
+            root,
+            path: vec![],
+            cmp: PhantomData,
+            search: PhantomData,
+        }
+    }
+
+    /// Cursor at the first entry that lies after `bound`.
+    pub(crate) fn lower_bound(root: Option<&'a Node<K, V>>, bound: Bound<K>) -> Self {
+        let mut cursor = Self::ghost(root);
+        let key = match bound {
+            Bound::Included(key) | Bound::Excluded(key) => key,
+            Bound::Unbounded => {
+                cursor.move_next();
+                return cursor;
+            }
+        };
+
+        let mut node = root;
+        while let Some(current) = node {
+            let keys = current.pairs().map_or(&[][..], |pairs| &pairs.keys[..]);
+            match S::search::<C>(keys, &key) {
+                Ok(index) => {
+                    cursor.path.push((current, index));
+                    if let Bound::Excluded(_) = bound {
+                        cursor.move_next();
+                    }
+                    return cursor;
+                }
+                Err(index) if current.is_leaf() => {
+                    if index < keys.len() {
+                        cursor.path.push((current, index));
+                    } else if !keys.is_empty() {
+                        cursor.path.push((current, index - 1));
+                        cursor.move_next();
+                    }
+                    return cursor;
+                }
+                Err(index) => {
+                    cursor.path.push((current, index));
+                    node = current.children().get(index);
+                }
+            }
+        }
+        cursor
+    }
+
+    /// Cursor at the last entry that lies before `bound`.
+    pub(crate) fn upper_bound(root: Option<&'a Node<K, V>>, bound: Bound<K>) -> Self {
+        let mut cursor = Self::ghost(root);
+        let key = match bound {
+            Bound::Included(key) | Bound::Excluded(key) => key,
+            Bound::Unbounded => {
+                cursor.move_prev();
+                return cursor;
+            }
+        };
+
+        let mut node = root;
+        while let Some(current) = node {
+            let keys = current.pairs().map_or(&[][..], |pairs| &pairs.keys[..]);
+            match S::search::<C>(keys, &key) {
+                Ok(index) => {
+                    cursor.path.push((current, index));
+                    if let Bound::Excluded(_) = bound {
+                        cursor.move_prev();
+                    }
+                    return cursor;
+                }
+                Err(index) if current.is_leaf() => {
+                    if index > 0 {
+                        cursor.path.push((current, index - 1));
+                    } else if !keys.is_empty() {
+                        cursor.path.push((current, 0));
+                        cursor.move_prev();
+                    }
+                    return cursor;
+                }
+                Err(index) => {
+                    cursor.path.push((current, index));
+                    node = current.children().get(index);
+                }
+            }
+        }
+        cursor
+    }
+
+    pub fn key(&self) -> Option<&'a K> {
+        self.current().map(|(key, _)| key)
+    }
+
+    pub fn value(&self) -> Option<&'a V> {
+        self.current().map(|(_, value)| value)
+    }
+
+    pub fn current(&self) -> Option<(&'a K, &'a V)> {
+        let (node, index) = *self.path.last()?;
+        let pairs = node.pairs()?;
+        Some((&pairs.keys[index], &pairs.values[index]))
+    }
+
+    pub fn is_ghost(&self) -> bool {
+        self.path.is_empty()
+    }
+
+    pub fn move_next(&mut self) {
+        let (node, index) = match self.path.pop() {
+            Some(frame) => frame,
+            None => {
+                self.descend(self.root, false);
+                return;
+            }
+        };
+
+        if !node.is_leaf() {
+            self.path.push((node, index + 1));
+            self.descend(node.children().get(index + 1), false);
+        } else if index + 1 < node.len() {
+            self.path.push((node, index + 1));
+        } else {
+            while let Some((node, child)) = self.path.pop() {
+                if child < node.len() {
+                    self.path.push((node, child));
+                    return;
+                }
+            }
+        }
+    }
+
+    pub fn move_prev(&mut self) {
+        let (node, index) = match self.path.pop() {
+            Some(frame) => frame,
+            None => {
+                self.descend(self.root, true);
+                return;
+            }
+        };
+
+        if !node.is_leaf() {
+            self.path.push((node, index));
+            self.descend(node.children().get(index), true);
+        } else if index > 0 {
+            self.path.push((node, index - 1));
+        } else {
+            while let Some((node, child)) = self.path.pop() {
+                if child > 0 {
+                    self.path.push((node, child - 1));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Walks down to the leftmost (or `rightmost`) entry of `node`.
+    fn descend(&mut self, mut node: Option<&'a Node<K, V>>, rightmost: bool) {
+        while let Some(current) = node {
+            let index = match (rightmost, current.is_leaf()) {
+                (false, _) => 0,
+                (true, false) => current.len(),
+                (true, true) => current.len().saturating_sub(1),
+            };
+            self.path.push((current, index));
+            node = current.children().get(index);
+        }
+    }
+}
+
+impl<'a, K, V, C, S> Iterator for Cursor<'a, K, V, C, S>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    C: Comparator<K>,
+    S: NodeSearch<K>,
+{
+    type Item = (&'a K, &'a V);
+
+    /// Yields the current entry and steps forward, stopping at the ghost.
+    fn next(&mut self) -> Option<Self::Item> {
+        let current = self.current()?;
+        self.move_next();
+        Some(current)
+    }
+}
+
+/// Cursor that can edit the tree. Like [`Cursor`] it keeps the path to the
+/// current entry, so moving only climbs or descends at node edges. An edit
+/// invalidates the path, which is then searched again from the root.
+#[derive(Debug)]
+pub struct CursorMut<'a, K, V, C, S = Binary, O = NoObserver>
+where
+    K: Ord,
+    C: Comparator<K>,
+    S: NodeSearch<K>,
+    O: Observer<K>,
+{
+    /// Held as a pointer so that moving the cursor leaves `path` valid.
+    tree: NonNull<BTree<K, V, C, S, O>>,
+    /// Like `Cursor::path`, as pointers taken from `tree` since its last edit.
+    path: Vec<(NonNull<Node<K, V>>, usize)>,
+    borrow: PhantomData<&'a mut BTree<K, V, C, S, O>>,
+}
+
+#[allow(dead_code)]
+impl<'a, K, V, C, S, O> CursorMut<'a, K, V, C, S, O>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    C: Comparator<K>,
+    S: NodeSearch<K>,
+    O: Observer<K>,
+{
+    pub(crate) fn lower_bound(tree: &'a mut BTree<K, V, C, S, O>, bound: Bound<K>) -> Self {
+        let mut cursor = Self::ghost(tree);
+        cursor.seek(bound, true);
+        cursor
+    }
+
+    pub(crate) fn upper_bound(tree: &'a mut BTree<K, V, C, S, O>, bound: Bound<K>) -> Self {
+        let mut cursor = Self::ghost(tree);
+        cursor.seek(bound, false);
+        cursor
+    }
+
+    fn ghost(tree: &'a mut BTree<K, V, C, S, O>) -> Self {
+        CursorMut {
+            tree: NonNull::from(tree),
+            path: vec![],
+            borrow: PhantomData,
+        }
+    }
+
+    pub fn key(&self) -> Option<&K> {
+        self.current().map(|(key, _)| key)
+    }
+
+    pub fn value(&self) -> Option<&V> {
+        self.current().map(|(_, value)| value)
+    }
+
+    pub fn value_mut(&mut self) -> Option<&mut V> {
+        let (node, index) = *self.path.last()?;
+        // The path was taken from `tree`, which nothing else can reach.
+        for (above, _) in &self.path {
+            unsafe { (*above.as_ptr()).touch() };
+        }
+        match unsafe { (*node.as_ptr()).node_type_untouched() } {
+            NodeType::Internal(pairs, _) | NodeType::Leaf(pairs) => pairs.values.get_mut(index),
+            NodeType::Undefined => None,
+        }
+    }
+
+    pub fn is_ghost(&self) -> bool {
+        self.path.is_empty()
+    }
+
+    pub fn move_next(&mut self) {
+        let (node, index) = match self.path.pop() {
+            Some(frame) => frame,
+            None => {
+                let root = self.root();
+                self.descend(root, false);
+                return;
+            }
+        };
+
+        let (is_leaf, len) = Self::shape(node);
+        if !is_leaf {
+            self.path.push((node, index + 1));
+            self.descend(Self::child(node, index + 1), false);
+        } else if index + 1 < len {
+            self.path.push((node, index + 1));
+        } else {
+            while let Some((node, child)) = self.path.pop() {
+                if child < Self::shape(node).1 {
+                    self.path.push((node, child));
+                    return;
+                }
+            }
+        }
+    }
+
+    pub fn move_prev(&mut self) {
+        let (node, index) = match self.path.pop() {
+            Some(frame) => frame,
+            None => {
+                let root = self.root();
+                self.descend(root, true);
+                return;
+            }
+        };
+
+        if !Self::shape(node).0 {
+            self.path.push((node, index));
+            self.descend(Self::child(node, index), true);
+        } else if index > 0 {
+            self.path.push((node, index - 1));
+        } else {
+            while let Some((node, child)) = self.path.pop() {
+                if child > 0 {
+                    self.path.push((node, child - 1));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Inserts an entry right before the current one, or at the very end when
+    /// at the ghost. Fails with `KeyOutOfOrder` if `key` would not land there.
+    pub fn insert_before(&mut self, key: K, value: V) -> Result<(), Error> {
+        let after_prev = match self.peek(false) {
+            Some(prev) => C::compare(&key, &prev) == Ordering::Greater,
+            None => true,
+        };
+        let before_current = match self.key() {
+            Some(current) => C::compare(&key, current) == Ordering::Less,
+            None => true,
+        };
+        if !(after_prev && before_current) {
+            return Err(Error::KeyOutOfOrder);
+        }
+
+        self.edit(|tree| tree.insert(key, value))
+    }
+
+    /// Inserts right after the current entry, or at the front from the ghost.
+    /// Fails with `KeyOutOfOrder` if `key` does not belong there.
+    pub fn insert_after(&mut self, key: K, value: V) -> Result<(), Error> {
+        let after_current = match self.key() {
+            Some(current) => C::compare(&key, current) == Ordering::Greater,
+            None => true,
+        };
+        let before_next = match self.peek(true) {
+            Some(next) => C::compare(&key, &next) == Ordering::Less,
+            None => true,
+        };
+        if !(after_current && before_next) {
+            return Err(Error::KeyOutOfOrder);
+        }
+
+        self.edit(|tree| tree.insert(key, value))
+    }
+
+    /// Removes the current entry and moves on to the next one.
+    pub fn remove_current(&mut self) -> Option<(K, V)> {
+        let key = *self.key()?;
+        self.path.clear();
+        let removed = self.tree_mut().remove(key);
+        self.seek(Bound::Excluded(key), true);
+        removed.ok().map(|value| (key, value))
+    }
+
+    fn current(&self) -> Option<(&K, &V)> {
+        let (node, index) = *self.path.last()?;
+        let pairs = unsafe { node.as_ref() }.pairs()?;
+        Some((&pairs.keys[index], &pairs.values[index]))
+    }
+
+    /// Key one step forward or back, leaving the cursor where it is.
+    fn peek(&mut self, forward: bool) -> Option<K> {
+        if forward {
+            self.move_next();
+        } else {
+            self.move_prev();
+        }
+        let key = self.key().copied();
+        if forward {
+            self.move_prev();
+        } else {
+            self.move_next();
+        }
+        key
+    }
+
+    /// Runs `f` on the tree and finds the current entry again afterwards.
+    fn edit<T, F>(&mut self, f: F) -> T
+    where
+        F: FnOnce(&mut BTree<K, V, C, S, O>) -> T,
+    {
+        let current = self.key().copied();
+        self.path.clear();
+        let result = f(self.tree_mut());
+        if let Some(key) = current {
+            self.seek(Bound::Included(key), true);
+        }
+        result
+    }
+
+    /// Replaces the path with the one `Cursor` finds for `bound`.
+    fn seek(&mut self, bound: Bound<K>, lower: bool) {
+        let root = unsafe { self.tree.as_ref() }.root.as_ref();
+        let indexes: Vec<usize> = match lower {
+            true => Cursor::<K, V, C, S>::lower_bound(root, bound).path,
+            false => Cursor::<K, V, C, S>::upper_bound(root, bound).path,
+        }
+        .into_iter()
+        .map(|(_, index)| index)
+        .collect();
+
+        self.path.clear();
+        let mut node = self.root();
+        for index in indexes {
+            let Some(current) = node else { break };
+            self.path.push((current, index));
+            node = Self::child(current, index);
+        }
+    }
+
+    /// Walks down to the leftmost (or `rightmost`) entry of `node`.
+    fn descend(&mut self, mut node: Option<NonNull<Node<K, V>>>, rightmost: bool) {
+        while let Some(current) = node {
+            let (is_leaf, len) = Self::shape(current);
+            let index = match (rightmost, is_leaf) {
+                (false, _) => 0,
+                (true, false) => len,
+                (true, true) => len.saturating_sub(1),
+            };
+            self.path.push((current, index));
+            node = Self::child(current, index);
+        }
+    }
+
+    /// Invalidates the path, so it must be empty or about to be replaced.
+    fn tree_mut(&mut self) -> &mut BTree<K, V, C, S, O> {
+        unsafe { self.tree.as_mut() }
+    }
+
+    fn root(&mut self) -> Option<NonNull<Node<K, V>>> {
+        self.tree_mut().root.as_mut().map(NonNull::from)
+    }
+
+    fn shape(node: NonNull<Node<K, V>>) -> (bool, usize) {
+        let node = unsafe { node.as_ref() };
+        (node.is_leaf(), node.len())
+    }
+
+    fn child(node: NonNull<Node<K, V>>, index: usize) -> Option<NonNull<Node<K, V>>> {
+        match unsafe { (*node.as_ptr()).node_type_untouched() } {
+            NodeType::Internal(_, children) => children.get_mut(index).map(NonNull::from),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::btree::compare::Natural;
+    use crate::app::btree::BTree;
+    use crate::Error;
+    use std::ops::Bound;
+
+    fn tree(n: u32) -> BTree<u32, u32, Natural> {
+        let mut tree = BTree::with(2).unwrap();
+        for key in 0..n {
+            tree.insert(key * 2, key).unwrap();
+        }
+        tree
+    }
+
+    #[test]
+    fn walks_both_ways_and_wraps_at_the_ghost() {
+        let n = if cfg!(miri) { 40 } else { 1_000 };
+        let mut tree = tree(n);
+        let mut cursor = tree.lower_bound_mut(Bound::Unbounded);
+
+        let mut forward = vec![];
+        while let Some(key) = cursor.key().copied() {
+            forward.push(key);
+            cursor.move_next();
+        }
+        assert_eq!(forward, (0..n).map(|key| key * 2).collect::<Vec<_>>());
+
+        assert!(cursor.is_ghost());
+        let mut backward = vec![];
+        cursor.move_prev();
+        while let Some(key) = cursor.key().copied() {
+            backward.push(key);
+            cursor.move_prev();
+        }
+        forward.reverse();
+        assert_eq!(backward, forward);
+
+        cursor.move_next();
+        assert_eq!(cursor.key(), Some(&0));
+    }
+
+    #[test]
+    fn edits_in_place_while_moving() {
+        let n = if cfg!(miri) { 30 } else { 500 };
+        let mut tree = tree(n);
+        let mut cursor = tree.lower_bound_mut(Bound::Included(1));
+        assert_eq!(cursor.key(), Some(&2));
+
+        while let Some(key) = cursor.key().copied() {
+            *cursor.value_mut().unwrap() += 1;
+            if key % 4 == 0 {
+                assert_eq!(cursor.remove_current(), Some((key, key / 2 + 1)));
+            } else {
+                cursor.insert_after(key + 1, 0).unwrap();
+                assert!(matches!(
+                    cursor.insert_after(key + 3, 0),
+                    Err(Error::KeyOutOfOrder)
+                ));
+                assert!(matches!(
+                    cursor.insert_before(key, 0),
+                    Err(Error::KeyOutOfOrder)
+                ));
+                assert_eq!(cursor.key(), Some(&key));
+                cursor.move_next();
+                cursor.move_next();
+            }
+        }
+
+        let expected: Vec<(u32, u32)> = (0..n)
+            .map(|key| key * 2)
+            .filter(|key| *key == 0 || key % 4 != 0)
+            .flat_map(|key| match key {
+                0 => vec![(0, 0)],
+                _ => vec![(key, key / 2 + 1), (key + 1, 0)],
+            })
+            .collect();
+        assert_eq!(
+            tree.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[test]
+    fn inserts_at_the_ghost_land_at_the_ends() {
+        let mut tree = tree(10);
+        let mut cursor = tree.upper_bound_mut(Bound::Excluded(0));
+        assert!(cursor.is_ghost());
+        cursor.insert_before(100, 1).unwrap();
+        cursor.insert_after(0, 1).unwrap_err();
+        assert!(cursor.is_ghost());
+        cursor.move_prev();
+        assert_eq!(cursor.key(), Some(&100));
+        assert_eq!(cursor.value(), Some(&1));
+    }
+}
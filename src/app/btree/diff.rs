@@ -0,0 +1,255 @@
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+
+use crate::app::btree::key_value::Comparator;
+use crate::app::btree::node::Node;
+
+/// One difference between two trees, as seen going from the old tree to the
+/// new one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change<'a, K, V> {
+    Added(&'a K, &'a V),
+    Removed(&'a K, &'a V),
+    Changed(&'a K, &'a V, &'a V),
+}
+
+/// What is left to walk of one tree: a subtree with its height, or a
+/// single entry.
+#[derive(Debug)]
+enum Item<'a, K: Ord, V> {
+    Node(&'a Node<K, V>, usize),
+    Entry(&'a K, &'a V),
+}
+
+/// Differences between two trees in key order. Subtrees both trees still
+/// share since one was cloned from the other are skipped without being
+/// read. Created by `BTree::diff`.
+#[derive(Debug)]
+pub struct Diff<'a, K, V, C>
+where
+    K: Copy + Clone + Ord,
+    V: Clone + PartialEq,
+    C: Comparator<K>,
+{
+    /// Items of each tree, the next one on top.
+    old: Vec<Item<'a, K, V>>,
+    new: Vec<Item<'a, K, V>>,
+    same: fn(&Node<K, V>, &Node<K, V>) -> bool,
+    comparator: PhantomData<C>,
+}
+
+impl<'a, K, V, C> Diff<'a, K, V, C>
+where
+    K: Copy + Clone + Ord,
+    V: Clone + PartialEq,
+    C: Comparator<K>,
+{
+    /// Walks `old` against `new`, skipping pairs of subtrees `same` holds
+    /// for.
+    pub(crate) fn new(
+        old: Option<&'a Node<K, V>>,
+        new: Option<&'a Node<K, V>>,
+        same: fn(&Node<K, V>, &Node<K, V>) -> bool,
+    ) -> Self {
+        let item = |root: &'a Node<K, V>| Item::Node(root, root.height());
+        Diff {
+            old: old.map(item).into_iter().collect(),
+            new: new.map(item).into_iter().collect(),
+            same,
+            comparator: PhantomData,
+        }
+    }
+
+    /// Replaces the subtree on top of `items` with its entries and children.
+    fn expand(items: &mut Vec<Item<'a, K, V>>) {
+        let (node, height) = match items.pop() {
+            Some(Item::Node(node, height)) => (node, height),
+            Some(entry) => return items.push(entry),
+            None => return,
+        };
+        let pairs = match node.pairs() {
+            Some(pairs) => pairs,
+            None => return,
+        };
+        let children = node.children();
+        let entries = pairs.keys.iter().zip(pairs.values.iter());
+        for (index, (key, value)) in entries.enumerate().rev() {
+            if let Some(child) = children.get(index + 1) {
+                items.push(Item::Node(child, height - 1));
+            }
+            items.push(Item::Entry(key, value));
+        }
+        if let Some(child) = children.first() {
+            items.push(Item::Node(child, height - 1));
+        }
+    }
+}
+
+impl<'a, K, V, C> Iterator for Diff<'a, K, V, C>
+where
+    K: Copy + Clone + Ord,
+    V: Clone + PartialEq,
+    C: Comparator<K>,
+{
+    type Item = Change<'a, K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match (self.old.last(), self.new.last()) {
+                (Some(Item::Node(old, _)), Some(Item::Node(new, _))) if (self.same)(old, new) => {
+                    self.old.pop();
+                    self.new.pop();
+                }
+                (Some(Item::Node(_, old)), Some(Item::Node(_, new))) => {
+                    let (old, new) = (*old, *new);
+                    if old >= new {
+                        Self::expand(&mut self.old);
+                    }
+                    if new >= old {
+                        Self::expand(&mut self.new);
+                    }
+                }
+                (Some(Item::Node(..)), _) => Self::expand(&mut self.old),
+                (_, Some(Item::Node(..))) => Self::expand(&mut self.new),
+                (Some(Item::Entry(key, old)), Some(Item::Entry(other, new))) => {
+                    let (key, old, other, new) = (*key, *old, *other, *new);
+                    match C::compare(key, other) {
+                        Ordering::Less => {
+                            self.old.pop();
+                            return Some(Change::Removed(key, old));
+                        }
+                        Ordering::Greater => {
+                            self.new.pop();
+                            return Some(Change::Added(other, new));
+                        }
+                        Ordering::Equal => {
+                            self.old.pop();
+                            self.new.pop();
+                            if old != new {
+                                return Some(Change::Changed(key, old, new));
+                            }
+                        }
+                    }
+                }
+                (Some(Item::Entry(key, old)), None) => {
+                    let change = Change::Removed(*key, *old);
+                    self.old.pop();
+                    return Some(change);
+                }
+                (None, Some(Item::Entry(key, new))) => {
+                    let change = Change::Added(*key, *new);
+                    self.new.pop();
+                    return Some(change);
+                }
+                (None, None) => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::btree::compare::Natural;
+    use crate::app::btree::BTree;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::cell::Cell;
+    use std::collections::BTreeMap;
+
+    thread_local! {
+        static COMPARED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Value that counts how often it is compared.
+    #[derive(Debug, Clone, Copy)]
+    struct Counted(u32);
+
+    impl PartialEq for Counted {
+        fn eq(&self, other: &Self) -> bool {
+            COMPARED.with(|compared| compared.set(compared.get() + 1));
+            self.0 == other.0
+        }
+    }
+
+    type Tree = BTree<u32, Counted, Natural>;
+
+    fn owned(changes: Diff<'_, u32, Counted, Natural>) -> Vec<(u32, Option<u32>, Option<u32>)> {
+        changes
+            .map(|change| match change {
+                Change::Added(key, new) => (*key, None, Some(new.0)),
+                Change::Removed(key, old) => (*key, Some(old.0), None),
+                Change::Changed(key, old, new) => (*key, Some(old.0), Some(new.0)),
+            })
+            .collect()
+    }
+
+    fn expected(
+        old: &BTreeMap<u32, u32>,
+        new: &BTreeMap<u32, u32>,
+    ) -> Vec<(u32, Option<u32>, Option<u32>)> {
+        let keys: std::collections::BTreeSet<_> = old.keys().chain(new.keys()).collect();
+        keys.into_iter()
+            .map(|key| (*key, old.get(key).copied(), new.get(key).copied()))
+            .filter(|(_, old, new)| old != new)
+            .collect()
+    }
+
+    fn compared() -> usize {
+        COMPARED.with(|compared| compared.replace(0))
+    }
+
+    #[test]
+    fn matches_a_full_scan_between_unrelated_trees() {
+        let mut rng = StdRng::seed_from_u64(3);
+        for t in [2, 3, 5] {
+            let (mut old, mut new) = (Tree::with(t).unwrap(), Tree::with(t).unwrap());
+            let (mut old_model, mut new_model) = (BTreeMap::new(), BTreeMap::new());
+            for _ in 0..2_000 {
+                let key = rng.gen_range(0..1_500);
+                let value = rng.gen_range(0..3);
+                if rng.gen_bool(0.5) && old.insert(key, Counted(value)).is_ok() {
+                    old_model.insert(key, value);
+                }
+                if rng.gen_bool(0.5) && new.insert(key, Counted(value)).is_ok() {
+                    new_model.insert(key, value);
+                }
+            }
+            assert_eq!(owned(old.diff(&new)), expected(&old_model, &new_model));
+            assert_eq!(owned(new.diff(&old)), expected(&new_model, &old_model));
+            assert_eq!(owned(old.diff(&Tree::new())).len(), old_model.len());
+        }
+    }
+
+    #[test]
+    fn skips_what_a_clone_still_shares() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut old = Tree::with(3).unwrap();
+        let mut model = BTreeMap::new();
+        for key in 0..10_000 {
+            old.insert(key, Counted(key)).unwrap();
+            model.insert(key, key);
+        }
+
+        let mut new = old.clone();
+        compared();
+        assert_eq!(owned(old.diff(&new)), vec![]);
+        assert_eq!(compared(), 0);
+
+        let mut new_model = model.clone();
+        for _ in 0..5 {
+            let key = rng.gen_range(0..12_000);
+            if new.remove(key).is_ok() {
+                new_model.remove(&key);
+            } else {
+                new.insert(key, Counted(0)).unwrap();
+                new_model.insert(key, 0);
+            }
+        }
+        *new.search_mut(42).unwrap() = Counted(1);
+        new_model.insert(42, 1);
+
+        compared();
+        assert_eq!(owned(old.diff(&new)), expected(&model, &new_model));
+        assert!(compared() < 500);
+    }
+}
@@ -0,0 +1,398 @@
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::{ptr, slice};
+
+use crate::app::btree::key_value::{Comparator, KeyValue};
+use crate::Error;
+
+/// `2 * T` contiguous slots. Stable Rust cannot spell `[_; 2 * T - 1]` for a
+/// generic `T`, so two `T`-sized halves are laid out back to back instead.
+#[repr(C)]
+struct Slots<X, const T: usize> {
+    head: [MaybeUninit<X>; T],
+    tail: [MaybeUninit<X>; T],
+}
+
+impl<X, const T: usize> Slots<X, T> {
+    fn uninit() -> Self {
+        Slots {
+            head: [const { MaybeUninit::uninit() }; T],
+            tail: [const { MaybeUninit::uninit() }; T],
+        }
+    }
+
+    fn as_ptr(&self) -> *const X {
+        self as *const Self as *const X
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut X {
+        self as *mut Self as *mut X
+    }
+}
+
+/// Index of a node in the slab of a [`ConstBTree`].
+type NodeId = u32;
+
+/// Node with room for `2T - 1` pairs and `2T` children, of which the first
+/// `len` and `len + 1` are initialised.
+struct FixedNode<K: Ord, V, const T: usize> {
+    len: usize,
+    is_leaf: bool,
+    pairs: Slots<KeyValue<K, V>, T>,
+    children: Slots<NodeId, T>,
+}
+
+impl<K: Ord, V, const T: usize> FixedNode<K, V, T> {
+    fn new(is_leaf: bool) -> Self {
+        FixedNode {
+            len: 0,
+            is_leaf,
+            pairs: Slots::uninit(),
+            children: Slots::uninit(),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == 2 * T - 1
+    }
+
+    fn pairs(&self) -> &[KeyValue<K, V>] {
+        unsafe { slice::from_raw_parts(self.pairs.as_ptr(), self.len) }
+    }
+
+    fn child(&self, index: usize) -> NodeId {
+        assert!(!self.is_leaf && index <= self.len);
+        unsafe { *self.children.as_ptr().add(index) }
+    }
+
+    fn find<C: Comparator<K>>(&self, key: &K) -> Result<usize, usize> {
+        self.pairs().binary_search_by(|k| C::compare(&k.key, key))
+    }
+
+    /// # Safety
+    /// The node must not be full and `index <= len`.
+    unsafe fn insert_pair(&mut self, index: usize, pair: KeyValue<K, V>) {
+        let pairs = self.pairs.as_mut_ptr();
+        ptr::copy(pairs.add(index), pairs.add(index + 1), self.len - index);
+        ptr::write(pairs.add(index), pair);
+        self.len += 1;
+    }
+
+    /// # Safety
+    /// The node must be internal and not full, and `index <= len`.
+    unsafe fn insert_child(&mut self, index: usize, child: NodeId) {
+        let children = self.children.as_mut_ptr();
+        ptr::copy(
+            children.add(index),
+            children.add(index + 1),
+            self.len + 1 - index,
+        );
+        ptr::write(children.add(index), child);
+    }
+
+    /// Moves the upper half of this full node into a new sibling and returns
+    /// the median together with the sibling.
+    fn split(&mut self) -> (KeyValue<K, V>, Self) {
+        debug_assert!(self.is_full());
+
+        let mut sibling = FixedNode::new(self.is_leaf);
+        unsafe {
+            let median = ptr::read(self.pairs.as_ptr().add(T - 1));
+            ptr::copy_nonoverlapping(
+                self.pairs.as_ptr().add(T),
+                sibling.pairs.as_mut_ptr(),
+                T - 1,
+            );
+            if !self.is_leaf {
+                ptr::copy_nonoverlapping(
+                    self.children.as_ptr().add(T),
+                    sibling.children.as_mut_ptr(),
+                    T,
+                );
+            }
+            self.len = T - 1;
+            sibling.len = T - 1;
+            (median, sibling)
+        }
+    }
+}
+
+impl<K: Ord, V, const T: usize> Drop for FixedNode<K, V, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(ptr::slice_from_raw_parts_mut(
+                self.pairs.as_mut_ptr(),
+                self.len,
+            ));
+        }
+    }
+}
+
+/// B-tree of compile-time degree `T` with pairs and children inline in
+/// fixed-size arrays and nodes side by side in one slab.
+pub struct ConstBTree<K, V, C, const T: usize>
+where
+    K: Ord,
+    C: Comparator<K>,
+{
+    nodes: Vec<FixedNode<K, V, T>>,
+    root: Option<NodeId>,
+    len: usize,
+    cmp: PhantomData<C>,
+}
+
+#[allow(dead_code)]
+impl<K, V, C, const T: usize> ConstBTree<K, V, C, T>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    C: Comparator<K>,
+{
+    pub fn new() -> Self {
+        const { assert!(T >= 2, "degree of a BTree must be at least 2") };
+
+        ConstBTree {
+            nodes: vec![],
+            root: None,
+            len: 0,
+            cmp: PhantomData,
+        }
+    }
+
+    /// Tree with room in its slab for the nodes `len` entries need at most.
+    pub fn with_capacity(len: usize) -> Self {
+        let mut tree = Self::new();
+        tree.nodes.reserve(2 * len.div_ceil(T - 1));
+        tree
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn search(&self, key: K) -> Result<&V, Error> {
+        let mut node = self.node(self.root.ok_or(Error::KeyWasNotFound)?);
+        loop {
+            match node.find::<C>(&key) {
+                Ok(index) => return Ok(&node.pairs()[index].value),
+                Err(_) if node.is_leaf => return Err(Error::KeyWasNotFound),
+                Err(index) => node = self.node(node.child(index)),
+            }
+        }
+    }
+
+    pub fn contains(&self, key: K) -> bool {
+        self.search(key).is_ok()
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Result<(), Error> {
+        let mut root = match self.root {
+            Some(root) => root,
+            None => self.alloc(FixedNode::new(true)),
+        };
+
+        if self.node(root).is_full() {
+            let mut new_root = FixedNode::new(false);
+            unsafe { ptr::write(new_root.children.as_mut_ptr(), root) };
+            root = self.alloc(new_root);
+            self.split_child(root, 0);
+        }
+        self.root = Some(root);
+
+        let result = self.insert_non_full(root, key, value);
+        if result.is_ok() {
+            self.len += 1;
+        }
+        result
+    }
+
+    fn node(&self, id: NodeId) -> &FixedNode<K, V, T> {
+        &self.nodes[id as usize]
+    }
+
+    fn node_mut(&mut self, id: NodeId) -> &mut FixedNode<K, V, T> {
+        &mut self.nodes[id as usize]
+    }
+
+    fn alloc(&mut self, node: FixedNode<K, V, T>) -> NodeId {
+        self.nodes.push(node);
+        (self.nodes.len() - 1) as NodeId
+    }
+
+    /// Splits the full child at `index` of `parent` around its median, which
+    /// moves up into `parent`. `parent` must not be full.
+    fn split_child(&mut self, parent: NodeId, index: usize) {
+        debug_assert!(!self.node(parent).is_full());
+
+        let child = self.node(parent).child(index);
+        let (median, sibling) = self.node_mut(child).split();
+        let sibling = self.alloc(sibling);
+
+        let parent = self.node_mut(parent);
+        unsafe {
+            parent.insert_child(index + 1, sibling);
+            parent.insert_pair(index, median);
+        }
+    }
+
+    fn insert_non_full(&mut self, mut id: NodeId, key: K, value: V) -> Result<(), Error> {
+        loop {
+            let node = self.node(id);
+            let mut index = match node.find::<C>(&key) {
+                Ok(_) => return Err(Error::KeyAlreadyExists),
+                Err(index) => index,
+            };
+
+            if node.is_leaf {
+                unsafe { self.node_mut(id).insert_pair(index, (key, value).into()) };
+                return Ok(());
+            }
+
+            if self.node(node.child(index)).is_full() {
+                self.split_child(id, index);
+                match C::compare(&key, &self.node(id).pairs()[index].key) {
+                    std::cmp::Ordering::Equal => return Err(Error::KeyAlreadyExists),
+                    std::cmp::Ordering::Greater => index += 1,
+                    std::cmp::Ordering::Less => {}
+                }
+            }
+
+            id = self.node(id).child(index);
+        }
+    }
+}
+
+impl<K, V, C, const T: usize> Default for ConstBTree<K, V, C, T>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    C: Comparator<K>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V, C, const T: usize> Debug for ConstBTree<K, V, C, T>
+where
+    K: Ord,
+    C: Comparator<K>,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConstBTree")
+            .field("t", &T)
+            .field("len", &self.len)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::btree::compare::Natural;
+    use std::rc::Rc;
+
+    /// Keys in order and the depth of every leaf.
+    fn walk<K, V, C, const T: usize>(
+        tree: &ConstBTree<K, V, C, T>,
+        id: NodeId,
+        depth: usize,
+        keys: &mut Vec<K>,
+        leaves: &mut Vec<usize>,
+    ) where
+        K: Copy + Ord,
+        V: Clone,
+        C: Comparator<K>,
+    {
+        let node = tree.node(id);
+        assert!(node.len < 2 * T && (Some(id) == tree.root || node.len >= T - 1));
+        for (i, pair) in node.pairs().iter().enumerate() {
+            if !node.is_leaf {
+                walk(tree, node.child(i), depth + 1, keys, leaves);
+            }
+            keys.push(pair.key);
+        }
+        match node.is_leaf {
+            true => leaves.push(depth),
+            false => walk(tree, node.child(node.len), depth + 1, keys, leaves),
+        }
+    }
+
+    fn check<V: Clone, const T: usize>(tree: &ConstBTree<u32, V, Natural, T>) -> Vec<u32> {
+        let (mut keys, mut leaves) = (vec![], vec![]);
+        if let Some(root) = tree.root {
+            walk(tree, root, 0, &mut keys, &mut leaves);
+        }
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(leaves.windows(2).all(|pair| pair[0] == pair[1]));
+        assert_eq!(keys.len(), tree.len());
+        keys
+    }
+
+    fn scrambled(n: u32) -> impl Iterator<Item = u32> {
+        (0..n).map(move |i| i.wrapping_mul(7_919) % n)
+    }
+
+    #[test]
+    fn finds_every_key_after_many_splits() {
+        let n = if cfg!(miri) { 300 } else { 20_000 };
+        let mut tree: ConstBTree<u32, u32, Natural, 2> = ConstBTree::with_capacity(n as usize);
+        for key in scrambled(n) {
+            tree.insert(key, key * 2).unwrap();
+        }
+
+        assert_eq!(check(&tree), (0..n).collect::<Vec<_>>());
+        for key in 0..n {
+            assert_eq!(tree.search(key).ok(), Some(&(key * 2)));
+        }
+        assert!(matches!(tree.search(n), Err(Error::KeyWasNotFound)));
+        assert!(tree.node_count() > n as usize / 3);
+    }
+
+    #[test]
+    fn rejects_duplicate_keys_and_keeps_the_first_value() {
+        let mut tree: ConstBTree<u32, u32, Natural, 3> = ConstBTree::new();
+        for key in scrambled(500) {
+            tree.insert(key, key).unwrap();
+        }
+        for key in scrambled(500) {
+            assert!(matches!(tree.insert(key, 0), Err(Error::KeyAlreadyExists)));
+        }
+
+        assert_eq!(tree.len(), 500);
+        assert_eq!(check(&tree).len(), 500);
+        assert!((0..500).all(|key| tree.search(key).ok() == Some(&key)));
+    }
+
+    #[test]
+    fn drops_every_value_exactly_once() {
+        let value = Rc::new(());
+        {
+            let mut tree: ConstBTree<u32, Rc<()>, Natural, 2> = ConstBTree::new();
+            for key in scrambled(200) {
+                tree.insert(key, Rc::clone(&value)).unwrap();
+            }
+            for key in 0..200 {
+                assert!(tree.insert(key, Rc::clone(&value)).is_err());
+            }
+            assert_eq!(Rc::strong_count(&value), 201);
+            check(&tree);
+
+            let mut strings: ConstBTree<u32, String, Natural, 2> = ConstBTree::new();
+            for key in scrambled(200) {
+                strings.insert(key, key.to_string()).unwrap();
+            }
+            assert_eq!(strings.search(123).ok().map(String::as_str), Some("123"));
+        }
+        assert_eq!(Rc::strong_count(&value), 1);
+    }
+}
@@ -0,0 +1,507 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use memmap2::Mmap;
+
+use crate::app::btree::key_value::Comparator;
+use crate::app::btree::node::Node;
+use crate::app::btree::observer::Observer;
+use crate::app::btree::search::{NodeSearch, PackedKey};
+use crate::app::btree::BTree;
+use crate::Error;
+
+const MAGIC: u64 = u64::from_le_bytes(*b"BTREEIMG");
+const HEADER_WORDS: usize = 4;
+const WORD: usize = std::mem::size_of::<u64>();
+/// Words of a list being appended to that are held before it starts going
+/// to the file.
+const LIST_BUFFER: usize = 1024;
+
+/// Writes `tree` as a flat image of little-endian `u64`s that [`Image`]
+/// searches in place, each value as the words `words` makes of it. The file
+/// is renamed into place once synced.
+pub fn write_image<K, V, C, S, O, F>(
+    tree: &BTree<K, V, C, S, O>,
+    path: &Path,
+    tag: u64,
+    mut words: F,
+) -> Result<(), Error>
+where
+    K: Copy + Clone + Ord + PackedKey,
+    V: Clone,
+    C: Comparator<K>,
+    S: NodeSearch<K>,
+    O: Observer<K>,
+    F: FnMut(&V) -> Vec<u64>,
+{
+    let mut out = vec![MAGIC, tag, tree.len() as u64, 0];
+    if let Some(ref root) = tree.root {
+        out[3] = write_node(root, &mut out, &mut words);
+    }
+
+    let bytes: Vec<u8> = out.iter().flat_map(|word| word.to_le_bytes()).collect();
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Appends `node` and everything below it to `out` and returns its offset.
+fn write_node<K, V, F>(node: &Node<K, V>, out: &mut Vec<u64>, words: &mut F) -> u64
+where
+    K: Copy + Clone + Ord + PackedKey,
+    V: Clone,
+    F: FnMut(&V) -> Vec<u64>,
+{
+    let children: Vec<u64> = node
+        .children()
+        .iter()
+        .map(|child| write_node(child, out, words))
+        .collect();
+
+    let (keys, values) = match node.pairs() {
+        Some(pairs) => (&pairs.keys[..], &pairs.values[..]),
+        None => (&[][..], &[][..]),
+    };
+    let lists: Vec<u64> = values
+        .iter()
+        .map(|value| {
+            let list = words(value);
+            let at = (out.len() * WORD) as u64;
+            out.push(list.len() as u64);
+            out.extend(list);
+            at
+        })
+        .collect();
+
+    let at = (out.len() * WORD) as u64;
+    out.push(keys.len() as u64);
+    out.push(children.len() as u64);
+    out.extend(keys.iter().map(PackedKey::packed));
+    out.extend(lists);
+    out.extend(children);
+    at
+}
+
+/// Node being filled on one level of an [`ImageBuilder`].
+#[derive(Debug, Default)]
+struct Pending {
+    keys: Vec<u64>,
+    lists: Vec<u64>,
+    children: Vec<u64>,
+}
+
+/// List of the key last given to `ImageBuilder::append`, not yet in a node.
+#[derive(Debug)]
+struct OpenList {
+    key: u64,
+    /// Words not written yet.
+    words: Vec<u64>,
+    /// Where the list starts and how many of its words are written, once it
+    /// outgrew `LIST_BUFFER`.
+    written: Option<(u64, u64)>,
+}
+
+/// Writes an image straight from entries in key order, holding one node per
+/// level. Nodes on the right edge may hold fewer keys than from
+/// [`write_image`].
+#[derive(Debug)]
+pub struct ImageBuilder {
+    path: PathBuf,
+    tmp: PathBuf,
+    out: BufWriter<File>,
+    /// Bytes written so far.
+    at: u64,
+    tag: u64,
+    len: u64,
+    max_keys: usize,
+    last: Option<u64>,
+    open: Option<OpenList>,
+    /// Leaves first.
+    levels: Vec<Pending>,
+}
+
+#[allow(dead_code)]
+impl ImageBuilder {
+    /// Starts an image at `path` whose nodes hold up to `2 * t - 1` keys.
+    /// Nothing shows up at `path` before `finish`.
+    pub fn create(path: &Path, tag: u64, t: usize) -> Result<Self, Error> {
+        if t < 2 {
+            return Err(Error::UnexpectedError);
+        }
+        let tmp = path.with_extension("tmp");
+        let mut builder = ImageBuilder {
+            path: path.to_path_buf(),
+            out: BufWriter::new(File::create(&tmp)?),
+            tmp,
+            at: 0,
+            tag,
+            len: 0,
+            max_keys: 2 * t - 1,
+            last: None,
+            open: None,
+            levels: vec![Pending::default()],
+        };
+        builder.write(&[0; HEADER_WORDS])?;
+        Ok(builder)
+    }
+
+    /// Adds `key` with its words. Keys must come in strictly increasing
+    /// packed order.
+    pub fn push(&mut self, key: u64, words: &[u64]) -> Result<(), Error> {
+        self.close_list()?;
+        self.start(key)?;
+        let list = self.at;
+        self.write(&[words.len() as u64])?;
+        self.write(words)?;
+        self.place(key, list)
+    }
+
+    /// Adds `word` to the list of `key`, the last key or a new greater one.
+    /// Lists past `LIST_BUFFER` words are written as they come.
+    pub fn append(&mut self, key: u64, word: u64) -> Result<(), Error> {
+        if self.open.as_ref().is_none_or(|open| open.key != key) {
+            self.close_list()?;
+            self.start(key)?;
+            self.open = Some(OpenList {
+                key,
+                words: Vec::with_capacity(LIST_BUFFER),
+                written: None,
+            });
+        }
+        let mut open = self.open.take().ok_or(Error::UnexpectedError)?;
+        open.words.push(word);
+        if open.words.len() == LIST_BUFFER {
+            self.spill_list(&mut open)?;
+        }
+        self.open = Some(open);
+        Ok(())
+    }
+
+    /// Checks that `key` comes after every key so far and counts it.
+    fn start(&mut self, key: u64) -> Result<(), Error> {
+        if self.last.is_some_and(|last| last >= key) {
+            return Err(Error::KeyOutOfOrder);
+        }
+        self.last = Some(key);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Writes the buffered words of `open`, behind a length to be filled in
+    /// by `close_list` if none is written yet.
+    fn spill_list(&mut self, open: &mut OpenList) -> Result<(), Error> {
+        let (list, written) = match open.written {
+            Some(written) => written,
+            None => {
+                let list = self.at;
+                self.write(&[0])?;
+                (list, 0)
+            }
+        };
+        self.write(&open.words)?;
+        open.written = Some((list, written + open.words.len() as u64));
+        open.words.clear();
+        Ok(())
+    }
+
+    /// Writes the rest of the list being appended to, if any, and puts its
+    /// key in a node.
+    fn close_list(&mut self) -> Result<(), Error> {
+        let mut open = match self.open.take() {
+            Some(open) => open,
+            None => return Ok(()),
+        };
+        let list = match open.written {
+            None => {
+                let list = self.at;
+                self.write(&[open.words.len() as u64])?;
+                self.write(&open.words)?;
+                list
+            }
+            Some(_) => {
+                self.spill_list(&mut open)?;
+                let (list, len) = open.written.ok_or(Error::UnexpectedError)?;
+                self.out.seek(SeekFrom::Start(list))?;
+                self.out.write_all(&len.to_le_bytes())?;
+                self.out.seek(SeekFrom::Start(self.at))?;
+                list
+            }
+        };
+        self.place(open.key, list)
+    }
+
+    /// Puts `key`, whose list starts at `list`, in the leaf being filled.
+    fn place(&mut self, key: u64, list: u64) -> Result<(), Error> {
+        if self.levels[0].keys.len() < self.max_keys {
+            self.levels[0].keys.push(key);
+            self.levels[0].lists.push(list);
+            return Ok(());
+        }
+        let leaf = self.flush(0)?;
+        self.separate(1, key, list, leaf)
+    }
+
+    /// Writes the nodes still being filled and the header, and puts the
+    /// image in place.
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.close_list()?;
+        let mut root = self.flush(0)?;
+        for level in 1..self.levels.len() {
+            self.levels[level].children.push(root);
+            root = if self.levels[level].keys.is_empty() {
+                // Nothing went past the child on the left, so it is the
+                // whole level.
+                self.levels[level].children[0]
+            } else {
+                self.flush(level)?
+            };
+        }
+        if self.len == 0 {
+            root = 0;
+        }
+
+        self.out.seek(SeekFrom::Start(0))?;
+        self.write(&[MAGIC, self.tag, self.len, root])?;
+        let file = self.out.into_inner().or(Err(Error::UnexpectedError))?;
+        file.sync_all()?;
+        fs::rename(&self.tmp, &self.path)?;
+        Ok(())
+    }
+
+    /// Puts `key` between `child`, the node just written below `level`, and
+    /// the one that comes next.
+    fn separate(&mut self, level: usize, key: u64, list: u64, child: u64) -> Result<(), Error> {
+        if level == self.levels.len() {
+            self.levels.push(Pending::default());
+        }
+        self.levels[level].children.push(child);
+        if self.levels[level].keys.len() < self.max_keys {
+            self.levels[level].keys.push(key);
+            self.levels[level].lists.push(list);
+            return Ok(());
+        }
+        let node = self.flush(level)?;
+        self.separate(level + 1, key, list, node)
+    }
+
+    /// Writes the node being filled on `level` and returns its offset.
+    fn flush(&mut self, level: usize) -> Result<u64, Error> {
+        let node = std::mem::take(&mut self.levels[level]);
+        let at = self.at;
+        self.write(&[node.keys.len() as u64, node.children.len() as u64])?;
+        self.write(&node.keys)?;
+        self.write(&node.lists)?;
+        self.write(&node.children)?;
+        Ok(at)
+    }
+
+    fn write(&mut self, words: &[u64]) -> Result<(), Error> {
+        for word in words {
+            self.out.write_all(&word.to_le_bytes())?;
+        }
+        self.at += (words.len() * WORD) as u64;
+        Ok(())
+    }
+}
+
+/// Read-only tree image written by [`write_image`], mapped into memory and
+/// searched where it lies. Opening one reads nothing but its header.
+#[derive(Debug)]
+pub struct Image {
+    path: PathBuf,
+    map: Mmap,
+}
+
+#[allow(dead_code)]
+impl Image {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let file = File::open(path)?;
+        // SAFETY: `write_image` replaces images by renaming a new file over
+        // them and never writes to one in place, so the mapping stays valid.
+        let map = unsafe { Mmap::map(&file)? };
+
+        let image = Image {
+            path: path.to_path_buf(),
+            map,
+        };
+        if image.map.len() < HEADER_WORDS * WORD || image.word(0) != Some(MAGIC) {
+            return Err(Error::ErrorDeserializing);
+        }
+        Ok(image)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn tag(&self) -> u64 {
+        self.word(WORD).unwrap_or(0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.word(2 * WORD).unwrap_or(0) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Words stored under `key`. Keys are compared by their packed image,
+    /// the same way they were ordered when written.
+    pub fn search<K: PackedKey>(&self, key: K) -> Option<Vec<u64>> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let needle = key.packed();
+        let mut node = self.word(3 * WORD)? as usize;
+        loop {
+            let (keys, children) = (self.word(node)? as usize, self.word(node + WORD)? as usize);
+            let key_at = |i: usize| self.word(node + (2 + i) * WORD);
+
+            let (mut lo, mut hi) = (0, keys);
+            while lo < hi {
+                let mid = (lo + hi) / 2;
+                if key_at(mid)? < needle {
+                    lo = mid + 1;
+                } else {
+                    hi = mid;
+                }
+            }
+
+            if lo < keys && key_at(lo)? == needle {
+                let list = self.word(node + (2 + keys + lo) * WORD)? as usize;
+                return self.list(list);
+            }
+            if children == 0 {
+                return None;
+            }
+            node = self.child(node, keys, lo)?;
+        }
+    }
+
+    /// Every packed key with its words, in key order.
+    pub fn entries(&self) -> Result<Vec<(u64, Vec<u64>)>, Error> {
+        let mut entries = Vec::with_capacity(self.len());
+        if !self.is_empty() {
+            let root = self.word(3 * WORD).ok_or(Error::ErrorDeserializing)?;
+            self.collect(root as usize, &mut entries)
+                .ok_or(Error::ErrorDeserializing)?;
+        }
+        Ok(entries)
+    }
+
+    fn collect(&self, node: usize, out: &mut Vec<(u64, Vec<u64>)>) -> Option<()> {
+        let (keys, children) = (self.word(node)? as usize, self.word(node + WORD)? as usize);
+        for i in 0..=keys {
+            if i < children {
+                self.collect(self.child(node, keys, i)?, out)?;
+            }
+            if i < keys {
+                let list = self.word(node + (2 + keys + i) * WORD)? as usize;
+                out.push((self.word(node + (2 + i) * WORD)?, self.list(list)?));
+            }
+        }
+        Some(())
+    }
+
+    /// Offset of child `i` of the node at `node`. Children come before their
+    /// parent, so `None` for one that does not, which only a corrupt image has.
+    fn child(&self, node: usize, keys: usize, i: usize) -> Option<usize> {
+        let child = self.word(node + (2 + 2 * keys + i) * WORD)? as usize;
+        (child < node).then_some(child)
+    }
+
+    fn list(&self, at: usize) -> Option<Vec<u64>> {
+        let len = self.word(at)? as usize;
+        (1..=len).map(|i| self.word(at + i * WORD)).collect()
+    }
+
+    fn word(&self, at: usize) -> Option<u64> {
+        let bytes = self.map.get(at..at + WORD)?;
+        Some(u64::from_le_bytes(bytes.try_into().ok()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::btree::compare::Natural;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lab-image-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn builder_image_finds_every_key() {
+        let path = dir("builder").join("index.img");
+        for len in [0u64, 1, 3, 4, 100, 2000] {
+            for t in [2, 3, 8] {
+                let mut builder = ImageBuilder::create(&path, 5, t).unwrap();
+                for key in 0..len {
+                    builder.push(key * 2, &[key, key + 1]).unwrap();
+                }
+                builder.finish().unwrap();
+
+                let image = Image::open(&path).unwrap();
+                assert_eq!((image.len() as u64, image.tag()), (len, 5));
+                for key in 0..len {
+                    assert_eq!(image.search(key * 2), Some(vec![key, key + 1]));
+                    assert_eq!(image.search(key * 2 + 1), None);
+                }
+                let keys: Vec<u64> = image.entries().unwrap().into_iter().map(|e| e.0).collect();
+                assert_eq!(keys, (0..len).map(|key| key * 2).collect::<Vec<_>>());
+            }
+        }
+    }
+
+    #[test]
+    fn appended_lists_may_outgrow_the_buffer() {
+        let path = dir("append").join("index.img");
+        let lens = [
+            1,
+            LIST_BUFFER - 1,
+            LIST_BUFFER,
+            LIST_BUFFER + 1,
+            3 * LIST_BUFFER,
+            2,
+        ];
+        let mut builder = ImageBuilder::create(&path, 0, 2).unwrap();
+        for (key, &len) in lens.iter().enumerate() {
+            for word in 0..len as u64 {
+                builder.append(key as u64, word).unwrap();
+            }
+        }
+        assert!(matches!(builder.append(0, 0), Err(Error::KeyOutOfOrder)));
+        builder.finish().unwrap();
+
+        let image = Image::open(&path).unwrap();
+        for (key, &len) in lens.iter().enumerate() {
+            assert_eq!(image.search(key as u64), Some((0..len as u64).collect()));
+        }
+    }
+
+    #[test]
+    fn builder_matches_write_image() {
+        let dir = dir("same");
+        let mut tree: BTree<u64, Vec<u64>, Natural> = BTree::with(3).unwrap();
+        let mut builder = ImageBuilder::create(&dir.join("built.img"), 1, 3).unwrap();
+        for key in 0..500 {
+            tree.insert(key * 3, vec![key]).unwrap();
+            builder.push(key * 3, &[key]).unwrap();
+        }
+        builder.finish().unwrap();
+        write_image(&tree, &dir.join("tree.img"), 1, |pos_vec| pos_vec.clone()).unwrap();
+
+        let built = Image::open(&dir.join("built.img")).unwrap();
+        let written = Image::open(&dir.join("tree.img")).unwrap();
+        assert_eq!(built.entries().unwrap(), written.entries().unwrap());
+    }
+}
@@ -0,0 +1,173 @@
+use std::marker::PhantomData;
+
+use crate::app::btree::aggregate::{Aggregate, AggregatedBTree};
+use crate::app::btree::compare::Natural;
+use crate::Error;
+
+/// Smallest start and largest end of the intervals in a subtree.
+#[derive(Debug, Clone, Copy)]
+pub struct Span<T>(PhantomData<T>);
+
+impl<T, V> Aggregate<(T, T), V> for Span<T>
+where
+    T: Copy + Ord,
+{
+    type Output = Option<(T, T)>;
+
+    fn empty() -> Self::Output {
+        None
+    }
+
+    fn lift(key: &(T, T), _value: &V) -> Self::Output {
+        Some(*key)
+    }
+
+    fn combine(lhs: &Self::Output, rhs: &Self::Output) -> Self::Output {
+        match (lhs, rhs) {
+            (Some(lhs), Some(rhs)) => Some((lhs.0.min(rhs.0), lhs.1.max(rhs.1))),
+            (Some(only), None) | (None, Some(only)) => Some(*only),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Closed intervals `[lo, hi]` ordered by `(lo, hi)`, each with the values
+/// it was added with, e.g. post index ranges of regions.
+#[derive(Debug, Clone)]
+pub struct IntervalTree<T, V>
+where
+    T: Copy + Ord,
+    V: Clone,
+{
+    tree: AggregatedBTree<(T, T), Vec<V>, Natural, Span<T>>,
+}
+
+#[allow(dead_code)]
+impl<T, V> IntervalTree<T, V>
+where
+    T: Copy + Ord,
+    V: Clone,
+{
+    pub fn new() -> Self {
+        IntervalTree {
+            tree: AggregatedBTree::new(),
+        }
+    }
+
+    pub fn with(t: usize) -> Option<Self> {
+        Some(IntervalTree {
+            tree: AggregatedBTree::with(t)?,
+        })
+    }
+
+    /// Adds `[lo, hi]`. Fails with `KeyOutOfOrder` if `lo > hi`.
+    pub fn insert(&mut self, lo: T, hi: T, value: V) -> Result<(), Error> {
+        if lo > hi {
+            return Err(Error::KeyOutOfOrder);
+        }
+        if self.tree.contains((lo, hi)) {
+            return self.tree.update((lo, hi), |values| values.push(value));
+        }
+        self.tree.insert((lo, hi), vec![value])
+    }
+
+    /// Takes `[lo, hi]` out with every value it was added with.
+    pub fn remove(&mut self, lo: T, hi: T) -> Result<Vec<V>, Error> {
+        self.tree.remove((lo, hi))
+    }
+
+    /// Values stored under exactly `[lo, hi]`.
+    pub fn search(&self, lo: T, hi: T) -> Result<&[V], Error> {
+        self.tree.search((lo, hi)).map(|values| &values[..])
+    }
+
+    /// Intervals that contain `point`, ordered by `(lo, hi)`.
+    pub fn stab(&self, point: T) -> Vec<((T, T), V)> {
+        self.overlapping(point, point)
+    }
+
+    /// Intervals that share at least one point with `[lo, hi]`, ordered by
+    /// `(lo, hi)`.
+    pub fn overlapping(&self, lo: T, hi: T) -> Vec<((T, T), V)> {
+        let mut found = vec![];
+        self.tree.visit_pruned(
+            |span| matches!(span, Some((start, end)) if *start <= hi && *end >= lo),
+            |key, values| {
+                if key.0 <= hi && key.1 >= lo {
+                    found.extend(values.iter().map(|value| (*key, value.clone())));
+                }
+            },
+        );
+        found
+    }
+}
+
+impl<T, V> Default for IntervalTree<T, V>
+where
+    T: Copy + Ord,
+    V: Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn brute(intervals: &[(u32, u32, u32)], lo: u32, hi: u32) -> Vec<((u32, u32), u32)> {
+        let mut found: Vec<_> = intervals
+            .iter()
+            .filter(|(start, end, _)| *start <= hi && *end >= lo)
+            .map(|&(start, end, value)| ((start, end), value))
+            .collect();
+        found.sort_by_key(|(key, _)| *key);
+        found
+    }
+
+    #[test]
+    fn stab_finds_every_covering_interval() {
+        let mut tree = IntervalTree::with(2).unwrap();
+        tree.insert(10, 20, 'a').unwrap();
+        tree.insert(15, 15, 'b').unwrap();
+        tree.insert(0, 9, 'c').unwrap();
+        tree.insert(10, 20, 'd').unwrap();
+
+        assert_eq!(
+            tree.stab(15),
+            vec![((10, 20), 'a'), ((10, 20), 'd'), ((15, 15), 'b')]
+        );
+        assert_eq!(tree.stab(9), vec![((0, 9), 'c')]);
+        assert_eq!(tree.stab(21), vec![]);
+        assert!(matches!(tree.insert(5, 4, 'e'), Err(Error::KeyOutOfOrder)));
+    }
+
+    #[test]
+    fn overlapping_matches_a_scan_after_removes() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut tree = IntervalTree::with(3).unwrap();
+        let mut intervals = vec![];
+        for value in 0..2_000 {
+            let lo = rng.gen_range(0..10_000);
+            let hi = lo + rng.gen_range(0..300);
+            if tree.search(lo, hi).is_err() {
+                tree.insert(lo, hi, value).unwrap();
+                intervals.push((lo, hi, value));
+            }
+        }
+        for _ in 0..800 {
+            let (lo, hi, value) = intervals.swap_remove(rng.gen_range(0..intervals.len()));
+            assert_eq!(tree.remove(lo, hi).ok(), Some(vec![value]));
+        }
+        assert!(matches!(tree.remove(1, 0), Err(Error::KeyWasNotFound)));
+
+        for _ in 0..300 {
+            let lo = rng.gen_range(0..10_500);
+            let hi = lo + rng.gen_range(0..100);
+            assert_eq!(tree.overlapping(lo, hi), brute(&intervals, lo, hi));
+            assert_eq!(tree.stab(lo), brute(&intervals, lo, lo));
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+use crate::app::btree::before_end;
+use crate::app::btree::key_value::Comparator;
+use crate::app::btree::node::{Node, NodeType};
+use crate::app::btree::search::NodeSearch;
+
+/// In-order iterator over the entries of a [`BTree`](super::BTree) whose keys
+/// fall into a range. Created by `BTree::range` and `BTree::iter`.
+#[derive(Debug)]
+pub struct Range<'a, K, V, C>
+where
+    K: Ord,
+{
+    /// Each frame is a node together with the index of the next pair to yield
+    /// from it. Frames above a node belong to the child being walked.
+    stack: Vec<(&'a Node<K, V>, usize)>,
+    end: Bound<K>,
+    cmp: PhantomData<C>,
+}
+
+impl<'a, K, V, C> Range<'a, K, V, C>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    C: Comparator<K>,
+{
+    pub(crate) fn new<S, R>(root: Option<&'a Node<K, V>>, range: R) -> Self
+    where
+        S: NodeSearch<K>,
+        R: RangeBounds<K>,
+    {
+        let mut stack = vec![];
+        let mut node = root;
+
+        while let Some(current) = node {
+            let keys = current.pairs().map_or(&[][..], |pairs| &pairs.keys[..]);
+            let (index, found) = match range.start_bound() {
+                Bound::Included(start) => match S::search::<C>(keys, start) {
+                    Ok(index) => (index, true),
+                    Err(index) => (index, false),
+                },
+                Bound::Excluded(start) => match S::search::<C>(keys, start) {
+                    Ok(index) => (index + 1, true),
+                    Err(index) => (index, false),
+                },
+                Bound::Unbounded => (0, false),
+            };
+            stack.push((current, index));
+
+            if found {
+                // An included start is the next pair itself. After an excluded
+                // start comes the whole child to its right.
+                if let (Bound::Excluded(_), Some(child)) =
+                    (range.start_bound(), current.children().get(index))
+                {
+                    Self::push_leftmost(&mut stack, child);
+                }
+                break;
+            }
+            node = current.children().get(index);
+        }
+
+        Range {
+            stack,
+            end: range.end_bound().cloned(),
+            cmp: PhantomData,
+        }
+    }
+
+    fn push_leftmost(stack: &mut Vec<(&'a Node<K, V>, usize)>, node: &'a Node<K, V>) {
+        let mut node = Some(node);
+        while let Some(current) = node {
+            stack.push((current, 0));
+            node = current.children().first();
+        }
+    }
+}
+
+impl<'a, K, V, C> Iterator for Range<'a, K, V, C>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    C: Comparator<K>,
+{
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, index)) = self.stack.pop() {
+            let pairs = node.pairs()?;
+            if index >= pairs.len() {
+                continue;
+            }
+
+            let (key, value) = (&pairs.keys[index], &pairs.values[index]);
+            if !before_end::<K, C, _>(&(Bound::Unbounded, self.end), key) {
+                self.stack.clear();
+                return None;
+            }
+
+            self.stack.push((node, index + 1));
+            if let Some(child) = node.children().get(index + 1) {
+                Self::push_leftmost(&mut self.stack, child);
+            }
+            return Some((key, value));
+        }
+        None
+    }
+}
+
+/// Pairs of one node not yet yielded, with the children that come after
+/// each of them.
+type Frame<K, V> = (
+    std::iter::Zip<std::vec::IntoIter<K>, std::vec::IntoIter<V>>,
+    std::vec::IntoIter<Node<K, V>>,
+);
+
+/// Entries cut out of a [`BTree`](super::BTree), in key order. Created by
+/// `BTree::drain_range`.
+#[derive(Debug)]
+pub struct DrainRange<K, V>
+where
+    K: Ord,
+{
+    stack: Vec<Frame<K, V>>,
+}
+
+impl<K, V> DrainRange<K, V>
+where
+    K: Ord,
+{
+    pub(crate) fn new(root: Option<Node<K, V>>) -> Self {
+        let mut drain = DrainRange { stack: vec![] };
+        if let Some(root) = root {
+            drain.push_leftmost(root);
+        }
+        drain
+    }
+
+    fn push_leftmost(&mut self, node: Node<K, V>) {
+        let mut node = Some(node);
+        while let Some(current) = node.take() {
+            let (pairs, children) = match current.into_node_type() {
+                NodeType::Internal(pairs, children) => (pairs, children),
+                NodeType::Leaf(pairs) => (pairs, vec![]),
+                NodeType::Undefined => return,
+            };
+            let mut children = children.into_iter();
+            node = children.next();
+            self.stack
+                .push((pairs.keys.into_iter().zip(pairs.values), children));
+        }
+    }
+}
+
+impl<K, V> Iterator for DrainRange<K, V>
+where
+    K: Ord,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (pairs, children) = self.stack.last_mut()?;
+            match pairs.next() {
+                Some(pair) => {
+                    if let Some(child) = children.next() {
+                        self.push_leftmost(child);
+                    }
+                    return Some(pair);
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use bincode::serialize;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::app::btree::diff::{Change, Diff};
+use crate::app::btree::node::{Comparator, Node};
+use crate::app::btree::observer::Observer;
+use crate::app::btree::search::NodeSearch;
+use crate::app::btree::BTree;
+use crate::Error;
+
+/// SHA-256 of an entry, or a sum of them.
+pub type Hash = [u8; 32];
+
+/// Keys with what one tree holds in place of another: `Some(value)` for
+/// keys that are new or changed, `None` for keys that are gone.
+pub type Changes<K, V> = Vec<(K, Option<V>)>;
+
+/// Hash of a tree with no entries.
+pub const EMPTY: Hash = [0; 32];
+
+/// SHA-256 over the key and value of one entry.
+pub fn entry<K, V>(key: &K, value: &V) -> Result<Hash, Error>
+where
+    K: Serialize,
+    V: Serialize,
+{
+    let mut hash = Sha256::new();
+    hash.update(serialize(key).or(Err(Error::ErrorSerializing))?);
+    hash.update(serialize(value).or(Err(Error::ErrorSerializing))?);
+    Ok(hash.finalize().into())
+}
+
+/// Adds `rhs` to `sum` as four wrapping 64-bit lanes. The order hashes are
+/// added in does not matter, so neither does the shape of the tree.
+pub fn add(sum: &mut Hash, rhs: &Hash) {
+    for (lane, rhs) in sum.chunks_exact_mut(8).zip(rhs.chunks_exact(8)) {
+        let lhs = u64::from_le_bytes(lane.try_into().unwrap());
+        let rhs = u64::from_le_bytes(rhs.try_into().unwrap());
+        lane.copy_from_slice(&lhs.wrapping_add(rhs).to_le_bytes());
+    }
+}
+
+impl<K, V, C, S, O> BTree<K, V, C, S, O>
+where
+    K: Copy + Clone + Ord + Serialize,
+    V: Clone + Serialize,
+    C: Comparator<K>,
+    S: NodeSearch<K>,
+    O: Observer<K>,
+{
+    /// Sum of the hashes of every entry, `EMPTY` for no entries, so trees
+    /// with the same entries hash the same whatever their shape. Each node
+    /// keeps the sum for its subtree once asked; inserts and removes clear it
+    /// on their way down, so only nodes they went through are hashed again.
+    pub fn root_hash(&self) -> Result<Hash, Error> {
+        self.root.as_ref().map_or(Ok(EMPTY), Node::digest)
+    }
+
+    /// What `to` holds in place of `self`, in key order. Subtrees with equal
+    /// hashes are skipped whole, so only the entries of those that differ
+    /// are read.
+    pub fn changes<T, P>(&self, to: &BTree<K, V, C, T, P>) -> Changes<K, V>
+    where
+        V: PartialEq,
+        T: NodeSearch<K>,
+        P: Observer<K>,
+    {
+        Diff::<K, V, C>::new(self.root.as_ref(), to.root.as_ref(), same_entries)
+            .map(|change| match change {
+                Change::Added(key, value) | Change::Changed(key, _, value) => {
+                    (*key, Some(value.clone()))
+                }
+                Change::Removed(key, _) => (*key, None),
+            })
+            .collect()
+    }
+}
+
+/// Whether two subtrees hold the same entries, going by their hashes.
+fn same_entries<K, V>(lhs: &Node<K, V>, rhs: &Node<K, V>) -> bool
+where
+    K: Clone + Ord + Serialize,
+    V: Clone + Serialize,
+{
+    lhs.same(rhs) || matches!((lhs.digest(), rhs.digest()), (Ok(lhs), Ok(rhs)) if lhs == rhs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::btree::compare::Natural;
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+    use std::collections::BTreeMap;
+    use std::ops::Bound;
+
+    type Tree = BTree<u32, u32, Natural>;
+
+    fn build(t: usize, entries: &[(u32, u32)]) -> Tree {
+        let mut tree = Tree::with(t).unwrap();
+        for (key, value) in entries {
+            tree.insert(*key, *value).unwrap();
+        }
+        tree
+    }
+
+    #[test]
+    fn root_hash_depends_on_entries_only() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut entries: Vec<_> = (0..3_000).map(|key| (key, key * 7)).collect();
+        let sorted = build(2, &entries);
+        entries.shuffle(&mut rng);
+        let shuffled = build(5, &entries);
+
+        assert_eq!(Tree::new().root_hash().unwrap(), EMPTY);
+        assert_ne!(sorted.root_hash().unwrap(), EMPTY);
+        assert_eq!(sorted.root_hash().unwrap(), shuffled.root_hash().unwrap());
+
+        entries[0].1 += 1;
+        assert_ne!(
+            build(3, &entries).root_hash().unwrap(),
+            sorted.root_hash().unwrap()
+        );
+    }
+
+    #[test]
+    fn kept_hashes_follow_every_change() {
+        let mut rng = StdRng::seed_from_u64(13);
+        let mut tree = Tree::with(3).unwrap();
+        let mut model = BTreeMap::new();
+        for round in 0..3_000 {
+            let key = rng.gen_range(0..1_000);
+            match rng.gen_range(0..4) {
+                0 | 1 => {
+                    if tree.insert(key, round).is_ok() {
+                        model.insert(key, round);
+                    }
+                }
+                2 => {
+                    assert_eq!(tree.remove(key).ok(), model.remove(&key));
+                }
+                _ => {
+                    let mut cursor = tree.lower_bound_mut(Bound::Included(key));
+                    if let Some(value) = cursor.value_mut() {
+                        *value += 1;
+                    }
+                    if let (Some(key), Some(value)) = (cursor.key(), cursor.value()) {
+                        model.insert(*key, *value);
+                    }
+                }
+            }
+            if round % 50 == 0 {
+                let entries: Vec<_> = model.iter().map(|(k, v)| (*k, *v)).collect();
+                assert_eq!(
+                    tree.root_hash().unwrap(),
+                    build(4, &entries).root_hash().unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn changes_match_the_entries_that_differ() {
+        let mut rng = StdRng::seed_from_u64(17);
+        let entries: Vec<_> = (0..5_000).map(|key| (key, key)).collect();
+        let from = build(4, &entries);
+        let mut to = build(4, &entries);
+        from.root_hash().unwrap();
+
+        let mut expected = BTreeMap::new();
+        for _ in 0..20 {
+            let key = rng.gen_range(0..6_000);
+            if to.remove(key).is_ok() {
+                expected.insert(key, None);
+            } else {
+                to.insert(key, 1).unwrap();
+                expected.insert(key, Some(1));
+            }
+        }
+        *to.search_mut(4_242).unwrap() += 1;
+        expected.insert(4_242, to.search(4_242).ok().copied());
+        expected.retain(|key, value| from.search(*key).ok() != value.as_ref());
+
+        assert_eq!(from.changes(&to), expected.into_iter().collect::<Vec<_>>());
+        assert_eq!(to.changes(&to.clone()), vec![]);
+        assert_eq!(from.changes(&Tree::new()).len(), from.len());
+    }
+}
@@ -1,41 +1,117 @@
+pub mod aggregate;
+pub mod arena;
+pub mod compare;
+pub mod composite;
+pub mod cursor;
+pub mod diff;
+pub mod fixed;
+pub mod image;
+pub mod interval;
+pub mod iter;
 pub mod key_value;
+pub mod merkle;
+pub mod mvcc;
 mod node;
+pub mod observer;
+pub mod search;
+pub mod set_ops;
+pub mod transaction;
+pub mod view;
 
+use std::cmp::Ordering;
 use std::fmt::{Debug, Display};
 use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
 
 use crate::Error;
+use composite::Prefix;
+use cursor::{Cursor, CursorMut};
+use diff::Diff;
+use iter::{DrainRange, Range};
 use key_value::KeyValue;
 use node::{Comparator, NodeType};
 use node::{Node, Split};
+use observer::{NoObserver, Observer, Sibling};
+use search::{Binary, NodeSearch};
+use set_ops::{Difference, Intersection, SymmetricDifference, Union};
+use transaction::Transaction;
+use view::LevelOrder;
 
+/// Whether `key` is not below the start of `range`.
+pub(crate) fn after_start<K, C, R>(range: &R, key: &K) -> bool
+where
+    C: Comparator<K>,
+    R: RangeBounds<K>,
+{
+    match range.start_bound() {
+        Bound::Included(start) => C::compare(key, start) != Ordering::Less,
+        Bound::Excluded(start) => C::compare(key, start) == Ordering::Greater,
+        Bound::Unbounded => true,
+    }
+}
+
+/// Whether `key` is not past the end of `range`.
+pub(crate) fn before_end<K, C, R>(range: &R, key: &K) -> bool
+where
+    C: Comparator<K>,
+    R: RangeBounds<K>,
+{
+    match range.end_bound() {
+        Bound::Included(end) => C::compare(key, end) != Ordering::Greater,
+        Bound::Excluded(end) => C::compare(key, end) == Ordering::Less,
+        Bound::Unbounded => true,
+    }
+}
+
+/// B-tree ordered by `C`, searching nodes with `S` and telling `O` about
+/// changes to its shape.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct BTree<K, V, C>
+pub struct BTree<K, V, C, S = Binary, O = NoObserver>
 where
     K: Ord,
     C: Comparator<K>,
+    S: NodeSearch<K>,
+    O: Observer<K>,
 {
     root: Option<Node<K, V>>,
     t: usize,
+    len: usize,
+    observer: O,
     cmp: PhantomData<C>,
+    search: PhantomData<S>,
 }
 
 #[allow(dead_code)]
-impl<K, V, C> BTree<K, V, C>
+impl<K, V, C, S, O> BTree<K, V, C, S, O>
 where
     K: Copy + Clone + Ord,
     V: Clone,
     C: Comparator<K>,
+    S: NodeSearch<K>,
+    O: Observer<K>,
 {
-    pub fn new() -> Self {
+    pub fn new() -> Self
+    where
+        O: Default,
+    {
         BTree {
             root: None,
             t: 2,
+            len: 0,
+            observer: O::default(),
             cmp: PhantomData,
+            search: PhantomData,
         }
     }
 
-    pub fn with(t: usize) -> Option<Self> {
+    pub fn with(t: usize) -> Option<Self>
+    where
+        O: Default,
+    {
+        Self::with_observer(t, O::default())
+    }
+
+    pub fn with_observer(t: usize, observer: O) -> Option<Self> {
         if t < 2 {
             return None;
         }
@@ -43,18 +119,37 @@ where
         Some(BTree {
             root: None,
             t,
+            len: 0,
+            observer,
             cmp: PhantomData,
+            search: PhantomData,
         })
     }
 
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    pub fn observer_mut(&mut self) -> &mut O {
+        &mut self.observer
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
     fn search_node<'a>(
         &self,
         node: &'a Node<K, V>,
         key: K,
     ) -> Result<(&'a Node<K, V>, usize), Error> {
-        match node.node_type {
-            NodeType::Internal(ref pairs, ref children) => {
-                let index = match pairs.binary_search_by(|k| C::compare(&k.key, &key)) {
+        match node.node_type() {
+            NodeType::Internal(pairs, children) => {
+                let index = match S::search::<C>(&pairs.keys, &key) {
                     Ok(index) => {
                         return Ok((node, index));
                     }
@@ -63,26 +158,43 @@ where
 
                 self.search_node(children.get(index).ok_or(Error::UnexpectedError)?, key)
             }
-            NodeType::Leaf(ref pairs) => {
-                match pairs.binary_search_by(|k| C::compare(&k.key, &key)) {
-                    Ok(index) => Ok((node, index)),
-                    Err(_) => Err(Error::KeyWasNotFound),
-                }
-            }
+            NodeType::Leaf(pairs) => match S::search::<C>(&pairs.keys, &key) {
+                Ok(index) => Ok((node, index)),
+                Err(_) => Err(Error::KeyWasNotFound),
+            },
             NodeType::Undefined => Err(Error::UnexpectedError),
         }
     }
 
     pub fn search(&self, key: K) -> Result<&V, Error> {
-        let (node, at) = self.search_node(self.root.as_ref().unwrap(), key)?;
+        let (node, at) = self.search_node(self.root.as_ref().ok_or(Error::KeyWasNotFound)?, key)?;
 
-        match node.node_type {
-            NodeType::Internal(ref pairs, _) => Ok(&pairs.get(at).unwrap().value),
-            NodeType::Leaf(ref pairs) => Ok(&pairs.get(at).unwrap().value),
+        match node.node_type() {
+            NodeType::Internal(pairs, _) => Ok(&pairs.values[at]),
+            NodeType::Leaf(pairs) => Ok(&pairs.values[at]),
             NodeType::Undefined => Err(Error::UnexpectedError),
         }
     }
 
+    pub fn search_mut(&mut self, key: K) -> Result<&mut V, Error> {
+        let mut node = self.root.as_mut().ok_or(Error::KeyWasNotFound)?;
+        loop {
+            match node.node_type_mut() {
+                NodeType::Internal(pairs, children) => match S::search::<C>(&pairs.keys, &key) {
+                    Ok(index) => return Ok(&mut pairs.values[index]),
+                    Err(index) => node = children.get_mut(index).ok_or(Error::UnexpectedError)?,
+                },
+                NodeType::Leaf(pairs) => {
+                    return match S::search::<C>(&pairs.keys, &key) {
+                        Ok(index) => Ok(&mut pairs.values[index]),
+                        Err(_) => Err(Error::KeyWasNotFound),
+                    };
+                }
+                NodeType::Undefined => return Err(Error::UnexpectedError),
+            }
+        }
+    }
+
     pub fn contains(&self, key: K) -> bool {
         if self.root.is_none() {
             return false;
@@ -91,106 +203,654 @@ where
     }
 
     pub fn insert(&mut self, key: K, value: V) -> Result<(), Error> {
-        if self.root.is_none() {
-            self.root = Some(Node::new(NodeType::Leaf(vec![(key, value).into()])));
-            return Ok(());
+        let mut root = match self.root.take() {
+            Some(root) => root,
+            None => {
+                let leaf = NodeType::Leaf(vec![(key, value).into()].into());
+                self.root = Some(Node::new(leaf, self.observer.next_id()));
+                self.len = 1;
+                return Ok(());
+            }
+        };
+
+        if root.is_full(self.t)? {
+            let split = root.split(self.t, self.observer.next_id())?;
+            let separator = split.pair.key;
+            self.observer
+                .on_split(0, root.id(), split.new_node.id(), &separator);
+            root = Node::new(
+                NodeType::Internal(vec![split.pair].into(), vec![root, split.new_node]),
+                self.observer.next_id(),
+            );
+            self.observer.on_root_grow(root.id(), &separator);
         }
 
-        let mut root = self.root.as_ref().unwrap().clone();
-        self.root = None;
+        let result = self.insert_recursive(&mut root, 0, key, value);
+        self.root = Some(root);
+        if result.is_ok() {
+            self.len += 1;
+        }
 
-        let is_root_full = root.is_full(self.t)?;
-        if is_root_full {
-            let split = root.split(self.t)?;
-            let mut new_root = Node::new(NodeType::Internal(
-                vec![split.pair],
-                vec![root, split.new_node],
-            ));
+        result.map(|_| ())
+    }
 
-            self.insert_recursive(&mut new_root, key, value)?;
-            self.root = Some(new_root);
+    pub fn remove(&mut self, key: K) -> Result<V, Error> {
+        let mut root = self.root.take().ok_or(Error::KeyWasNotFound)?;
+        let result = self.remove_recursive(&mut root, 0, key);
 
-            return Ok(());
+        self.root = match root.node_type_mut() {
+            NodeType::Internal(pairs, children) if pairs.is_empty() => {
+                let child = children.pop();
+                if let Some(ref child) = child {
+                    self.observer.on_root_shrink(child.id());
+                }
+                child
+            }
+            NodeType::Leaf(pairs) if pairs.is_empty() => None,
+            _ => Some(root),
+        };
+        if result.is_ok() {
+            self.len -= 1;
         }
 
-        if let Some(split) = match root.node_type {
-            NodeType::Internal(ref pairs, ref mut children) => {
-                let index = match pairs.binary_search_by(|k| C::compare(&k.key, &key)) {
-                    Ok(_) => return Err(Error::KeyAlreadyExists),
-                    Err(index) => index,
-                };
+        result
+    }
 
-                let is_child_full = children
-                    .get_mut(index)
-                    .ok_or(Error::KeyWasNotFound)?
-                    .is_full(self.t)?;
+    /// Starts a batch of changes that can be rolled back as a whole.
+    pub fn begin(&mut self) -> Transaction<'_, K, V, C, S, O> {
+        Transaction::new(self)
+    }
 
-                if !is_child_full {
-                    None
-                } else {
-                    self.insert_recursive(
-                        children.get_mut(index).ok_or(Error::KeyWasNotFound)?,
-                        key,
-                        value.clone(),
-                    )?
+    /// Runs `f` in a transaction that is committed if it returns `Ok` and
+    /// rolled back if it returns `Err`.
+    pub fn transaction<T, F>(&mut self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Transaction<'_, K, V, C, S, O>) -> Result<T, Error>,
+    {
+        let mut tx = self.begin();
+        let result = f(&mut tx)?;
+        tx.commit();
+        Ok(result)
+    }
+
+    /// Entries with keys in `range`, in key order.
+    pub fn range<R>(&self, range: R) -> Range<'_, K, V, C>
+    where
+        R: RangeBounds<K>,
+    {
+        Range::new::<S, R>(self.root.as_ref(), range)
+    }
+
+    pub fn iter(&self) -> Range<'_, K, V, C> {
+        self.range(..)
+    }
+
+    /// Entries whose composite key starts with `prefix`, e.g. every
+    /// `(post_index, surname)` under one post index.
+    pub fn prefix<P>(&self, prefix: P) -> Range<'_, K, V, C>
+    where
+        P: Copy,
+        K: Prefix<P>,
+    {
+        let (first, last) = (K::first(prefix), K::last(prefix));
+        match C::compare(&first, &last) {
+            Ordering::Greater => self.range(last..=first),
+            _ => self.range(first..=last),
+        }
+    }
+
+    /// Read-only views of every node, breadth first.
+    pub fn level_order(&self) -> LevelOrder<'_, K, V> {
+        LevelOrder::new(self.root.as_ref())
+    }
+
+    /// Keys in either `self` or `other`, merged lazily in key order.
+    pub fn union<'a, W, T, P>(&'a self, other: &'a BTree<K, W, C, T, P>) -> Union<'a, K, V, W, C>
+    where
+        W: Clone,
+        T: NodeSearch<K>,
+        P: Observer<K>,
+    {
+        Union::new(self.iter(), other.iter())
+    }
+
+    /// Keys in both `self` and `other`.
+    pub fn intersection<'a, W, T, P>(
+        &'a self,
+        other: &'a BTree<K, W, C, T, P>,
+    ) -> Intersection<'a, K, V, W, C>
+    where
+        W: Clone,
+        T: NodeSearch<K>,
+        P: Observer<K>,
+    {
+        Intersection::new(self.iter(), other.iter())
+    }
+
+    /// Keys in `self` but not in `other`.
+    pub fn difference<'a, W, T, P>(
+        &'a self,
+        other: &'a BTree<K, W, C, T, P>,
+    ) -> Difference<'a, K, V, W, C>
+    where
+        W: Clone,
+        T: NodeSearch<K>,
+        P: Observer<K>,
+    {
+        Difference::new(self.iter(), other.iter())
+    }
+
+    /// Keys in exactly one of `self` and `other`.
+    pub fn symmetric_difference<'a, W, T, P>(
+        &'a self,
+        other: &'a BTree<K, W, C, T, P>,
+    ) -> SymmetricDifference<'a, K, V, W, C>
+    where
+        W: Clone,
+        T: NodeSearch<K>,
+        P: Observer<K>,
+    {
+        SymmetricDifference::new(self.iter(), other.iter())
+    }
+
+    /// What changed going from `self` to `other`, in key order. Subtrees left
+    /// alone in both since one tree was cloned from the other are skipped.
+    pub fn diff<'a, T, P>(&'a self, other: &'a BTree<K, V, C, T, P>) -> Diff<'a, K, V, C>
+    where
+        V: PartialEq,
+        T: NodeSearch<K>,
+        P: Observer<K>,
+    {
+        Diff::new(self.root.as_ref(), other.root.as_ref(), Node::same)
+    }
+
+    /// Cursor at the first entry after `bound`, or at the ghost. Resumes a scan
+    /// with `Bound::Excluded(last_seen)`.
+    pub fn lower_bound(&self, bound: Bound<K>) -> Cursor<'_, K, V, C, S> {
+        Cursor::lower_bound(self.root.as_ref(), bound)
+    }
+
+    /// Cursor at the last entry before `bound`, or at the ghost position if
+    /// there is none.
+    pub fn upper_bound(&self, bound: Bound<K>) -> Cursor<'_, K, V, C, S> {
+        Cursor::upper_bound(self.root.as_ref(), bound)
+    }
+
+    pub fn lower_bound_mut(&mut self, bound: Bound<K>) -> CursorMut<'_, K, V, C, S, O> {
+        CursorMut::lower_bound(self, bound)
+    }
+
+    pub fn upper_bound_mut(&mut self, bound: Bound<K>) -> CursorMut<'_, K, V, C, S, O> {
+        CursorMut::upper_bound(self, bound)
+    }
+
+    /// Removes every entry with a key in `range`.
+    pub fn remove_range<R>(&mut self, range: R)
+    where
+        R: RangeBounds<K>,
+    {
+        self.drain_range(range);
+    }
+
+    /// Keeps only the entries for which `f` returns `true`. Only subtrees that
+    /// lose an entry are joined back together; the rest are left as they are.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        if let Some(root) = self.root.take() {
+            self.root = self
+                .retain_node(root, &mut f)
+                .expect("a valid tree can always be joined back together");
+        }
+    }
+
+    /// Removes every entry in `range` and yields them in key order. Only the
+    /// nodes on the two cut paths are rebalanced.
+    pub fn drain_range<R>(&mut self, range: R) -> DrainRange<K, V>
+    where
+        R: RangeBounds<K>,
+    {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        DrainRange::new(self.cut(range).expect("a valid tree can be cut anywhere"))
+    }
+
+    /// Takes the entries in `range` out as a detached subtree.
+    fn cut(&mut self, range: (Bound<K>, Bound<K>)) -> Result<Option<Node<K, V>>, Error> {
+        let mut left = self.root.take();
+        let observer = &mut self.observer;
+        let mut next_id = || observer.next_id();
+        let mut middle = left.as_mut().map(|root| {
+            root.split_off(&|key: &K| after_start::<K, C, _>(&range, key), &mut next_id)
+        });
+        let mut right = middle.as_mut().map(|middle| {
+            middle.split_off(&|key: &K| !before_end::<K, C, _>(&range, key), &mut next_id)
+        });
+
+        self.fix_border(&mut left, true)?;
+        self.fix_border(&mut right, false)?;
+        self.root = self.join(left, right)?;
+
+        self.len -= middle.as_ref().map_or(0, Node::count);
+        Ok(middle)
+    }
+
+    /// Repairs the right (`last`) or left edge of a tree cut by
+    /// `Node::split_off`, topping up every node on it on the way down.
+    fn fix_border(&mut self, root: &mut Option<Node<K, V>>, last: bool) -> Result<(), Error> {
+        self.shrink_root(root);
+        let mut node = match root.as_mut() {
+            Some(node) => node,
+            None => return Ok(()),
+        };
+        let mut depth = 0;
+        while let NodeType::Internal(pairs, children) = node.node_type_mut() {
+            let index = if last { children.len() - 1 } else { 0 };
+            let index = self.top_up(pairs, children, depth + 1, index, self.t)?;
+            node = &mut children[index];
+            depth += 1;
+        }
+        self.shrink_root(root);
+        Ok(())
+    }
+
+    /// Drops roots left without pairs.
+    fn shrink_root(&mut self, root: &mut Option<Node<K, V>>) {
+        loop {
+            match root.as_ref().map(Node::node_type) {
+                Some(NodeType::Internal(pairs, _)) if pairs.is_empty() => {
+                    *root = root.take().and_then(|node| match node.into_node_type() {
+                        NodeType::Internal(_, mut children) => children.pop(),
+                        _ => None,
+                    });
+                    if let Some(ref root) = root {
+                        self.observer.on_root_shrink(root.id());
+                    }
                 }
+                Some(NodeType::Leaf(pairs)) if pairs.is_empty() => *root = None,
+                _ => return,
             }
-            NodeType::Leaf(ref mut pairs) => {
-                let index = match pairs.binary_search_by(|k| C::compare(&k.key, &key)) {
-                    Ok(_) => return Err(Error::KeyAlreadyExists),
-                    Err(index) => index,
+        }
+    }
+
+    /// Gives `children[index]` at least `min` pairs from a sibling, merging the
+    /// two if they fit. Returns where the child ends up.
+    fn top_up(
+        &mut self,
+        pairs: &mut node::Pairs<K, V>,
+        children: &mut Vec<Node<K, V>>,
+        depth: usize,
+        index: usize,
+        min: usize,
+    ) -> Result<usize, Error> {
+        let short = children[index].len();
+        if short >= min {
+            return Ok(index);
+        }
+
+        let left = if index > 0 { index - 1 } else { index };
+        if children[left].len() + children[left + 1].len() < 2 * self.t - 1 {
+            self.observer.on_merge(
+                depth,
+                children[left].id(),
+                children[left + 1].id(),
+                &pairs.keys[left],
+            );
+            let right = children.remove(left + 1);
+            children[left].merge(pairs.remove(left), right)?;
+            return Ok(left);
+        }
+
+        let (lhs, rhs) = children.split_at_mut(left + 1);
+        let (lhs, rhs) = (&mut lhs[left], &mut rhs[0]);
+        let (key, value) = (&mut pairs.keys[left], &mut pairs.values[left]);
+        if index == left {
+            lhs.shift_left(key, value, rhs, min - short)?;
+            self.observer
+                .on_borrow(depth, lhs.id(), Sibling::Right, key);
+        } else {
+            lhs.shift_right(key, value, rhs, min - short)?;
+            self.observer.on_borrow(depth, rhs.id(), Sibling::Left, key);
+        }
+        Ok(index)
+    }
+
+    /// Joins two trees, every key of `left` before every key of `right`, by
+    /// hanging the shorter one off the taller one's facing edge.
+    fn join(
+        &mut self,
+        left: Option<Node<K, V>>,
+        right: Option<Node<K, V>>,
+    ) -> Result<Option<Node<K, V>>, Error> {
+        let (mut left, mut right) = match (left, right) {
+            (Some(left), Some(right)) => (Some(left), Some(right)),
+            (left, right) => return Ok(left.or(right)),
+        };
+
+        let separator = match Self::taller(&left, &right) {
+            true => self.pop_edge(&mut right, false)?,
+            false => self.pop_edge(&mut left, true)?,
+        };
+        self.join_with(left, separator, right).map(Some)
+    }
+
+    /// Joins two trees and `separator`, which goes between them.
+    fn join_with(
+        &mut self,
+        left: Option<Node<K, V>>,
+        separator: KeyValue<K, V>,
+        right: Option<Node<K, V>>,
+    ) -> Result<Node<K, V>, Error> {
+        let last = Self::taller(&left, &right);
+        let (tall, short) = if last { (left, right) } else { (right, left) };
+        let mut root = match tall {
+            Some(root) => root,
+            None => {
+                let leaf = NodeType::Leaf(vec![separator].into());
+                return Ok(Node::new(leaf, self.observer.next_id()));
+            }
+        };
+
+        let height = root.height();
+        let split = match short {
+            Some(short) if short.height() == height => {
+                let (first, second) = if last { (root, short) } else { (short, root) };
+                let mut pairs = vec![separator].into();
+                let mut children = vec![first, second];
+                let index = if children[0].len() < children[1].len() {
+                    0
+                } else {
+                    1
                 };
-                pairs.insert(index, (key, value.clone()).into());
-                self.root = Some(root);
-                return Ok(());
+                self.top_up(&mut pairs, &mut children, 1, index, self.t - 1)?;
+                let id = self.observer.next_id();
+                let mut root = Some(Node::new(NodeType::Internal(pairs, children), id));
+                self.shrink_root(&mut root);
+                return root.ok_or(Error::UnexpectedError);
             }
+            Some(short) => {
+                let levels = height - short.height() - 1;
+                self.graft(&mut root, 0, levels, separator, Some(short), last)?
+            }
+            None => self.graft(&mut root, 0, height, separator, None, last)?,
+        };
+
+        if let Some(split) = split {
+            let separator = split.pair.key;
+            root = Node::new(
+                NodeType::Internal(vec![split.pair].into(), vec![root, split.new_node]),
+                self.observer.next_id(),
+            );
+            self.observer.on_root_grow(root.id(), &separator);
+        }
+        Ok(root)
+    }
+
+    /// Whether `left` is at least as tall as `right`, counting a missing tree
+    /// as shorter than any other.
+    fn taller(left: &Option<Node<K, V>>, right: &Option<Node<K, V>>) -> bool {
+        match (left, right) {
+            (Some(left), Some(right)) => left.height() >= right.height(),
+            (_, right) => right.is_none(),
+        }
+    }
+
+    /// Drops the pairs of the subtree at `node` that `f` rejects. Children
+    /// that lost nothing are kept as they are; the rest are joined back
+    /// together around the separators that stay.
+    fn retain_node<F>(
+        &mut self,
+        mut node: Node<K, V>,
+        f: &mut F,
+    ) -> Result<Option<Node<K, V>>, Error>
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        let (pairs, children) = match node.node_type_mut() {
+            NodeType::Leaf(pairs) => {
+                let before = pairs.len();
+                pairs.retain(f);
+                self.len -= before - pairs.len();
+                return Ok(if pairs.is_empty() { None } else { Some(node) });
+            }
+            NodeType::Internal(pairs, children) => (pairs, children),
             NodeType::Undefined => return Err(Error::UnexpectedError),
-        } {
-            root.insert::<C>(split.pair, split.new_node)?;
+        };
+
+        let before = self.len;
+        let mut kept = Vec::with_capacity(children.len());
+        let mut keep = Vec::with_capacity(pairs.len());
+        for (i, child) in std::mem::take(children).into_iter().enumerate() {
+            kept.push(self.retain_node(child, f)?);
+            if i < pairs.len() {
+                keep.push(f(&pairs.keys[i], &mut pairs.values[i]));
+            }
         }
 
-        match root.node_type {
-            NodeType::Internal(ref pairs, ref mut children) => {
-                let index = match pairs.binary_search_by(|k| C::compare(&k.key, &key)) {
-                    Ok(_) => return Err(Error::KeyAlreadyExists),
-                    Err(index) => index,
-                };
-                self.insert_recursive(
-                    children.get_mut(index).ok_or(Error::KeyWasNotFound)?,
-                    key,
-                    value,
-                )?;
+        if self.len == before && keep.iter().all(|keep| *keep) {
+            *children = kept.into_iter().flatten().collect();
+            return Ok(Some(node));
+        }
+
+        let pairs = std::mem::replace(pairs, Vec::new().into());
+        let mut kept = kept.into_iter();
+        let mut joined = kept.next().flatten();
+        let pairs = pairs.keys.into_iter().zip(pairs.values);
+        for (((key, value), keep), child) in pairs.zip(keep).zip(kept) {
+            joined = match keep {
+                true => Some(self.join_with(joined, (key, value).into(), child)?),
+                false => {
+                    self.len -= 1;
+                    self.join(joined, child)?
+                }
+            };
+        }
+        Ok(joined)
+    }
+
+    /// Adds `pair` and `child` `levels` down the right (`last`) or left edge of
+    /// `node`. Returns the split of `node` if it overflows.
+    fn graft(
+        &mut self,
+        node: &mut Node<K, V>,
+        depth: usize,
+        levels: usize,
+        pair: KeyValue<K, V>,
+        child: Option<Node<K, V>>,
+        last: bool,
+    ) -> Result<Option<Split<K, V>>, Error> {
+        if levels > 0 {
+            let children = match node.node_type_mut() {
+                NodeType::Internal(_, children) => children,
+                _ => return Err(Error::UnexpectedError),
+            };
+            let index = if last { children.len() - 1 } else { 0 };
+            if let Some(split) = self.graft(
+                &mut children[index],
+                depth + 1,
+                levels - 1,
+                pair,
+                child,
+                last,
+            )? {
+                node.insert::<C, S>(split.pair, split.new_node)?;
             }
-            NodeType::Leaf(ref mut pairs) => {
-                let index = match pairs.binary_search_by(|k| C::compare(&k.key, &key)) {
-                    Ok(_) => return Err(Error::KeyAlreadyExists),
-                    Err(index) => index,
+        } else {
+            let grafted = child.is_some();
+            if last {
+                node.push_back(pair, child)?;
+            } else {
+                node.push_front(pair, child)?;
+            }
+            if let (true, NodeType::Internal(pairs, children)) = (grafted, node.node_type_mut()) {
+                // The grafted root can be short of the `t - 1` pairs a child needs.
+                let index = if last { children.len() - 1 } else { 0 };
+                self.top_up(pairs, children, depth + 1, index, self.t - 1)?;
+            }
+        }
+
+        if node.len() < 2 * self.t {
+            return Ok(None);
+        }
+        let split = node.split(self.t, self.observer.next_id())?;
+        self.observer
+            .on_split(depth, node.id(), split.new_node.id(), &split.pair.key);
+        Ok(Some(split))
+    }
+
+    /// Removes the largest (`last`) or smallest pair of a whole tree.
+    fn pop_edge(
+        &mut self,
+        root: &mut Option<Node<K, V>>,
+        last: bool,
+    ) -> Result<KeyValue<K, V>, Error> {
+        let pair = self.remove_edge(root.as_mut().ok_or(Error::UnexpectedError)?, 0, last)?;
+        self.shrink_root(root);
+        Ok(pair)
+    }
+
+    fn remove_recursive(
+        &mut self,
+        node: &mut Node<K, V>,
+        depth: usize,
+        key: K,
+    ) -> Result<V, Error> {
+        let t = self.t;
+        let (pairs, children) = match node.node_type_mut() {
+            NodeType::Leaf(pairs) => {
+                return match S::search::<C>(&pairs.keys, &key) {
+                    Ok(index) => Ok(pairs.remove(index).value),
+                    Err(_) => Err(Error::KeyWasNotFound),
                 };
-                pairs.insert(index, (key, value.clone()).into());
             }
+            NodeType::Internal(pairs, children) => (pairs, children),
             NodeType::Undefined => return Err(Error::UnexpectedError),
+        };
+
+        match S::search::<C>(&pairs.keys, &key) {
+            Ok(index) if children[index].len() >= t => {
+                let pair = self.remove_edge(&mut children[index], depth + 1, true)?;
+                pairs.keys[index] = pair.key;
+                Ok(std::mem::replace(&mut pairs.values[index], pair.value))
+            }
+            Ok(index) if children[index + 1].len() >= t => {
+                let pair = self.remove_edge(&mut children[index + 1], depth + 1, false)?;
+                pairs.keys[index] = pair.key;
+                Ok(std::mem::replace(&mut pairs.values[index], pair.value))
+            }
+            Ok(index) => {
+                self.observer.on_merge(
+                    depth + 1,
+                    children[index].id(),
+                    children[index + 1].id(),
+                    &pairs.keys[index],
+                );
+                let right = children.remove(index + 1);
+                children[index].merge(pairs.remove(index), right)?;
+                self.remove_recursive(&mut children[index], depth + 1, key)
+            }
+            Err(index) => {
+                let index = self.fill_child(pairs, children, depth + 1, index)?;
+                self.remove_recursive(&mut children[index], depth + 1, key)
+            }
         }
+    }
 
-        self.root = Some(root);
+    /// Removes the largest (`last`) or smallest pair of the subtree.
+    fn remove_edge(
+        &mut self,
+        node: &mut Node<K, V>,
+        depth: usize,
+        last: bool,
+    ) -> Result<KeyValue<K, V>, Error> {
+        match node.node_type_mut() {
+            NodeType::Leaf(_) if last => node.pop_back().map(|(pair, _)| pair),
+            NodeType::Leaf(_) => node.pop_front().map(|(pair, _)| pair),
+            NodeType::Internal(pairs, children) => {
+                let index = if last { children.len() - 1 } else { 0 };
+                let index = self.fill_child(pairs, children, depth + 1, index)?;
+                self.remove_edge(&mut children[index], depth + 1, last)
+            }
+            NodeType::Undefined => Err(Error::UnexpectedError),
+        }
+    }
 
-        Ok(())
+    /// Makes sure `children[index]` holds at least `t` pairs before descending.
+    /// Returns where the child ends up.
+    fn fill_child(
+        &mut self,
+        pairs: &mut node::Pairs<K, V>,
+        children: &mut Vec<Node<K, V>>,
+        depth: usize,
+        index: usize,
+    ) -> Result<usize, Error> {
+        let t = self.t;
+        if children[index].len() >= t {
+            return Ok(index);
+        }
+
+        if index > 0 && children[index - 1].len() >= t {
+            let (pair, child) = children[index - 1].pop_back()?;
+            let separator = pairs.remove(index - 1);
+            pairs.insert(index - 1, pair);
+            children[index].push_front(separator, child)?;
+            self.observer.on_borrow(
+                depth,
+                children[index].id(),
+                Sibling::Left,
+                &pairs.keys[index - 1],
+            );
+            return Ok(index);
+        }
+
+        if index + 1 < children.len() && children[index + 1].len() >= t {
+            let (pair, child) = children[index + 1].pop_front()?;
+            let separator = pairs.remove(index);
+            pairs.insert(index, pair);
+            children[index].push_back(separator, child)?;
+            self.observer.on_borrow(
+                depth,
+                children[index].id(),
+                Sibling::Right,
+                &pairs.keys[index],
+            );
+            return Ok(index);
+        }
+
+        let index = if index + 1 < children.len() {
+            index
+        } else {
+            index - 1
+        };
+        self.observer.on_merge(
+            depth,
+            children[index].id(),
+            children[index + 1].id(),
+            &pairs.keys[index],
+        );
+        let right = children.remove(index + 1);
+        children[index].merge(pairs.remove(index), right)?;
+        Ok(index)
     }
 
     fn insert_recursive(
         &mut self,
         node: &mut Node<K, V>,
+        depth: usize,
         key: K,
         value: V,
     ) -> Result<Option<Split<K, V>>, Error> {
         let is_full = node.is_full(self.t)?;
         if is_full {
-            let split = node.split(self.t)?;
+            let split = node.split(self.t, self.observer.next_id())?;
+            self.observer
+                .on_split(depth, node.id(), split.new_node.id(), &split.pair.key);
             return Ok(Some(split));
         }
 
-        if let Some(split) = match node.node_type {
-            NodeType::Internal(ref pairs, ref mut children) => {
-                let index = match pairs.binary_search_by(|k| C::compare(&k.key, &key)) {
+        if let Some(split) = match node.node_type_mut() {
+            NodeType::Internal(pairs, children) => {
+                let index = match S::search::<C>(&pairs.keys, &key) {
                     Ok(_) => return Err(Error::KeyAlreadyExists),
                     Err(index) => index,
                 };
@@ -203,6 +863,7 @@ where
                 if !is_child_full {
                     self.insert_recursive(
                         children.get_mut(index).ok_or(Error::KeyWasNotFound)?,
+                        depth + 1,
                         key,
                         value.clone(),
                     )?;
@@ -210,13 +871,14 @@ where
                 } else {
                     self.insert_recursive(
                         children.get_mut(index).ok_or(Error::KeyWasNotFound)?,
+                        depth + 1,
                         key,
                         value.clone(),
                     )?
                 }
             }
-            NodeType::Leaf(ref mut pairs) => {
-                let index = match pairs.binary_search_by(|k| C::compare(&k.key, &key)) {
+            NodeType::Leaf(pairs) => {
+                let index = match S::search::<C>(&pairs.keys, &key) {
                     Ok(_) => return Err(Error::KeyAlreadyExists),
                     Err(index) => index,
                 };
@@ -225,23 +887,24 @@ where
             }
             NodeType::Undefined => return Err(Error::UnexpectedError),
         } {
-            node.insert::<C>(split.pair, split.new_node)?;
+            node.insert::<C, S>(split.pair, split.new_node)?;
         }
 
-        match node.node_type {
-            NodeType::Internal(ref pairs, ref mut children) => {
-                let index = match pairs.binary_search_by(|k| C::compare(&k.key, &key)) {
+        match node.node_type_mut() {
+            NodeType::Internal(pairs, children) => {
+                let index = match S::search::<C>(&pairs.keys, &key) {
                     Ok(_) => return Err(Error::KeyAlreadyExists),
                     Err(index) => index,
                 };
                 self.insert_recursive(
                     children.get_mut(index).ok_or(Error::KeyWasNotFound)?,
+                    depth + 1,
                     key,
                     value,
                 )
             }
-            NodeType::Leaf(ref mut pairs) => {
-                let index = match pairs.binary_search_by(|k| C::compare(&k.key, &key)) {
+            NodeType::Leaf(pairs) => {
+                let index = match S::search::<C>(&pairs.keys, &key) {
                     Ok(_) => return Err(Error::KeyAlreadyExists),
                     Err(index) => index,
                 };
@@ -253,22 +916,26 @@ where
     }
 }
 
-impl<K, V, C> Default for BTree<K, V, C>
+impl<K, V, C, S, O> Default for BTree<K, V, C, S, O>
 where
     K: Copy + Clone + Ord,
     V: Clone,
     C: Comparator<K>,
+    S: NodeSearch<K>,
+    O: Observer<K> + Default,
 {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl<K, V, C> Display for BTree<K, V, C>
+impl<K, V, C, S, O> Display for BTree<K, V, C, S, O>
 where
     K: Copy + Clone + Ord + Display,
     V: Clone + Display,
     C: Comparator<K>,
+    S: NodeSearch<K>,
+    O: Observer<K>,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self.root {
@@ -281,3 +948,188 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::btree::compare::Natural;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::collections::BTreeMap;
+
+    type Tree = BTree<u32, u32, Natural>;
+
+    /// Checks fill, key order and leaf depth of every node and returns the
+    /// number of pairs below `node`.
+    fn check_node(
+        node: &Node<u32, u32>,
+        t: usize,
+        depth: usize,
+        leaf: &mut Option<usize>,
+    ) -> usize {
+        let keys = &node.pairs().unwrap().keys;
+        assert!(!keys.is_empty() && keys.len() < 2 * t);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        if node.is_leaf() {
+            assert_eq!(*leaf.get_or_insert(depth), depth);
+            return keys.len();
+        }
+
+        assert_eq!(node.children().len(), keys.len() + 1);
+        let mut count = keys.len();
+        for (i, child) in node.children().iter().enumerate() {
+            let below = &child.pairs().unwrap().keys;
+            assert!(below.len() >= t - 1);
+            assert!(i == 0 || below[0] > keys[i - 1]);
+            assert!(i == keys.len() || below[below.len() - 1] < keys[i]);
+            count += check_node(child, t, depth + 1, leaf);
+        }
+        count
+    }
+
+    fn check(tree: &Tree, model: &BTreeMap<u32, u32>) {
+        let count = tree
+            .root
+            .as_ref()
+            .map_or(0, |root| check_node(root, tree.t, 0, &mut None));
+        assert_eq!(count, tree.len());
+        assert_eq!(tree.len(), model.len());
+        assert!(tree
+            .iter()
+            .map(|(k, v)| (*k, *v))
+            .eq(model.iter().map(|(k, v)| (*k, *v))));
+    }
+
+    fn bound(rng: &mut StdRng) -> Bound<u32> {
+        let key = rng.gen_range(0..1_000);
+        match rng.gen_range(0..3) {
+            0 => Bound::Included(key),
+            1 => Bound::Excluded(key),
+            _ => Bound::Unbounded,
+        }
+    }
+
+    /// Whether `BTreeMap::range` accepts the bounds.
+    fn valid(lo: Bound<u32>, hi: Bound<u32>) -> bool {
+        match (lo, hi) {
+            (Bound::Excluded(a), Bound::Excluded(b)) => a < b,
+            (Bound::Included(a) | Bound::Excluded(a), Bound::Included(b) | Bound::Excluded(b)) => {
+                a <= b
+            }
+            _ => true,
+        }
+    }
+
+    #[test]
+    fn matches_std_btree_map() {
+        for t in [2, 3, 4, 7] {
+            let mut rng = StdRng::seed_from_u64(t as u64);
+            let mut tree = Tree::with(t).unwrap();
+            let mut model = BTreeMap::new();
+            for step in 0..4_000 {
+                let key = rng.gen_range(0..1_000);
+                match rng.gen_range(0..100) {
+                    0..=54 => {
+                        let result = tree.insert(key, step);
+                        assert_eq!(result.is_ok(), !model.contains_key(&key));
+                        model.entry(key).or_insert(step);
+                    }
+                    55..=84 => assert_eq!(tree.remove(key).ok(), model.remove(&key)),
+                    85..=89 => {
+                        let (lo, hi) = (bound(&mut rng), bound(&mut rng));
+                        if !valid(lo, hi) {
+                            continue;
+                        }
+                        let drained: Vec<(u32, u32)> = tree.drain_range((lo, hi)).collect();
+                        let expected: Vec<u32> = model.range((lo, hi)).map(|(k, _)| *k).collect();
+                        let expected: Vec<(u32, u32)> = expected
+                            .iter()
+                            .map(|k| (*k, model.remove(k).unwrap()))
+                            .collect();
+                        assert_eq!(drained, expected);
+                    }
+                    90..=94 => {
+                        let modulus = rng.gen_range(1..8);
+                        let keep = |key: &u32, value: &mut u32| {
+                            *value += 1;
+                            !key.is_multiple_of(modulus)
+                        };
+                        tree.retain(keep);
+                        model.retain(keep);
+                    }
+                    _ => {
+                        let at = bound(&mut rng);
+                        let lower = model.range((at, Bound::Unbounded)).next().map(|(k, _)| k);
+                        let upper = model
+                            .range((Bound::Unbounded, at))
+                            .next_back()
+                            .map(|(k, _)| k);
+                        assert_eq!(tree.lower_bound(at).key(), lower);
+                        assert_eq!(tree.upper_bound(at).key(), upper);
+                    }
+                }
+                check(&tree, &model);
+            }
+        }
+    }
+
+    #[test]
+    fn retain_keeping_everything_leaves_nodes_alone() {
+        let mut tree = Tree::with(3).unwrap();
+        for key in 0..500 {
+            tree.insert(key, key).unwrap();
+        }
+        let shape = |tree: &Tree| -> Vec<Vec<u32>> {
+            tree.level_order().map(|view| view.keys.to_vec()).collect()
+        };
+        let before = shape(&tree);
+
+        let mut seen = vec![];
+        tree.retain(|key, _| {
+            seen.push(*key);
+            true
+        });
+
+        assert_eq!(seen, (0..500).collect::<Vec<_>>());
+        assert_eq!(shape(&tree), before);
+
+        tree.retain(|key, _| *key < 10 || *key >= 490);
+        check(
+            &tree,
+            &(0..10).chain(490..500).map(|key| (key, key)).collect(),
+        );
+    }
+
+    #[test]
+    fn observer_names_nodes_and_names_stick() {
+        let mut tree: BTree<u32, u32, Natural, Binary, observer::Counts> =
+            BTree::with_observer(2, observer::Counts::default()).unwrap();
+        for key in 0..200 {
+            tree.insert(key, key).unwrap();
+        }
+        let ids = |tree: &BTree<u32, u32, Natural, Binary, observer::Counts>| {
+            let mut ids: Vec<_> = tree
+                .level_order()
+                .map(|view| (view.keys[0], view.id))
+                .collect();
+            ids.sort_unstable();
+            ids
+        };
+        let before = ids(&tree);
+        let mut unique: Vec<_> = before.iter().map(|(_, id)| *id).collect();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(unique.len(), before.len());
+        assert!(unique
+            .iter()
+            .all(|id| *id > 0 && *id <= tree.observer().nodes as u64));
+
+        tree.search_mut(100).map(|value| *value += 1).unwrap();
+        assert_eq!(ids(&tree), before);
+
+        let mut plain = Tree::with(2).unwrap();
+        for key in 0..50 {
+            plain.insert(key, key).unwrap();
+        }
+        assert!(plain.level_order().all(|view| view.id == 0));
+    }
+}
@@ -0,0 +1,433 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard};
+
+use crate::app::btree::key_value::Comparator;
+use crate::app::btree::BTree;
+use crate::Error;
+
+/// One value of a key, visible to readers at versions `begin..end`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Versioned<V> {
+    pub begin: u64,
+    pub end: u64,
+    pub value: V,
+}
+
+impl<V> Versioned<V> {
+    fn is_visible(&self, at: u64) -> bool {
+        self.begin <= at && at < self.end
+    }
+
+    fn is_live(&self) -> bool {
+        self.end == u64::MAX
+    }
+}
+
+/// B-tree that keeps old values so a reader pinned at version `N` sees the
+/// tree as of `N`. Writes become visible together on `commit`.
+#[derive(Debug, Clone)]
+pub struct MvccBTree<K, V, C>
+where
+    K: Ord,
+    C: Comparator<K>,
+{
+    tree: BTree<K, Vec<Versioned<V>>, C>,
+    /// Last committed version.
+    version: u64,
+    /// Number of readers pinned at each version.
+    pinned: BTreeMap<u64, usize>,
+    /// Keys that got a value ended, with the version it ended at, oldest
+    /// first. These are the only keys `vacuum` has to look at.
+    ended: VecDeque<(u64, K)>,
+}
+
+#[allow(dead_code)]
+impl<K, V, C> MvccBTree<K, V, C>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    C: Comparator<K>,
+{
+    pub fn new() -> Self {
+        MvccBTree {
+            tree: BTree::new(),
+            version: 0,
+            pinned: BTreeMap::new(),
+            ended: VecDeque::new(),
+        }
+    }
+
+    pub fn with(t: usize) -> Option<Self> {
+        Some(MvccBTree {
+            tree: BTree::with(t)?,
+            version: 0,
+            pinned: BTreeMap::new(),
+            ended: VecDeque::new(),
+        })
+    }
+
+    /// Last committed version.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Registers a reader at the last committed version and returns it.
+    /// Nothing that version can see is vacuumed until it is unpinned.
+    pub fn pin(&mut self) -> u64 {
+        *self.pinned.entry(self.version).or_insert(0) += 1;
+        self.version
+    }
+
+    pub fn unpin(&mut self, version: u64) {
+        if let Some(count) = self.pinned.get_mut(&version) {
+            *count -= 1;
+            if *count == 0 {
+                self.pinned.remove(&version);
+            }
+        }
+    }
+
+    /// Value of `key` as seen at version `at`.
+    pub fn get(&self, key: K, at: u64) -> Option<&V> {
+        let versions = self.tree.search(key).ok()?;
+        versions
+            .iter()
+            .find(|versioned| versioned.is_visible(at))
+            .map(|versioned| &versioned.value)
+    }
+
+    /// Entries with keys in `range` as seen at version `at`, in key order.
+    pub fn range<R>(&self, at: u64, range: R) -> impl Iterator<Item = (&K, &V)>
+    where
+        R: RangeBounds<K>,
+    {
+        self.tree.range(range).filter_map(move |(key, versions)| {
+            versions
+                .iter()
+                .find(|versioned| versioned.is_visible(at))
+                .map(|versioned| (key, &versioned.value))
+        })
+    }
+
+    /// Sets `key` to `value` from the next version on.
+    pub fn put(&mut self, key: K, value: V) -> Result<(), Error> {
+        let next = self.version + 1;
+        let versioned = Versioned {
+            begin: next,
+            end: u64::MAX,
+            value,
+        };
+
+        let versions = match self.tree.search_mut(key) {
+            Ok(versions) => versions,
+            Err(_) => return self.tree.insert(key, vec![versioned]),
+        };
+        match versions.last_mut() {
+            Some(last) if last.is_live() && last.begin == next => *last = versioned,
+            Some(last) => {
+                if last.is_live() {
+                    last.end = next;
+                    self.ended.push_back((next, key));
+                }
+                versions.push(versioned);
+            }
+            None => versions.push(versioned),
+        }
+        Ok(())
+    }
+
+    /// Removes `key` from the next version on.
+    pub fn delete(&mut self, key: K) -> Result<(), Error> {
+        let next = self.version + 1;
+        let versions = self.tree.search_mut(key)?;
+        match versions.last_mut() {
+            Some(last) if last.is_live() && last.begin == next => {
+                versions.pop();
+                if versions.is_empty() {
+                    self.tree.remove(key)?;
+                }
+            }
+            Some(last) if last.is_live() => {
+                last.end = next;
+                self.ended.push_back((next, key));
+            }
+            _ => return Err(Error::KeyWasNotFound),
+        }
+        Ok(())
+    }
+
+    /// Makes every `put` and `delete` since the last commit visible and
+    /// returns the new version.
+    pub fn commit(&mut self) -> u64 {
+        self.version += 1;
+        self.version
+    }
+
+    /// Drops values no reader can see anymore, going through at most `limit`
+    /// keys. Returns how many it went through.
+    pub fn vacuum(&mut self, limit: usize) -> usize {
+        let horizon = self
+            .pinned
+            .keys()
+            .next()
+            .copied()
+            .unwrap_or(self.version)
+            .min(self.version);
+
+        let mut done = 0;
+        while done < limit {
+            let key = match self.ended.front() {
+                Some(&(end, key)) if end <= horizon => key,
+                _ => break,
+            };
+            self.ended.pop_front();
+            done += 1;
+
+            if let Ok(versions) = self.tree.search_mut(key) {
+                versions.retain(|versioned| versioned.end > horizon);
+                if versions.is_empty() {
+                    let _ = self.tree.remove(key);
+                }
+            }
+        }
+        done
+    }
+}
+
+impl<K, V, C> Default for MvccBTree<K, V, C>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    C: Comparator<K>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Handle on an [`MvccBTree`] shared between threads, locked only for one
+/// `write` or one read at a time.
+#[derive(Debug)]
+pub struct SharedMvcc<K, V, C>
+where
+    K: Ord,
+    C: Comparator<K>,
+{
+    tree: Arc<RwLock<MvccBTree<K, V, C>>>,
+}
+
+impl<K, V, C> Clone for SharedMvcc<K, V, C>
+where
+    K: Ord,
+    C: Comparator<K>,
+{
+    fn clone(&self) -> Self {
+        SharedMvcc {
+            tree: Arc::clone(&self.tree),
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl<K, V, C> SharedMvcc<K, V, C>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    C: Comparator<K>,
+{
+    pub fn new(tree: MvccBTree<K, V, C>) -> Self {
+        SharedMvcc {
+            tree: Arc::new(RwLock::new(tree)),
+        }
+    }
+
+    /// Runs `f` on the tree with every other handle kept out.
+    pub fn write<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut MvccBTree<K, V, C>) -> R,
+    {
+        f(&mut self.tree.write().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Reader pinned at the last committed version until it is dropped.
+    pub fn snapshot(&self) -> MvccSnapshot<K, V, C> {
+        let version = self.write(MvccBTree::pin);
+        MvccSnapshot {
+            shared: self.clone(),
+            version,
+        }
+    }
+}
+
+/// Consistent view of a [`SharedMvcc`] at one version. Created by
+/// `SharedMvcc::snapshot`.
+#[derive(Debug)]
+pub struct MvccSnapshot<K, V, C>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    C: Comparator<K>,
+{
+    shared: SharedMvcc<K, V, C>,
+    version: u64,
+}
+
+#[allow(dead_code)]
+impl<K, V, C> MvccSnapshot<K, V, C>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    C: Comparator<K>,
+{
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn get(&self, key: K) -> Option<V> {
+        self.read().get(key, self.version).cloned()
+    }
+
+    /// Up to `limit` entries after `after`, in key order. Pass
+    /// `Bound::Excluded` of the last key seen to get the next chunk.
+    pub fn chunk(&self, after: Bound<K>, limit: usize) -> Vec<(K, V)> {
+        self.read()
+            .range(self.version, (after, Bound::Unbounded))
+            .take(limit)
+            .map(|(key, value)| (*key, value.clone()))
+            .collect()
+    }
+
+    /// Entries with keys in `range`, in key order.
+    pub fn range<R>(&self, range: R) -> Vec<(K, V)>
+    where
+        R: RangeBounds<K>,
+    {
+        self.read()
+            .range(self.version, range)
+            .map(|(key, value)| (*key, value.clone()))
+            .collect()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, MvccBTree<K, V, C>> {
+        self.shared
+            .tree
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<K, V, C> Drop for MvccSnapshot<K, V, C>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    C: Comparator<K>,
+{
+    fn drop(&mut self) {
+        let version = self.version;
+        self.shared.write(|tree| tree.unpin(version));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::btree::compare::Natural;
+
+    type Tree = MvccBTree<u32, u32, Natural>;
+
+    fn live(tree: &Tree, at: u64) -> Vec<(u32, u32)> {
+        tree.range(at, ..)
+            .map(|(key, value)| (*key, *value))
+            .collect()
+    }
+
+    #[test]
+    fn readers_keep_the_version_they_pinned() {
+        let mut tree = Tree::with(2).unwrap();
+        for key in 0..10 {
+            tree.put(key, key).unwrap();
+        }
+        let first = tree.commit();
+        let pinned = tree.pin();
+
+        tree.put(3, 30).unwrap();
+        tree.delete(4).unwrap();
+        tree.put(10, 10).unwrap();
+        assert_eq!(
+            live(&tree, pinned),
+            (0..10).map(|key| (key, key)).collect::<Vec<_>>()
+        );
+        let second = tree.commit();
+
+        assert_eq!(pinned, first);
+        assert_eq!(tree.get(3, first), Some(&3));
+        assert_eq!(tree.get(3, second), Some(&30));
+        assert_eq!(tree.get(4, first), Some(&4));
+        assert_eq!(tree.get(4, second), None);
+        assert_eq!(tree.get(10, first), None);
+        assert_eq!(live(&tree, second).len(), 10);
+    }
+
+    #[test]
+    fn delete_after_put_in_one_commit() {
+        let mut tree = Tree::new();
+        tree.put(1, 1).unwrap();
+        tree.put(2, 2).unwrap();
+        let before = tree.commit();
+
+        tree.put(1, 10).unwrap();
+        tree.delete(1).unwrap();
+        tree.put(3, 3).unwrap();
+        tree.delete(3).unwrap();
+        assert!(matches!(tree.delete(3), Err(Error::KeyWasNotFound)));
+        tree.delete(2).unwrap();
+        tree.put(2, 20).unwrap();
+        let after = tree.commit();
+
+        assert_eq!(live(&tree, before), vec![(1, 1), (2, 2)]);
+        assert_eq!(live(&tree, after), vec![(2, 20)]);
+        tree.vacuum(usize::MAX);
+        assert_eq!(live(&tree, after), vec![(2, 20)]);
+        assert_eq!(tree.tree.len(), 1);
+    }
+
+    #[test]
+    fn vacuum_spares_what_a_pinned_reader_sees() {
+        let shared = SharedMvcc::new(Tree::with(3).unwrap());
+        shared.write(|tree| {
+            for key in 0..100 {
+                tree.put(key, 0).unwrap();
+            }
+            tree.commit()
+        });
+        let reader = shared.snapshot();
+
+        for round in 1..=5 {
+            shared.write(|tree| {
+                for key in 0..100 {
+                    tree.put(key, round).unwrap();
+                }
+                tree.commit();
+                tree.vacuum(usize::MAX);
+            });
+        }
+        assert_eq!(reader.get(42), Some(0));
+        assert_eq!(reader.range(..).len(), 100);
+        assert!(reader.range(..).iter().all(|(_, value)| *value == 0));
+        shared.write(|tree| {
+            assert!(tree
+                .tree
+                .iter()
+                .all(|(_, versions)| versions.len() == 6 && versions[0].value == 0));
+        });
+
+        drop(reader);
+        let latest = shared.snapshot();
+        shared.write(|tree| tree.vacuum(usize::MAX));
+        assert_eq!(latest.get(42), Some(5));
+        shared.write(|tree| {
+            assert!(tree.tree.iter().all(|(_, versions)| versions.len() == 1));
+        });
+    }
+}
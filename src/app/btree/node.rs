@@ -1,6 +1,11 @@
 use std::fmt::Display;
+use std::sync::{Arc, OnceLock};
+
+use serde::Serialize;
 
 pub use crate::app::btree::key_value::Comparator;
+use crate::app::btree::merkle::{self, Hash};
+use crate::app::btree::search::NodeSearch;
 use crate::app::btree::KeyValue;
 use crate::Error;
 
@@ -15,18 +20,155 @@ impl<K: Ord, V> Split<K, V> {
     }
 }
 
+/// Entries of a node. Keys are kept apart from the values so that searching
+/// a node scans one packed `[K]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pairs<K, V> {
+    pub keys: Vec<K>,
+    pub values: Vec<V>,
+}
+
+#[allow(dead_code)]
+impl<K: Ord, V> Pairs<K, V> {
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn insert(&mut self, index: usize, pair: KeyValue<K, V>) {
+        self.keys.insert(index, pair.key);
+        self.values.insert(index, pair.value);
+    }
+
+    pub fn remove(&mut self, index: usize) -> KeyValue<K, V> {
+        KeyValue {
+            key: self.keys.remove(index),
+            value: self.values.remove(index),
+        }
+    }
+
+    pub fn split_off(&mut self, at: usize) -> Self {
+        Pairs {
+            keys: self.keys.split_off(at),
+            values: self.values.split_off(at),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.keys.iter().zip(self.values.iter())
+    }
+
+    /// Keeps only the pairs for which `f` returns `true`, in order.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        let keep: Vec<bool> = self
+            .keys
+            .iter()
+            .zip(self.values.iter_mut())
+            .map(|(key, value)| f(key, value))
+            .collect();
+        let mut flags = keep.iter();
+        self.keys.retain(|_| *flags.next().unwrap_or(&true));
+        let mut flags = keep.iter();
+        self.values.retain(|_| *flags.next().unwrap_or(&true));
+    }
+}
+
+impl<K: Ord, V> std::convert::From<Vec<KeyValue<K, V>>> for Pairs<K, V> {
+    fn from(pairs: Vec<KeyValue<K, V>>) -> Self {
+        let (keys, values) = pairs.into_iter().map(|kv| (kv.key, kv.value)).unzip();
+        Pairs { keys, values }
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum NodeType<K: Ord, V> {
-    Internal(Vec<KeyValue<K, V>>, Vec<Node<K, V>>),
-    Leaf(Vec<KeyValue<K, V>>),
+    Internal(Pairs<K, V>, Vec<Node<K, V>>),
+    Leaf(Pairs<K, V>),
     Undefined,
 }
 
+/// Number a node keeps for its whole life, handed out by the tree's
+/// [`Observer`](super::observer::Observer).
+pub type NodeId = u64;
+
 #[allow(dead_code)]
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug)]
 pub struct Node<K: Ord, V> {
-    pub node_type: NodeType<K, V>,
+    node_type: NodeType<K, V>,
+    id: NodeId,
+    /// Shared by a node and its clones until either is changed, so two nodes
+    /// holding the same token hold the same subtree.
+    lineage: OnceLock<Arc<()>>,
+    /// Sum of the entry hashes of the subtree, kept once asked for.
+    digest: OnceLock<Hash>,
+}
+
+impl<K: Ord + Clone, V: Clone> Clone for Node<K, V> {
+    fn clone(&self) -> Self {
+        let lineage = self.lineage.get_or_init(|| Arc::new(())).clone();
+        Node {
+            node_type: self.node_type.clone(),
+            id: self.id,
+            lineage: OnceLock::from(lineage),
+            digest: self.digest.clone(),
+        }
+    }
+}
+
+/// Nodes are equal when they hold the same entries; ids are not compared.
+impl<K: Ord, V: PartialEq> PartialEq for Node<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.node_type == other.node_type
+    }
+}
+
+impl<K: Ord, V: Eq> Eq for Node<K, V> {}
+
+#[allow(dead_code)]
+impl<K: Ord, V> Node<K, V> {
+    pub fn node_type(&self) -> &NodeType<K, V> {
+        &self.node_type
+    }
+
+    /// Entries and children for changing, which parts the node from its
+    /// clones. Every node on the way down to a change goes through here.
+    pub fn node_type_mut(&mut self) -> &mut NodeType<K, V> {
+        self.touch();
+        &mut self.node_type
+    }
+
+    /// Like `node_type_mut` but leaves the node tied to its clones, for
+    /// cursors that `touch` every node above an entry before handing it out.
+    pub(crate) fn node_type_untouched(&mut self) -> &mut NodeType<K, V> {
+        &mut self.node_type
+    }
+
+    /// Parts the node from its clones and forgets its digest ahead of a
+    /// change below it.
+    pub fn touch(&mut self) {
+        self.lineage.take();
+        self.digest.take();
+    }
+
+    pub fn into_node_type(self) -> NodeType<K, V> {
+        self.node_type
+    }
+
+    /// Whether `self` and `other` are copies of one subtree that neither has
+    /// changed since. `false` says nothing either way.
+    pub fn same(&self, other: &Self) -> bool {
+        match (self.lineage.get(), other.lineage.get()) {
+            (Some(lhs), Some(rhs)) => Arc::ptr_eq(lhs, rhs),
+            _ => false,
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -35,27 +177,40 @@ where
     K: Clone + Ord,
     V: Clone,
 {
-    pub fn new(node_type: NodeType<K, V>) -> Self {
-        Node { node_type }
+    pub fn new(node_type: NodeType<K, V>, id: NodeId) -> Self {
+        Node {
+            node_type,
+            id,
+            lineage: OnceLock::new(),
+            digest: OnceLock::new(),
+        }
     }
 
-    pub fn split(&mut self, t: usize) -> Result<Split<K, V>, Error> {
-        match self.node_type {
-            NodeType::Internal(ref mut key_val_pairs, ref mut children) => {
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// Moves the upper half to a new sibling named `id`.
+    pub fn split(&mut self, t: usize, id: NodeId) -> Result<Split<K, V>, Error> {
+        match self.node_type_mut() {
+            NodeType::Internal(key_val_pairs, children) => {
                 let mut sibling_pairs = key_val_pairs.split_off(t - 1);
                 let median = sibling_pairs.remove(0);
                 let sibling_children = children.split_off(t);
 
                 Ok(Split::new(
                     median,
-                    Node::new(NodeType::Internal(sibling_pairs, sibling_children)),
+                    Node::new(NodeType::Internal(sibling_pairs, sibling_children), id),
                 ))
             }
-            NodeType::Leaf(ref mut key_val_pairs) => {
+            NodeType::Leaf(key_val_pairs) => {
                 let sibling_pairs = key_val_pairs.split_off(t);
                 let median = key_val_pairs.remove(t - 1);
 
-                Ok(Split::new(median, Node::new(NodeType::Leaf(sibling_pairs))))
+                Ok(Split::new(
+                    median,
+                    Node::new(NodeType::Leaf(sibling_pairs), id),
+                ))
             }
             NodeType::Undefined => Err(Error::UnexpectedError),
         }
@@ -69,13 +224,14 @@ where
         }
     }
 
-    pub fn insert<C>(&mut self, pair: KeyValue<K, V>, child: Self) -> Result<(), Error>
+    pub fn insert<C, S>(&mut self, pair: KeyValue<K, V>, child: Self) -> Result<(), Error>
     where
         C: Comparator<K>,
+        S: NodeSearch<K>,
     {
-        match self.node_type {
-            NodeType::Internal(ref mut pairs, ref mut children) => {
-                let index = match pairs.binary_search_by(|k| C::compare(&k.key, &pair.key)) {
+        match self.node_type_mut() {
+            NodeType::Internal(pairs, children) => {
+                let index = match S::search::<C>(&pairs.keys, &pair.key) {
                     Ok(_) => {
                         return Err(Error::KeyAlreadyExists);
                     }
@@ -90,6 +246,222 @@ where
             _ => Err(Error::UnexpectedError),
         }
     }
+
+    pub fn len(&self) -> usize {
+        self.pairs().map_or(0, Pairs::len)
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        matches!(self.node_type, NodeType::Leaf(_))
+    }
+
+    pub fn pairs(&self) -> Option<&Pairs<K, V>> {
+        match self.node_type {
+            NodeType::Internal(ref pairs, _) | NodeType::Leaf(ref pairs) => Some(pairs),
+            NodeType::Undefined => None,
+        }
+    }
+
+    pub fn children(&self) -> &[Node<K, V>] {
+        match self.node_type {
+            NodeType::Internal(_, ref children) => children,
+            _ => &[],
+        }
+    }
+
+    /// Appends `separator` followed by every pair and child of `right`.
+    pub fn merge(&mut self, separator: KeyValue<K, V>, right: Self) -> Result<(), Error> {
+        match (self.node_type_mut(), right.node_type) {
+            (
+                NodeType::Internal(pairs, children),
+                NodeType::Internal(right_pairs, right_children),
+            ) => {
+                pairs.keys.push(separator.key);
+                pairs.values.push(separator.value);
+                pairs.keys.extend(right_pairs.keys);
+                pairs.values.extend(right_pairs.values);
+                children.extend(right_children);
+                Ok(())
+            }
+            (NodeType::Leaf(pairs), NodeType::Leaf(right_pairs)) => {
+                pairs.keys.push(separator.key);
+                pairs.values.push(separator.value);
+                pairs.keys.extend(right_pairs.keys);
+                pairs.values.extend(right_pairs.values);
+                Ok(())
+            }
+            _ => Err(Error::UnexpectedError),
+        }
+    }
+
+    pub fn push_front(&mut self, pair: KeyValue<K, V>, child: Option<Self>) -> Result<(), Error> {
+        match (self.node_type_mut(), child) {
+            (NodeType::Internal(pairs, children), Some(child)) => {
+                pairs.insert(0, pair);
+                children.insert(0, child);
+                Ok(())
+            }
+            (NodeType::Leaf(pairs), None) => {
+                pairs.insert(0, pair);
+                Ok(())
+            }
+            _ => Err(Error::UnexpectedError),
+        }
+    }
+
+    pub fn push_back(&mut self, pair: KeyValue<K, V>, child: Option<Self>) -> Result<(), Error> {
+        match (self.node_type_mut(), child) {
+            (NodeType::Internal(pairs, children), Some(child)) => {
+                pairs.insert(pairs.len(), pair);
+                children.push(child);
+                Ok(())
+            }
+            (NodeType::Leaf(pairs), None) => {
+                pairs.insert(pairs.len(), pair);
+                Ok(())
+            }
+            _ => Err(Error::UnexpectedError),
+        }
+    }
+
+    pub fn pop_front(&mut self) -> Result<(KeyValue<K, V>, Option<Self>), Error> {
+        match self.node_type_mut() {
+            NodeType::Internal(pairs, children) if !pairs.is_empty() => {
+                Ok((pairs.remove(0), Some(children.remove(0))))
+            }
+            NodeType::Leaf(pairs) if !pairs.is_empty() => Ok((pairs.remove(0), None)),
+            _ => Err(Error::UnexpectedError),
+        }
+    }
+
+    pub fn pop_back(&mut self) -> Result<(KeyValue<K, V>, Option<Self>), Error> {
+        match self.node_type_mut() {
+            NodeType::Internal(pairs, children) if !pairs.is_empty() => {
+                Ok((pairs.remove(pairs.len() - 1), children.pop()))
+            }
+            NodeType::Leaf(pairs) if !pairs.is_empty() => Ok((pairs.remove(pairs.len() - 1), None)),
+            _ => Err(Error::UnexpectedError),
+        }
+    }
+
+    /// Moves the last `n` pairs of `self` to its right sibling `right` through
+    /// the separator `key`/`value`, children along.
+    pub fn shift_right(
+        &mut self,
+        key: &mut K,
+        value: &mut V,
+        right: &mut Self,
+        n: usize,
+    ) -> Result<(), Error> {
+        let (pairs, right_pairs) = match (self.node_type_mut(), right.node_type_mut()) {
+            (
+                NodeType::Internal(pairs, children),
+                NodeType::Internal(right_pairs, right_children),
+            ) => {
+                let moved = children.split_off(children.len() - n);
+                right_children.splice(0..0, moved);
+                (pairs, right_pairs)
+            }
+            (NodeType::Leaf(pairs), NodeType::Leaf(right_pairs)) => (pairs, right_pairs),
+            _ => return Err(Error::UnexpectedError),
+        };
+
+        let mut moved = pairs.split_off(pairs.len() - n);
+        let separator = moved.remove(0);
+        moved.keys.push(std::mem::replace(key, separator.key));
+        moved.values.push(std::mem::replace(value, separator.value));
+        right_pairs.keys.splice(0..0, moved.keys);
+        right_pairs.values.splice(0..0, moved.values);
+        Ok(())
+    }
+
+    /// Moves the first `n` pairs of `right`, the right sibling of `self`,
+    /// to the end of `self`, the other way round from `shift_right`.
+    pub fn shift_left(
+        &mut self,
+        key: &mut K,
+        value: &mut V,
+        right: &mut Self,
+        n: usize,
+    ) -> Result<(), Error> {
+        let (pairs, right_pairs) = match (self.node_type_mut(), right.node_type_mut()) {
+            (
+                NodeType::Internal(pairs, children),
+                NodeType::Internal(right_pairs, right_children),
+            ) => {
+                children.extend(right_children.drain(..n));
+                (pairs, right_pairs)
+            }
+            (NodeType::Leaf(pairs), NodeType::Leaf(right_pairs)) => (pairs, right_pairs),
+            _ => return Err(Error::UnexpectedError),
+        };
+
+        let rest = right_pairs.split_off(n);
+        let mut moved = std::mem::replace(right_pairs, rest);
+        let separator = moved.remove(n - 1);
+        pairs.keys.push(std::mem::replace(key, separator.key));
+        pairs.values.push(std::mem::replace(value, separator.value));
+        pairs.keys.extend(moved.keys);
+        pairs.values.extend(moved.values);
+        Ok(())
+    }
+
+    /// Moves the pairs for which `goes_right` holds, a suffix of the keys, to a
+    /// new subtree of the same height with nodes named by `next_id`. Nodes on
+    /// the cut path may underflow.
+    pub fn split_off<F, I>(&mut self, goes_right: &F, next_id: &mut I) -> Self
+    where
+        F: Fn(&K) -> bool,
+        I: FnMut() -> NodeId,
+    {
+        match self.node_type_mut() {
+            NodeType::Internal(pairs, children) => {
+                let at = pairs.keys.partition_point(|key| !goes_right(key));
+                let right_pairs = pairs.split_off(at);
+                let mut right_children = children.split_off(at + 1);
+                right_children.insert(0, children[at].split_off(goes_right, next_id));
+                Node::new(NodeType::Internal(right_pairs, right_children), next_id())
+            }
+            NodeType::Leaf(pairs) => {
+                let at = pairs.keys.partition_point(|key| !goes_right(key));
+                Node::new(NodeType::Leaf(pairs.split_off(at)), next_id())
+            }
+            NodeType::Undefined => Node::new(NodeType::Undefined, next_id()),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.len() + self.children().iter().map(Node::count).sum::<usize>()
+    }
+
+    /// Number of levels below this node.
+    pub fn height(&self) -> usize {
+        self.children()
+            .first()
+            .map_or(0, |child| child.height() + 1)
+    }
+}
+
+impl<K, V> Node<K, V>
+where
+    K: Clone + Ord + Serialize,
+    V: Clone + Serialize,
+{
+    /// Sum of the hashes of every entry in the subtree, see
+    /// `BTree::root_hash`.
+    pub fn digest(&self) -> Result<Hash, Error> {
+        if let Some(digest) = self.digest.get() {
+            return Ok(*digest);
+        }
+        let mut digest = merkle::EMPTY;
+        for (key, value) in self.pairs().into_iter().flat_map(Pairs::iter) {
+            merkle::add(&mut digest, &merkle::entry(key, value)?);
+        }
+        for child in self.children() {
+            merkle::add(&mut digest, &child.digest()?);
+        }
+        Ok(*self.digest.get_or_init(|| digest))
+    }
 }
 
 impl<K, V> Node<K, V>
@@ -104,7 +476,7 @@ where
                     "[{}]",
                     pairs
                         .iter()
-                        .map(|x| x.to_string())
+                        .map(|(key, value)| format!("({}, {})", key, value))
                         .collect::<Vec<String>>()
                         .join(", ")
                 );
@@ -119,7 +491,7 @@ where
                     "[{}]",
                     pairs
                         .iter()
-                        .map(|x| x.to_string())
+                        .map(|(key, value)| format!("({}, {})", key, value))
                         .collect::<Vec<String>>()
                         .join(", ")
                 );
@@ -0,0 +1,82 @@
+pub use crate::app::btree::node::NodeId;
+
+/// Sibling a node borrowed a pair from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sibling {
+    Left,
+    Right,
+}
+
+/// Hooks a [`BTree`](super::BTree) calls when its shape changes, with nodes
+/// named by [`NodeId`] and `depth` 0 at the root. All default to nothing.
+pub trait Observer<K> {
+    /// Id for a node the tree is about to create. The default names every
+    /// node 0, so observers that tell nodes apart count up here.
+    fn next_id(&mut self) -> NodeId {
+        0
+    }
+
+    /// Full `node` at `depth` was split in two: its upper half went to the
+    /// new sibling `new` and `separator` moved up into the parent.
+    fn on_split(&mut self, _depth: usize, _node: NodeId, _new: NodeId, _separator: &K) {}
+
+    /// `merged`, the right sibling of `node`, was merged into it around
+    /// `separator`.
+    fn on_merge(&mut self, _depth: usize, _node: NodeId, _merged: NodeId, _separator: &K) {}
+
+    /// `node` at `depth` took pairs from its `from` sibling through the
+    /// parent, where `separator` is now the key between the two.
+    fn on_borrow(&mut self, _depth: usize, _node: NodeId, _from: Sibling, _separator: &K) {}
+
+    /// The root was split and `root`, holding only `separator`, was put on
+    /// top of it.
+    fn on_root_grow(&mut self, _root: NodeId, _separator: &K) {}
+
+    /// The root ran out of keys and its only child, `root`, took its place.
+    fn on_root_shrink(&mut self, _root: NodeId) {}
+}
+
+/// The default observer, which ignores everything.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NoObserver;
+
+impl<K> Observer<K> for NoObserver {}
+
+/// Tallies every kind of event, e.g. for benchmarks, and numbers nodes in
+/// the order they were created.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counts {
+    pub nodes: usize,
+    pub splits: usize,
+    pub merges: usize,
+    pub borrows: usize,
+    pub root_grows: usize,
+    pub root_shrinks: usize,
+}
+
+impl<K> Observer<K> for Counts {
+    fn next_id(&mut self) -> NodeId {
+        self.nodes += 1;
+        self.nodes as NodeId
+    }
+
+    fn on_split(&mut self, _depth: usize, _node: NodeId, _new: NodeId, _separator: &K) {
+        self.splits += 1;
+    }
+
+    fn on_merge(&mut self, _depth: usize, _node: NodeId, _merged: NodeId, _separator: &K) {
+        self.merges += 1;
+    }
+
+    fn on_borrow(&mut self, _depth: usize, _node: NodeId, _from: Sibling, _separator: &K) {
+        self.borrows += 1;
+    }
+
+    fn on_root_grow(&mut self, _root: NodeId, _separator: &K) {
+        self.root_grows += 1;
+    }
+
+    fn on_root_shrink(&mut self, _root: NodeId) {
+        self.root_shrinks += 1;
+    }
+}
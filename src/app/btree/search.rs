@@ -0,0 +1,72 @@
+use std::cmp::Ordering;
+
+use crate::app::btree::key_value::Comparator;
+
+/// How a node finds a key among its sorted keys, as
+/// `slice::binary_search_by` does.
+pub trait NodeSearch<K> {
+    fn search<C: Comparator<K>>(keys: &[K], key: &K) -> Result<usize, usize>;
+}
+
+/// Plain binary search. The default, as it pulls ahead of the scans from
+/// t = 32 up in `benches/node_search.rs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Binary;
+
+impl<K> NodeSearch<K> for Binary {
+    fn search<C: Comparator<K>>(keys: &[K], key: &K) -> Result<usize, usize> {
+        keys.binary_search_by(|k| C::compare(k, key))
+    }
+}
+
+/// Counts the keys that are smaller than `key` without an early exit, which
+/// the CPU can pipeline without mispredicted branches on small nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Linear;
+
+impl<K> NodeSearch<K> for Linear {
+    fn search<C: Comparator<K>>(keys: &[K], key: &K) -> Result<usize, usize> {
+        let index = keys
+            .iter()
+            .map(|k| (C::compare(k, key) == Ordering::Less) as usize)
+            .sum();
+        found_at::<K, C>(keys, key, index)
+    }
+}
+
+/// Keys that map onto `u64` preserving the order of the tree's comparator.
+pub trait PackedKey: Copy {
+    fn packed(&self) -> u64;
+}
+
+macro_rules! packed_unsigned {
+    ($($t:ty),*) => {
+        $(impl PackedKey for $t {
+            fn packed(&self) -> u64 {
+                *self as u64
+            }
+        })*
+    };
+}
+
+packed_unsigned!(u8, u16, u32, u64, usize);
+
+/// [`Linear`] on the `u64` image of the keys, so the loop vectorises. Keys
+/// must order the same way `PackedKey::packed` does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Packed;
+
+impl<K: PackedKey> NodeSearch<K> for Packed {
+    fn search<C: Comparator<K>>(keys: &[K], key: &K) -> Result<usize, usize> {
+        let needle = key.packed();
+        let index = keys.iter().map(|k| (k.packed() < needle) as usize).sum();
+        found_at::<K, C>(keys, key, index)
+    }
+}
+
+fn found_at<K, C: Comparator<K>>(keys: &[K], key: &K, index: usize) -> Result<usize, usize> {
+    match keys.get(index) {
+        Some(k) if C::compare(k, key) == Ordering::Equal => Ok(index),
+        _ => Err(index),
+    }
+}
@@ -0,0 +1,126 @@
+use std::cmp::Ordering;
+use std::iter::Peekable;
+
+use crate::app::btree::iter::Range;
+use crate::app::btree::key_value::Comparator;
+
+/// Walks the entries of two trees side by side in the order of `C`. Every
+/// step yields the smaller of the two heads, or both when their keys are equal.
+#[derive(Debug)]
+pub(crate) struct Merge<'a, K, V, W, C>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    W: Clone,
+    C: Comparator<K>,
+{
+    lhs: Peekable<Range<'a, K, V, C>>,
+    rhs: Peekable<Range<'a, K, W, C>>,
+}
+
+impl<'a, K, V, W, C> Merge<'a, K, V, W, C>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    W: Clone,
+    C: Comparator<K>,
+{
+    pub(crate) fn new(lhs: Range<'a, K, V, C>, rhs: Range<'a, K, W, C>) -> Self {
+        Merge {
+            lhs: lhs.peekable(),
+            rhs: rhs.peekable(),
+        }
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn next(&mut self) -> Option<(Option<(&'a K, &'a V)>, Option<(&'a K, &'a W)>)> {
+        let order = match (self.lhs.peek(), self.rhs.peek()) {
+            (Some((lhs, _)), Some((rhs, _))) => C::compare(lhs, rhs),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => return None,
+        };
+
+        match order {
+            Ordering::Less => Some((self.lhs.next(), None)),
+            Ordering::Greater => Some((None, self.rhs.next())),
+            Ordering::Equal => Some((self.lhs.next(), self.rhs.next())),
+        }
+    }
+}
+
+macro_rules! set_op {
+    ($(#[$doc:meta])* $name:ident, |$lhs:ident, $rhs:ident| $pick:expr) => {
+        $(#[$doc])*
+        #[derive(Debug)]
+        pub struct $name<'a, K, V, W, C>
+        where
+            K: Copy + Clone + Ord,
+            V: Clone,
+            W: Clone,
+            C: Comparator<K>,
+        {
+            merge: Merge<'a, K, V, W, C>,
+        }
+
+        impl<'a, K, V, W, C> $name<'a, K, V, W, C>
+        where
+            K: Copy + Clone + Ord,
+            V: Clone,
+            W: Clone,
+            C: Comparator<K>,
+        {
+            pub(crate) fn new(lhs: Range<'a, K, V, C>, rhs: Range<'a, K, W, C>) -> Self {
+                $name {
+                    merge: Merge::new(lhs, rhs),
+                }
+            }
+        }
+
+        impl<'a, K, V, W, C> Iterator for $name<'a, K, V, W, C>
+        where
+            K: Copy + Clone + Ord,
+            V: Clone,
+            W: Clone,
+            C: Comparator<K>,
+        {
+            type Item = &'a K;
+
+            fn next(&mut self) -> Option<Self::Item> {
+                while let Some((lhs, rhs)) = self.merge.next() {
+                    let ($lhs, $rhs) = (lhs.map(|(key, _)| key), rhs.map(|(key, _)| key));
+                    if let Some(key) = $pick {
+                        return Some(key);
+                    }
+                }
+                None
+            }
+        }
+    };
+}
+
+set_op!(
+    /// Keys found in either tree, each yielded once. Created by `BTree::union`.
+    Union,
+    |lhs, rhs| lhs.or(rhs)
+);
+
+set_op!(
+    /// Keys found in both trees. Created by `BTree::intersection`.
+    Intersection,
+    |lhs, rhs| lhs.and(rhs)
+);
+
+set_op!(
+    /// Keys of the left tree missing from the right one. Created by
+    /// `BTree::difference`.
+    Difference,
+    |lhs, rhs| if rhs.is_none() { lhs } else { None }
+);
+
+set_op!(
+    /// Keys found in exactly one of the trees. Created by
+    /// `BTree::symmetric_difference`.
+    SymmetricDifference,
+    |lhs, rhs| lhs.xor(rhs)
+);
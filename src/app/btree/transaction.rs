@@ -0,0 +1,228 @@
+use crate::app::btree::key_value::Comparator;
+use crate::app::btree::observer::Observer;
+use crate::app::btree::search::NodeSearch;
+use crate::app::btree::BTree;
+use crate::Error;
+
+/// How to take back one change made inside a transaction.
+#[derive(Debug, Clone)]
+enum Undo<K, V> {
+    Inserted(K),
+    Removed(K, V),
+    Replaced(K, V),
+    /// Run on the value to take back an `edit`.
+    Edited(K, fn(&mut V)),
+}
+
+/// Changes to a [`BTree`] that all stay on `commit` or all go on `rollback`
+/// or drop. Created by `BTree::begin`.
+#[derive(Debug)]
+pub struct Transaction<'a, K, V, C, S, O>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    C: Comparator<K>,
+    S: NodeSearch<K>,
+    O: Observer<K>,
+{
+    tree: &'a mut BTree<K, V, C, S, O>,
+    undo: Vec<Undo<K, V>>,
+}
+
+#[allow(dead_code)]
+impl<'a, K, V, C, S, O> Transaction<'a, K, V, C, S, O>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    C: Comparator<K>,
+    S: NodeSearch<K>,
+    O: Observer<K>,
+{
+    pub(crate) fn new(tree: &'a mut BTree<K, V, C, S, O>) -> Self {
+        Transaction { tree, undo: vec![] }
+    }
+
+    pub fn search(&self, key: K) -> Result<&V, Error> {
+        self.tree.search(key)
+    }
+
+    pub fn contains(&self, key: K) -> bool {
+        self.tree.contains(key)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Result<(), Error> {
+        self.tree.insert(key, value)?;
+        self.undo.push(Undo::Inserted(key));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: K) -> Result<V, Error> {
+        let value = self.tree.remove(key)?;
+        self.undo.push(Undo::Removed(key, value.clone()));
+        Ok(value)
+    }
+
+    /// Puts `value` under an existing `key` and returns the old value.
+    pub fn replace(&mut self, key: K, value: V) -> Result<V, Error> {
+        let old = std::mem::replace(self.tree.search_mut(key)?, value);
+        self.undo.push(Undo::Replaced(key, old.clone()));
+        Ok(old)
+    }
+
+    /// Mutates the value under `key` in place, keeping a copy of it to roll
+    /// back to.
+    pub fn update<F>(&mut self, key: K, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut V),
+    {
+        let value = self.tree.search_mut(key)?;
+        self.undo.push(Undo::Replaced(key, value.clone()));
+        f(value);
+        Ok(())
+    }
+
+    /// Mutates the value under `key` in place; rolling back runs `undo` on it
+    /// instead of restoring a copy.
+    pub fn edit<F>(&mut self, key: K, f: F, undo: fn(&mut V)) -> Result<(), Error>
+    where
+        F: FnOnce(&mut V),
+    {
+        f(self.tree.search_mut(key)?);
+        self.undo.push(Undo::Edited(key, undo));
+        Ok(())
+    }
+
+    pub fn commit(mut self) {
+        self.undo.clear();
+    }
+
+    /// Takes back every change, newest first.
+    pub fn rollback(mut self) {
+        self.undo_all();
+    }
+
+    fn undo_all(&mut self) {
+        // Each step restores the state the next older one was made in, so
+        // none of them can fail.
+        while let Some(undo) = self.undo.pop() {
+            let _ = match undo {
+                Undo::Inserted(key) => self.tree.remove(key).map(|_| ()),
+                Undo::Removed(key, value) => self.tree.insert(key, value),
+                Undo::Replaced(key, value) => {
+                    self.tree.search_mut(key).map(|current| *current = value)
+                }
+                Undo::Edited(key, undo) => self.tree.search_mut(key).map(undo),
+            };
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl<K, T, C, S, O> Transaction<'_, K, Vec<T>, C, S, O>
+where
+    K: Copy + Clone + Ord,
+    T: Clone,
+    C: Comparator<K>,
+    S: NodeSearch<K>,
+    O: Observer<K>,
+{
+    /// Appends `item` to the `Vec` under `key`, which rolling back pops.
+    pub fn push(&mut self, key: K, item: T) -> Result<(), Error> {
+        self.edit(
+            key,
+            |items| items.push(item),
+            |items| {
+                items.pop();
+            },
+        )
+    }
+}
+
+impl<K, V, C, S, O> Drop for Transaction<'_, K, V, C, S, O>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+    C: Comparator<K>,
+    S: NodeSearch<K>,
+    O: Observer<K>,
+{
+    fn drop(&mut self) {
+        self.undo_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::btree::compare::Natural;
+    use crate::app::btree::BTree;
+    use crate::Error;
+
+    fn tree() -> BTree<u32, Vec<u32>, Natural> {
+        let mut tree = BTree::with(2).unwrap();
+        for key in 0..50 {
+            tree.insert(key, vec![key]).unwrap();
+        }
+        tree
+    }
+
+    fn entries(tree: &BTree<u32, Vec<u32>, Natural>) -> Vec<(u32, Vec<u32>)> {
+        tree.iter()
+            .map(|(key, value)| (*key, value.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn failed_transaction_leaves_tree_as_it_was() {
+        let mut tree = tree();
+        let before = entries(&tree);
+
+        let result: Result<(), Error> = tree.transaction(|tx| {
+            tx.insert(100, vec![])?;
+            tx.remove(3)?;
+            tx.replace(4, vec![0])?;
+            tx.update(5, |value| value.clear())?;
+            tx.push(6, 60)?;
+            tx.push(100, 1)?;
+            for key in 10..40 {
+                tx.remove(key)?;
+            }
+            tx.insert(7, vec![])
+        });
+
+        assert!(matches!(result, Err(Error::KeyAlreadyExists)));
+        assert_eq!(entries(&tree), before);
+        assert_eq!(tree.len(), 50);
+    }
+
+    #[test]
+    fn commit_keeps_changes() {
+        let mut tree = tree();
+        tree.transaction(|tx| {
+            tx.remove(3)?;
+            tx.push(6, 60)?;
+            tx.insert(100, vec![1])
+        })
+        .unwrap();
+
+        assert!(!tree.contains(3));
+        assert_eq!(tree.search(6).ok(), Some(&vec![6, 60]));
+        assert_eq!(tree.search(100).ok(), Some(&vec![1]));
+    }
+
+    #[test]
+    fn dropping_uncommitted_transaction_rolls_back() {
+        let mut tree = tree();
+        let before = entries(&tree);
+        {
+            let mut tx = tree.begin();
+            tx.push(1, 10).unwrap();
+            tx.remove(2).unwrap();
+        }
+        assert_eq!(entries(&tree), before);
+
+        let mut tx = tree.begin();
+        tx.push(1, 10).unwrap();
+        tx.rollback();
+        assert_eq!(entries(&tree), before);
+    }
+}
@@ -0,0 +1,67 @@
+use std::collections::VecDeque;
+
+use crate::app::btree::node::{Node, NodeId};
+
+/// Read-only look at a single node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeView<'a, K> {
+    /// Given by the tree's observer when the node was created and kept for
+    /// as long as it exists.
+    pub id: NodeId,
+    /// `None` for the root.
+    pub parent: Option<NodeId>,
+    /// Distance from the root, which is at 0.
+    pub depth: usize,
+    pub keys: &'a [K],
+    pub child_count: usize,
+    pub is_leaf: bool,
+}
+
+/// Nodes of a [`BTree`](super::BTree) level by level, left to right within a
+/// level. Created by `BTree::level_order`.
+#[derive(Debug)]
+pub struct LevelOrder<'a, K, V>
+where
+    K: Ord,
+{
+    queue: VecDeque<(&'a Node<K, V>, usize, Option<NodeId>)>,
+}
+
+impl<'a, K, V> LevelOrder<'a, K, V>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+{
+    pub(crate) fn new(root: Option<&'a Node<K, V>>) -> Self {
+        LevelOrder {
+            queue: root.map(|root| (root, 0, None)).into_iter().collect(),
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for LevelOrder<'a, K, V>
+where
+    K: Copy + Clone + Ord,
+    V: Clone,
+{
+    type Item = NodeView<'a, K>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (node, depth, parent) = self.queue.pop_front()?;
+        let children = node.children();
+        self.queue.extend(
+            children
+                .iter()
+                .map(|child| (child, depth + 1, Some(node.id()))),
+        );
+
+        Some(NodeView {
+            id: node.id(),
+            parent,
+            depth,
+            keys: node.pairs().map_or(&[][..], |pairs| &pairs.keys[..]),
+            child_count: children.len(),
+            is_leaf: node.is_leaf(),
+        })
+    }
+}
@@ -0,0 +1,221 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::Error;
+
+/// Bytes of one spilled `(key, position)` pair.
+const PAIR: usize = 16;
+/// Runs merged at once, unless told otherwise.
+const DEFAULT_FAN_IN: usize = 64;
+
+/// Sorts `(key, position)` pairs that need not fit in memory, spilling
+/// sorted chunks next to `base` and merging up to `fan_in` at a time.
+#[derive(Debug)]
+pub struct ExternalSorter {
+    base: PathBuf,
+    chunk: usize,
+    fan_in: usize,
+    pairs: Vec<(u64, u64)>,
+    runs: Vec<PathBuf>,
+    /// Run files created so far, for naming the next one.
+    created: usize,
+}
+
+#[allow(dead_code)]
+impl ExternalSorter {
+    /// Sorter spilling to files named after `base`. `None` if `chunk` is 0.
+    pub fn new(base: &Path, chunk: usize) -> Option<Self> {
+        Self::with(base, chunk, DEFAULT_FAN_IN)
+    }
+
+    /// Sorter that merges up to `fan_in` runs at once. `None` if `chunk` is
+    /// 0 or `fan_in` is below 2.
+    pub fn with(base: &Path, chunk: usize, fan_in: usize) -> Option<Self> {
+        if chunk == 0 || fan_in < 2 {
+            return None;
+        }
+        Some(ExternalSorter {
+            base: base.to_path_buf(),
+            chunk,
+            fan_in,
+            pairs: Vec::with_capacity(chunk),
+            runs: vec![],
+            created: 0,
+        })
+    }
+
+    pub fn runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    pub fn push(&mut self, key: u64, pos: u64) -> Result<(), Error> {
+        self.pairs.push((key, pos));
+        if self.pairs.len() == self.chunk {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Every pair pushed, ordered by key and then by position, merging the
+    /// oldest `fan_in` runs into one while there are more.
+    pub fn finish(mut self) -> Result<Merge, Error> {
+        if !self.pairs.is_empty() {
+            self.spill()?;
+        }
+
+        while self.runs.len() > self.fan_in {
+            let mut merge = Merge::open(self.runs.drain(..self.fan_in).collect())?;
+            let mut out = self.create_run()?;
+            while let Some((key, pos)) = merge.next_pair()? {
+                write_pair(&mut out, key, pos)?;
+            }
+            out.flush()?;
+        }
+        Merge::open(std::mem::take(&mut self.runs))
+    }
+
+    fn spill(&mut self) -> Result<(), Error> {
+        self.pairs.sort_unstable();
+        let mut out = self.create_run()?;
+        for (key, pos) in self.pairs.drain(..) {
+            write_pair(&mut out, key, pos)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// New run file, removed with the sorter if it never gets merged.
+    fn create_run(&mut self) -> Result<BufWriter<File>, Error> {
+        let path = self.base.with_extension(format!("sort{}", self.created));
+        self.created += 1;
+        let out = BufWriter::new(File::create(&path)?);
+        self.runs.push(path);
+        Ok(out)
+    }
+}
+
+fn write_pair(out: &mut BufWriter<File>, key: u64, pos: u64) -> Result<(), Error> {
+    out.write_all(&key.to_le_bytes())?;
+    out.write_all(&pos.to_le_bytes())?;
+    Ok(())
+}
+
+impl Drop for ExternalSorter {
+    fn drop(&mut self) {
+        for path in &self.runs {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// K-way merge of the runs of an [`ExternalSorter`]. Its run files are
+/// removed when it is dropped.
+#[derive(Debug)]
+pub struct Merge {
+    runs: Vec<BufReader<File>>,
+    /// Smallest pair not yet handed out from every run that has one left.
+    heap: BinaryHeap<Reverse<(u64, u64, usize)>>,
+    paths: Vec<PathBuf>,
+}
+
+impl Merge {
+    /// Merge of the runs at `paths`, which it then owns.
+    fn open(paths: Vec<PathBuf>) -> Result<Self, Error> {
+        let mut merge = Merge {
+            runs: vec![],
+            heap: BinaryHeap::new(),
+            paths,
+        };
+        for path in &merge.paths {
+            merge.runs.push(BufReader::new(File::open(path)?));
+        }
+        for run in 0..merge.runs.len() {
+            merge.refill(run)?;
+        }
+        Ok(merge)
+    }
+
+    fn refill(&mut self, run: usize) -> Result<(), Error> {
+        let mut bytes = [0; PAIR];
+        match self.runs[run].read_exact(&mut bytes) {
+            Ok(()) => {
+                let (key, pos) = bytes.split_at(PAIR / 2);
+                let key = u64::from_le_bytes(key.try_into().or(Err(Error::ErrorDeserializing))?);
+                let pos = u64::from_le_bytes(pos.try_into().or(Err(Error::ErrorDeserializing))?);
+                self.heap.push(Reverse((key, pos, run)));
+                Ok(())
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Next pair, in order. The positions of a key come one by one, so a
+    /// key shared by any number of records costs no memory.
+    pub fn next_pair(&mut self) -> Result<Option<(u64, u64)>, Error> {
+        match self.heap.pop() {
+            Some(Reverse((key, pos, run))) => {
+                self.refill(run)?;
+                Ok(Some((key, pos)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Drop for Merge {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lab-sort-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn sorted(dir: &Path, chunk: usize, fan_in: usize, pairs: &[(u64, u64)]) -> Vec<(u64, u64)> {
+        let mut sorter = ExternalSorter::with(&dir.join("index"), chunk, fan_in).unwrap();
+        for &(key, pos) in pairs {
+            sorter.push(key, pos).unwrap();
+        }
+        let mut merge = sorter.finish().unwrap();
+        let mut out = vec![];
+        while let Some(pair) = merge.next_pair().unwrap() {
+            out.push(pair);
+        }
+        out
+    }
+
+    #[test]
+    fn pairs_come_out_sorted() {
+        let dir = dir("sorted");
+        let pairs: Vec<(u64, u64)> = (0..1000).map(|pos| (pos * 7919 % 101, pos)).collect();
+        let mut expected = pairs.clone();
+        expected.sort_unstable();
+
+        for (chunk, fan_in) in [(1000, 2), (64, 64), (10, 3), (7, 2)] {
+            assert_eq!(sorted(&dir, chunk, fan_in, &pairs), expected);
+            assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        }
+        assert!(sorted(&dir, 5, 2, &[]).is_empty());
+    }
+
+    #[test]
+    fn rejects_degenerate_settings() {
+        let base = Path::new("index");
+        assert!(ExternalSorter::new(base, 0).is_none());
+        assert!(ExternalSorter::with(base, 10, 1).is_none());
+    }
+}
@@ -1,67 +1,59 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+use crate::app::btree::composite::Column;
+
+/// Bytes a [`Fixed`] holds.
+const LEN: usize = 25;
+
+/// Text of up to 25 bytes of UTF-8, padded with zeros, so that it always
+/// encodes to exactly 25 bytes whatever the text is.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
 pub struct Fixed {
-    str: [char; 25],
+    str: [u8; LEN],
 }
 
 #[allow(dead_code)]
 impl Fixed {
-    pub fn new(str: [char; 25]) -> Self {
+    pub fn new(str: [u8; LEN]) -> Self {
         Self { str }
     }
 
+    /// As much of `str` as fits in whole chars.
     pub fn from(str: &str) -> Self {
-        let mut result: [char; 25] = ['\0'; 25];
-        for (i, character) in str.chars().enumerate().take(std::cmp::min(str.len(), 25)) {
-            result[i] = character;
+        let mut len = str.len().min(LEN);
+        while !str.is_char_boundary(len) {
+            len -= 1;
         }
+        let mut result = [0; LEN];
+        result[..len].copy_from_slice(&str.as_bytes()[..len]);
         Self { str: result }
     }
 }
 
+/// Orders like the text it holds, since UTF-8 sorts like the chars it
+/// encodes and unused bytes are 0.
+impl Column for Fixed {
+    const MIN: Self = Fixed { str: [0; LEN] };
+    const MAX: Self = Fixed {
+        str: [u8::MAX; LEN],
+    };
+}
+
 impl std::convert::From<String> for Fixed {
     fn from(value: String) -> Self {
-        let mut result: [char; 25] = ['\0'; 25];
-        for (i, character) in value
-            .chars()
-            .enumerate()
-            .take(std::cmp::min(value.len(), 25))
-        {
-            result[i] = character;
-        }
-        Self { str: result }
+        Fixed::from(value.as_str())
     }
 }
 
 impl std::convert::From<&str> for Fixed {
     fn from(value: &str) -> Self {
-        let mut result: [char; 25] = ['\0'; 25];
-        for (i, character) in value
-            .chars()
-            .enumerate()
-            .take(std::cmp::min(value.len(), 25))
-        {
-            result[i] = character;
-        }
-        Self { str: result }
+        Fixed::from(value)
     }
 }
 
 impl std::fmt::Display for Fixed {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let len = self
-            .str
-            .iter()
-            .cloned()
-            .position(|ch| ch as u8 == b'\0')
-            .unwrap_or(25);
-
-        let mut string = String::with_capacity(len);
-
-        for i in 0..len {
-            string.push(self.str[i]);
-        }
-        write!(f, "{}", string)
+        let len = self.str.iter().position(|&byte| byte == 0).unwrap_or(LEN);
+        write!(f, "{}", String::from_utf8_lossy(&self.str[..len]))
     }
 }
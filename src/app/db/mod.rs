@@ -1,26 +1,54 @@
+pub mod external_sort;
 pub mod file_handler;
 mod fixed_str;
 pub mod goods;
 pub mod person;
+pub mod record;
+pub mod record_index;
+pub mod wal;
 
-use std::cell::RefCell;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fs;
+use std::hash::Hash;
 use std::marker::PhantomData;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
 
-use crate::app::btree::{key_value::Comparator, BTree};
+use serde::{Deserialize, Serialize};
+
+use crate::app::btree::{
+    compare::Natural,
+    image::{self, Image, ImageBuilder},
+    merkle::Changes,
+    mvcc::{MvccBTree, SharedMvcc},
+    search::PackedKey,
+    BTree,
+};
+use crate::app::hash::bloom::BloomFilter;
+use crate::app::lsm::Lsm;
 use crate::Error;
+use external_sort::ExternalSorter;
 use file_handler::{FileHandler, STRUCT_SIZE};
+use fixed_str::Fixed;
 use goods::Crate;
+use record::Record;
+use record_index::{
+    CoveringIndex, HashIndex, Hits, IndexEngine, KeyExtractor, LsmIndex, Projection, RecordIndex,
+    SummaryIndex,
+};
+use wal::Wal;
 
-const DEGREE_OF_TREE: usize = 200;
+pub const DEGREE_OF_TREE: usize = 200;
+/// Log entries after which a persisted index is checkpointed, so replay on
+/// the next start never has more than this many to go through.
+pub const CHECKPOINT_EVERY: usize = 10_000;
 
 pub trait Random {
     fn random() -> Self;
 }
 
-#[derive(Debug)]
-struct Comp;
-
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 pub enum From {
     Sender,
     Receiver,
@@ -36,19 +64,55 @@ impl From {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 pub enum KeyType {
     GoodsID,
     PostIndex(From),
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Serialize, Deserialize)]
 pub enum Key {
     GoodsID(u64),
     PostIndex(u32),
 }
 
+impl KeyType {
+    /// Tag an index image is written with, see `image::write_image`.
+    fn tag(&self) -> u64 {
+        match self {
+            KeyType::GoodsID => 0,
+            KeyType::PostIndex(From::Sender) => 1,
+            KeyType::PostIndex(From::Receiver) => 2,
+        }
+    }
+
+    fn from_tag(tag: u64) -> Option<Self> {
+        match tag {
+            0 => Some(KeyType::GoodsID),
+            1 => Some(KeyType::PostIndex(From::Sender)),
+            2 => Some(KeyType::PostIndex(From::Receiver)),
+            _ => None,
+        }
+    }
+}
+
 impl Key {
+    /// Inverse of `PackedKey::packed` for keys of `key_type`.
+    fn unpacked(packed: u64, key_type: KeyType) -> Self {
+        match key_type {
+            KeyType::GoodsID => Key::GoodsID(packed),
+            KeyType::PostIndex(_) => Key::PostIndex(packed as u32),
+        }
+    }
+
+    fn of(data: &Crate, key_type: KeyType) -> Self {
+        match key_type {
+            KeyType::GoodsID => Key::GoodsID(data.goods_id),
+            KeyType::PostIndex(From::Sender) => Key::PostIndex(data.sender.post_index),
+            KeyType::PostIndex(From::Receiver) => Key::PostIndex(data.receiver.post_index),
+        }
+    }
+
     fn is_goods_id(&self) -> bool {
         matches!(self, Key::GoodsID(_))
     }
@@ -57,45 +121,289 @@ impl Key {
     }
 }
 
-impl Comparator<Key> for Comp {
-    fn compare(lhs: &Key, rhs: &Key) -> std::cmp::Ordering {
-        match lhs {
-            Key::GoodsID(ref lhs_key) => match rhs {
-                Key::GoodsID(ref rhs_key) => lhs_key.cmp(rhs_key),
-                Key::PostIndex(_) => panic!(),
-            },
-            Key::PostIndex(ref lhs_key) => match rhs {
-                Key::GoodsID(_) => panic!(),
-                Key::PostIndex(ref rhs_key) => lhs_key.cmp(rhs_key),
-            },
+impl PackedKey for Key {
+    fn packed(&self) -> u64 {
+        match self {
+            Key::GoodsID(id) => *id,
+            Key::PostIndex(index) => *index as u64,
         }
     }
 }
 
+impl KeyExtractor<Crate> for KeyType {
+    type Key = Key;
+
+    fn key(&self, data: &Crate) -> Key {
+        Key::of(data, *self)
+    }
+}
+
+impl Record for Crate {
+    const SIZE: usize = STRUCT_SIZE;
+
+    type Field = KeyType;
+    type Key = Key;
+    type Summary = (Fixed, u32);
+
+    fn tag(field: KeyType) -> u64 {
+        field.tag()
+    }
+
+    fn from_tag(tag: u64) -> Option<KeyType> {
+        KeyType::from_tag(tag)
+    }
+
+    fn unpacked(packed: u64, field: KeyType) -> Key {
+        Key::unpacked(packed, field)
+    }
+
+    /// Goods name and receiver's post index, what the list of hits shows.
+    fn summary(&self) -> (Fixed, u32) {
+        (self.goods_name, self.receiver.post_index)
+    }
+}
+
+/// Keys of an index with their positions.
+type Entries<K> = Vec<(K, Vec<u64>)>;
+
+/// Versioned copy of an index that other threads read while records are
+/// being added, one `(key, position)` entry per record so that adding one
+/// only adds an entry. The positions of `key` are the entries in
+/// `(key, 0)..=(key, u64::MAX)`. Created by `DataBase::share_index`.
+pub type SharedIndex<K> = SharedMvcc<(K, u64), (), Natural>;
+
 #[derive(Debug)]
-enum Index {
-    Indexed(BTree<Key, RefCell<Vec<u64>>, Comp>, KeyType),
+enum Index<T: Record> {
+    Indexed(RecordIndex<T, T::Field>),
+    /// Point lookups only, from a hash table.
+    Hashed(HashIndex<T, T::Field>),
+    /// Read-only image searched in place until the first change.
+    Mapped(Image, T::Field),
+    /// Runs on disk behind a memtable, one entry per record.
+    Lsm(LsmIndex<T, T::Field>),
+    /// Tree that keeps the summary of each record next to its position.
+    Covering(SummaryIndex<T, T::Field>),
     NotIndexed,
 }
 
+impl<T: Record> Index<T> {
+    fn key_type(&self) -> Option<T::Field> {
+        match self {
+            Index::Indexed(index) => Some(*index.extractor()),
+            Index::Hashed(index) => Some(*index.extractor()),
+            Index::Mapped(_, key_type) => Some(*key_type),
+            Index::Lsm(index) => Some(*index.extractor()),
+            Index::Covering(index) => Some(*index.extractor()),
+            Index::NotIndexed => None,
+        }
+    }
+
+    fn positions(&self, key: T::Key) -> Option<Vec<u64>> {
+        match self {
+            Index::Indexed(index) => index.positions(key).ok().map(Cow::into_owned),
+            Index::Hashed(index) => index.positions(key).ok().map(Cow::into_owned),
+            Index::Mapped(image, _) => image.search(key),
+            Index::Lsm(index) => index.positions(key).ok().map(Cow::into_owned),
+            Index::Covering(index) => Some(index.hits(key)?.iter().map(|(pos, _)| *pos).collect()),
+            Index::NotIndexed => None,
+        }
+    }
+
+    /// Every key with its positions, in key order.
+    fn entries(&self) -> Result<Entries<T::Key>, Error> {
+        match self {
+            Index::Indexed(index) => Ok(index
+                .range(..)
+                .map(|(key, pos_vec)| (*key, pos_vec.clone()))
+                .collect()),
+            Index::Hashed(index) => {
+                let mut entries: Entries<T::Key> = index
+                    .engine()
+                    .iter()
+                    .map(|(key, pos_vec)| (*key, pos_vec.clone()))
+                    .collect();
+                entries.sort_unstable_by_key(|(key, _)| *key);
+                Ok(entries)
+            }
+            Index::Mapped(image, key_type) => Ok(image
+                .entries()?
+                .into_iter()
+                .map(|(packed, pos_vec)| (T::unpacked(packed, *key_type), pos_vec))
+                .collect()),
+            Index::Lsm(index) => {
+                let mut entries: Entries<T::Key> = vec![];
+                for entry in index.engine().range(..)? {
+                    let ((key, pos), ()) = entry?;
+                    match entries.last_mut() {
+                        Some((last, pos_vec)) if *last == key => pos_vec.push(pos),
+                        _ => entries.push((key, vec![pos])),
+                    }
+                }
+                Ok(entries)
+            }
+            Index::Covering(index) => Ok(index
+                .range(..)
+                .map(|(key, hits)| (*key, hits.iter().map(|(pos, _)| *pos).collect()))
+                .collect()),
+            Index::NotIndexed => Ok(vec![]),
+        }
+    }
+
+    /// Positions under the keys in `range`, in key order. `None` unless the
+    /// index keeps its keys in order, or if its runs cannot be read.
+    fn range_positions<R>(&self, range: R) -> Option<Vec<u64>>
+    where
+        R: RangeBounds<T::Key>,
+    {
+        match self {
+            Index::Indexed(index) => index.range_positions(range).ok()?,
+            Index::Hashed(index) => index.range_positions(range).ok()?,
+            Index::Lsm(index) => index.range_positions(range).ok()?,
+            Index::Covering(index) => Some(index.range_positions(range)),
+            Index::Mapped(..) | Index::NotIndexed => None,
+        }
+    }
+
+    /// Stores `pos` under `key`, loading a mapped image first.
+    fn insert(&mut self, key: T::Key, pos: u64) -> Result<(), Error> {
+        self.materialize()?;
+        match self {
+            Index::Indexed(index) => index.insert(key, pos),
+            Index::Hashed(index) => index.insert(key, pos),
+            Index::Lsm(index) => index.insert(key, pos),
+            // A covering index needs the whole record, see `add`.
+            Index::Covering(_) | Index::Mapped(..) | Index::NotIndexed => {
+                Err(Error::UnexpectedError)
+            }
+        }
+    }
+
+    /// Stores `pos` under the key of `record`, with its summary if the index
+    /// is a covering one.
+    fn add(&mut self, record: &T, pos: u64) -> Result<(), Error> {
+        match self {
+            Index::Covering(index) => index.add(record, pos),
+            _ => {
+                let key_type = self.key_type().ok_or(Error::UnexpectedError)?;
+                self.insert(key_type.key(record), pos)
+            }
+        }
+    }
+
+    /// Takes `pos` out from under `key` in an LSM index, which may have
+    /// flushed it for a record that never made it to the file. Other kinds
+    /// are loaded from a snapshot taken before, so they cannot hold it.
+    fn forget(&mut self, key: T::Key, pos: u64) -> Result<(), Error> {
+        match self {
+            Index::Lsm(index) => index.engine_mut().delete((key, pos)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Drops every entry, keeping the index on the same field. A mapped
+    /// image is replaced by an empty tree.
+    fn clear(&mut self) -> Result<(), Error> {
+        match self {
+            Index::Indexed(index) => *index = RecordIndex::new(*index.extractor())?,
+            Index::Hashed(index) => *index = RecordIndex::new(*index.extractor())?,
+            Index::Mapped(_, key_type) => *self = Index::Indexed(RecordIndex::new(*key_type)?),
+            Index::Lsm(index) => index.engine_mut().clear()?,
+            Index::Covering(index) => index.clear()?,
+            Index::NotIndexed => {}
+        }
+        Ok(())
+    }
+
+    /// Loads a mapped image into a tree that can take changes.
+    fn materialize(&mut self) -> Result<(), Error> {
+        if let Index::Mapped(_, key_type) = self {
+            let key_type = *key_type;
+            let mut index = RecordIndex::new(key_type)?;
+            for (key, pos_vec) in self.entries()? {
+                for pos in pos_vec {
+                    index.insert(key, pos)?;
+                }
+            }
+            *self = Index::Indexed(index);
+        }
+        Ok(())
+    }
+}
+
+/// Index change written to the log before it is applied: the record at
+/// `pos` is stored under `key`.
+#[derive(Debug, Serialize, Deserialize)]
+struct Logged<K> {
+    key: K,
+    pos: u64,
+}
+
+/// What a [`Snapshot`] holds the index as, `S` being the summaries of a
+/// covering one.
+#[derive(Debug, Serialize, Deserialize)]
+enum Stored<K, S> {
+    Entries(Entries<K>),
+    /// Entries of an index kept in a hash table, which is reloaded as one.
+    Hashed(Entries<K>),
+    /// Image at this path, left where it is rather than copied.
+    Image(PathBuf),
+    /// LSM tree with its runs in this directory, flushed at the checkpoint.
+    Lsm(PathBuf),
+    /// Keys of a covering index with their hits.
+    Covered(Vec<(K, Hits<S>)>),
+}
+
+/// State of the index as of the last checkpoint, covering the first `len`
+/// records of the data file.
+#[derive(Debug, Serialize, Deserialize)]
+struct Snapshot<F, K, S> {
+    key_type: F,
+    len: u64,
+    stored: Stored<K, S>,
+}
+
+/// Where a durable index keeps its snapshot and log.
 #[derive(Debug)]
-pub struct DataBase<'a, T> {
+struct Durability {
+    snapshot: PathBuf,
+    wal: Wal,
+    /// Entries in `wal`.
+    logged: usize,
+}
+
+/// File of [`Record`]s of one type, laid end to end, with an optional index
+/// on one of their fields.
+#[derive(Debug)]
+pub struct DataBase<'a, T: Record> {
     file: FileHandler<'a>,
     len: usize,
-    index: Index,
+    index: Index<T>,
+    durability: Option<Durability>,
+    /// Keys every record was added under, for each field with a filter.
+    filters: Vec<(T::Field, BloomFilter)>,
+    shared: Option<SharedIndex<T::Key>>,
     _ph: PhantomData<T>,
 }
 
 #[allow(dead_code)]
-impl<'a> DataBase<'a, Crate> {
+impl<'a, T: Record> DataBase<'a, T> {
+    /// Opens the records in `file`, dropping any part of one a crash left
+    /// at its end, so the next record goes where it belongs.
     pub fn new(mut file: FileHandler<'a>) -> Result<Self, Error> {
         file.open()?;
-        let len = file.len()? as usize / STRUCT_SIZE;
+        let bytes = file.len()?;
+        let len = bytes as usize / T::SIZE;
+        if bytes != (len * T::SIZE) as u64 {
+            file.truncate((len * T::SIZE) as u64)?;
+        }
 
         Ok(DataBase {
             file,
             len,
             index: Index::NotIndexed,
+            durability: None,
+            filters: vec![],
+            shared: None,
             _ph: PhantomData,
         })
     }
@@ -108,144 +416,719 @@ impl<'a> DataBase<'a, Crate> {
         self.len == 0
     }
 
+    /// Drops every record. An index stays on the same field, empty, and so
+    /// does a persisted one.
     pub fn clean(&mut self) -> Result<(), Error> {
-        self.file.truncate(0)
+        self.filters
+            .iter_mut()
+            .for_each(|(_, filter)| filter.clear());
+        self.index.clear()?;
+        self.publish_all()?;
+        self.file.truncate(0)?;
+        self.len = 0;
+        self.checkpoint()
     }
 
-    pub fn peek(&mut self, pos: u64) -> Result<Crate, Error> {
+    pub fn peek(&mut self, pos: u64) -> Result<T, Error> {
         if self.len as u64 <= pos {
             return Err(Error::OutOfBounds);
         }
         self.file
-            .read::<Crate>(Some(pos * STRUCT_SIZE as u64))
+            .read::<T>(Some(pos * T::SIZE as u64))
             .or(Err(Error::ErrorDeserializing))
     }
 
-    pub fn indexed_by(&self) -> Option<KeyType> {
-        match &self.index {
-            Index::Indexed(_, key_type) => Some(*key_type),
-            Index::NotIndexed => None,
+    pub fn indexed_by(&self) -> Option<T::Field> {
+        self.index.key_type()
+    }
+
+    /// Writes the index as a flat image that `open_index_image` maps back in
+    /// without loading it.
+    pub fn save_index_image(&self, path: &Path) -> Result<(), Error> {
+        match self.index {
+            Index::Indexed(ref index) => {
+                image::write_image(index.tree(), path, T::tag(*index.extractor()), |pos_vec| {
+                    pos_vec.clone()
+                })
+            }
+            Index::Hashed(_)
+            | Index::Mapped(..)
+            | Index::Lsm(_)
+            | Index::Covering(_)
+            | Index::NotIndexed => Err(Error::UnexpectedError),
         }
     }
 
-    pub fn index(&mut self, key_type: KeyType) -> Result<(), Error> {
+    /// Uses the image at `path` as the index. Lookups read it in place; the
+    /// first `add_record` loads it into memory.
+    pub fn open_index_image(&mut self, path: &Path) -> Result<(), Error> {
+        let image = Image::open(path)?;
+        let key_type = T::from_tag(image.tag()).ok_or(Error::ErrorDeserializing)?;
+        self.set_index(Index::Mapped(image, key_type))
+    }
+
+    /// Builds the index for `key_type` as an image at `path`, sorting the keys
+    /// externally `chunk` records at a time. A checkpoint points at the image.
+    pub fn index_external(
+        &mut self,
+        key_type: T::Field,
+        path: &Path,
+        chunk: usize,
+    ) -> Result<(), Error> {
+        let mut sorter = ExternalSorter::new(path, chunk).ok_or(Error::UnexpectedError)?;
         self.file.seek_to_start()?;
-        match key_type {
-            KeyType::GoodsID => {
-                let mut index: BTree<Key, RefCell<Vec<u64>>, Comp> =
-                    BTree::with(DEGREE_OF_TREE).ok_or(Error::UnexpectedError)?;
-                let mut pos: u64 = 0;
-                while let Ok(data) = self.file.read::<Crate>(None) {
-                    let already_contains = index.contains(Key::GoodsID(data.goods_id));
-                    if !already_contains {
-                        index.insert(Key::GoodsID(data.goods_id), RefCell::new(vec![pos]))?;
-                    } else {
-                        let pos_vec = index.search(Key::GoodsID(data.goods_id))?;
-                        pos_vec.borrow_mut().push(pos);
+        let mut pos: u64 = 0;
+        while let Ok(data) = self.file.read::<T>(None) {
+            sorter.push(key_type.key(&data).packed(), pos)?;
+            pos += 1;
+        }
+
+        let mut merge = sorter.finish()?;
+        let mut builder = ImageBuilder::create(path, T::tag(key_type), DEGREE_OF_TREE)?;
+        while let Some((key, pos)) = merge.next_pair()? {
+            builder.append(key, pos)?;
+        }
+        builder.finish()?;
+
+        self.open_index_image(path)?;
+        self.checkpoint()
+    }
+
+    /// The index as a tree, positions sorted. Equal `root_hash`es mean equal
+    /// indexes; `BTree::changes` lists what differs.
+    pub fn merkle(&self) -> Result<BTree<T::Key, Vec<u64>, Natural>, Error> {
+        Self::hashed(self.index.entries()?)
+    }
+
+    /// Keys whose positions in the index differ from a fresh build over the
+    /// file, with the right ones. Fails with `UnexpectedError` if not indexed.
+    pub fn verify_index(&mut self) -> Result<Changes<T::Key, Vec<u64>>, Error> {
+        let key_type = self.indexed_by().ok_or(Error::UnexpectedError)?;
+        let stored = self.merkle()?;
+        let fresh = Index::Indexed(self.build_index(key_type)?);
+        let fresh = Self::hashed(fresh.entries()?)?;
+        Ok(stored.changes(&fresh))
+    }
+
+    fn hashed(entries: Entries<T::Key>) -> Result<BTree<T::Key, Vec<u64>, Natural>, Error> {
+        let mut tree = BTree::with(DEGREE_OF_TREE).ok_or(Error::UnexpectedError)?;
+        for (key, mut pos_vec) in entries {
+            pos_vec.sort_unstable();
+            tree.insert(key, pos_vec)?;
+        }
+        Ok(tree)
+    }
+
+    /// Builds a fresh index over the whole file. It is built on the side and
+    /// only replaces the current one once every record went in.
+    pub fn index(&mut self, key_type: T::Field) -> Result<(), Error> {
+        let index = self.index_by(key_type)?;
+        self.set_index(Index::Indexed(index))?;
+        self.checkpoint()
+    }
+
+    /// Like `index`, but in a hash table: no range searches. `persist_index`
+    /// reloads it as a hash table too.
+    pub fn hash_index(&mut self, key_type: T::Field) -> Result<(), Error> {
+        let index = self.hash_index_by(key_type)?;
+        self.set_index(Index::Hashed(index))?;
+        self.checkpoint()
+    }
+
+    /// Like `index`, but in an LSM tree with its runs in `dir`, for adding
+    /// records faster than a tree on disk could take them. Runs already in
+    /// `dir` are dropped. `persist_index` reopens the tree where it is.
+    pub fn lsm_index(&mut self, key_type: T::Field, dir: &Path) -> Result<(), Error> {
+        let index = self.build_lsm_index(key_type, dir)?;
+        self.set_index(Index::Lsm(index))?;
+        self.checkpoint()
+    }
+
+    /// Like `index`, but keeps the `Record::summary` of each record next to
+    /// its position, so `search_covered` answers without reading the file.
+    pub fn covering_index(&mut self, key_type: T::Field) -> Result<(), Error> {
+        let index = self.summary_index(key_type)?;
+        self.set_index(Index::Covering(index))?;
+        self.checkpoint()
+    }
+
+    fn summary_index(&mut self, key_type: T::Field) -> Result<SummaryIndex<T, T::Field>, Error> {
+        self.covering_index_by(key_type, T::summary as fn(&T) -> _)
+    }
+
+    /// Builds and hands back an index keyed by `extractor`, e.g.
+    /// `|data: &Crate| data.receiver.post_index`.
+    pub fn index_by<E>(&mut self, extractor: E) -> Result<RecordIndex<T, E>, Error>
+    where
+        E: KeyExtractor<T>,
+    {
+        self.build_index(extractor)
+    }
+
+    /// Like `index_by`, but keeps the positions in a hash table. Point
+    /// lookups skip the tree descent; ranges cannot be served at all.
+    pub fn hash_index_by<E>(&mut self, extractor: E) -> Result<HashIndex<T, E>, Error>
+    where
+        E: KeyExtractor<T>,
+        E::Key: Hash,
+    {
+        self.build_index(extractor)
+    }
+
+    /// Builds an index that also keeps what `projection` takes out of each
+    /// record, so hits need no `peek`.
+    pub fn covering_index_by<E, P>(
+        &mut self,
+        extractor: E,
+        projection: P,
+    ) -> Result<CoveringIndex<T, E, P>, Error>
+    where
+        E: KeyExtractor<T>,
+        P: Projection<T>,
+    {
+        self.file.seek_to_start()?;
+        let mut index = CoveringIndex::new(extractor, projection)?;
+        let mut pos: u64 = 0;
+        while let Ok(data) = self.file.read::<T>(None) {
+            index.add(&data, pos)?;
+            pos += 1;
+        }
+        Ok(index)
+    }
+
+    fn build_index<E, I>(&mut self, extractor: E) -> Result<RecordIndex<T, E, I>, Error>
+    where
+        E: KeyExtractor<T>,
+        I: IndexEngine<E::Key>,
+    {
+        self.fill(RecordIndex::new(extractor)?)
+    }
+
+    fn build_lsm_index(
+        &mut self,
+        key_type: T::Field,
+        dir: &Path,
+    ) -> Result<LsmIndex<T, T::Field>, Error> {
+        // Two trees in one directory would write over each other's runs.
+        if let Index::Lsm(_) = self.index {
+            self.set_index(Index::NotIndexed)?;
+        }
+        let mut lsm = Lsm::open(dir)?;
+        lsm.clear()?;
+        self.fill(RecordIndex::with(key_type, lsm))
+    }
+
+    /// Adds every record of the file to `index`.
+    fn fill<E, I>(&mut self, mut index: RecordIndex<T, E, I>) -> Result<RecordIndex<T, E, I>, Error>
+    where
+        E: KeyExtractor<T>,
+        I: IndexEngine<E::Key>,
+    {
+        self.file.seek_to_start()?;
+        let mut pos: u64 = 0;
+        while let Ok(data) = self.file.read::<T>(None) {
+            index.add(&data, pos)?;
+            pos += 1;
+        }
+        Ok(index)
+    }
+
+    /// Keeps the index in `dir` and loads the one there, replaying the log and
+    /// indexing any records added since. Rebuilds it if that is not possible.
+    pub fn persist_index(&mut self, dir: &Path) -> Result<(), Error> {
+        fs::create_dir_all(dir)?;
+        let snapshot_path = dir.join("index.snapshot");
+        let mut wal = Wal::open(&dir.join("index.wal"))?;
+
+        let snapshot: Option<Snapshot<T::Field, T::Key, T::Summary>> =
+            wal::read_snapshot(&snapshot_path)?;
+        let logged: Vec<Logged<T::Key>> = wal.replay()?;
+        self.durability = Some(Durability {
+            snapshot: snapshot_path,
+            wal,
+            logged: 0,
+        });
+
+        let snapshot = match snapshot {
+            Some(snapshot) => snapshot,
+            None => return self.checkpoint(),
+        };
+
+        // A data file cut short since the snapshot was taken may hold other
+        // records at the positions it still has, so none of it is trusted.
+        let len = self.len as u64;
+        if snapshot.len > len {
+            let index = match snapshot.stored {
+                Stored::Hashed(_) => Index::Hashed(self.build_index(snapshot.key_type)?),
+                Stored::Lsm(dir) => Index::Lsm(self.build_lsm_index(snapshot.key_type, &dir)?),
+                Stored::Covered(_) => Index::Covering(self.summary_index(snapshot.key_type)?),
+                _ => Index::Indexed(self.build_index(snapshot.key_type)?),
+            };
+            self.set_index(index)?;
+            return self.checkpoint();
+        }
+
+        // The index is built on the side, so a failure here leaves the
+        // current one untouched.
+        let mut index = match snapshot.stored {
+            Stored::Entries(entries) => Index::Indexed(Self::load(snapshot.key_type, entries)?),
+            Stored::Hashed(entries) => Index::Hashed(Self::load(snapshot.key_type, entries)?),
+            Stored::Image(path) => match Image::open(&path) {
+                Ok(image) if T::from_tag(image.tag()) == Some(snapshot.key_type) => {
+                    Index::Mapped(image, snapshot.key_type)
+                }
+                // The image is gone or was replaced by one of another index.
+                _ => {
+                    let index = self.build_index(snapshot.key_type)?;
+                    self.set_index(Index::Indexed(index))?;
+                    return self.checkpoint();
+                }
+            },
+            Stored::Lsm(dir) => {
+                if let Index::Lsm(_) = self.index {
+                    self.set_index(Index::NotIndexed)?;
+                }
+                match dir.is_dir().then(|| Lsm::open(&dir)) {
+                    Some(Ok(lsm)) => Index::Lsm(RecordIndex::with(snapshot.key_type, lsm)),
+                    // The runs are gone or cannot be read.
+                    _ => {
+                        let index = self.build_index(snapshot.key_type)?;
+                        self.set_index(Index::Indexed(index))?;
+                        return self.checkpoint();
                     }
-                    pos += 1;
                 }
-                self.index = Index::Indexed(index, key_type);
             }
-            KeyType::PostIndex(From::Sender) => {
-                let mut index: BTree<Key, RefCell<Vec<u64>>, Comp> =
-                    BTree::with(DEGREE_OF_TREE).ok_or(Error::UnexpectedError)?;
-                let mut pos: u64 = 0;
-                while let Ok(data) = self.file.read::<Crate>(None) {
-                    let already_contains = index.contains(Key::PostIndex(data.sender.post_index));
-                    if !already_contains {
-                        index.insert(
-                            Key::PostIndex(data.sender.post_index),
-                            RefCell::new(vec![pos]),
-                        )?;
-                    } else {
-                        let pos_vec = index.search(Key::PostIndex(data.sender.post_index))?;
-                        pos_vec.borrow_mut().push(pos);
+            Stored::Covered(entries) => {
+                let mut index = CoveringIndex::new(snapshot.key_type, T::summary as fn(&T) -> _)?;
+                for (key, hits) in entries {
+                    for (pos, summary) in hits {
+                        index.insert(key, pos, summary)?;
                     }
-                    pos += 1;
                 }
-                self.index = Index::Indexed(index, key_type);
+                Index::Covering(index)
+            }
+        };
+
+        let mut covered = snapshot.len;
+        for Logged { key, pos } in logged {
+            // Anything below `snapshot.len` is already in the snapshot, as
+            // the log may outlive a crash right after a checkpoint.
+            if (snapshot.len..len).contains(&pos) {
+                match index {
+                    // The log has the key only.
+                    Index::Covering(ref mut index) => index.add(&self.peek(pos)?, pos)?,
+                    _ => index.insert(key, pos)?,
+                }
+                covered = covered.max(pos + 1);
+            } else if pos >= len {
+                index.forget(key, pos)?;
+            }
+        }
+        for pos in covered..len {
+            index.add(&self.peek(pos)?, pos)?;
+        }
+        self.set_index(index)?;
+
+        self.checkpoint()
+    }
+
+    /// Index on `key_type` holding `entries`.
+    fn load<I>(
+        key_type: T::Field,
+        entries: Entries<T::Key>,
+    ) -> Result<RecordIndex<T, T::Field, I>, Error>
+    where
+        I: IndexEngine<T::Key>,
+    {
+        let mut index = RecordIndex::new(key_type)?;
+        for (key, pos_vec) in entries {
+            for pos in pos_vec {
+                index.insert(key, pos)?;
             }
-            KeyType::PostIndex(From::Receiver) => {
-                let mut index: BTree<Key, RefCell<Vec<u64>>, Comp> =
-                    BTree::with(DEGREE_OF_TREE).ok_or(Error::UnexpectedError)?;
-                let mut pos: u64 = 0;
-                while let Ok(data) = self.file.read::<Crate>(None) {
-                    let already_contains = index.contains(Key::PostIndex(data.receiver.post_index));
-                    if !already_contains {
-                        index.insert(
-                            Key::PostIndex(data.receiver.post_index),
-                            RefCell::new(vec![pos]),
-                        )?;
-                    } else {
-                        let pos_vec = index.search(Key::PostIndex(data.receiver.post_index))?;
-                        pos_vec.borrow_mut().push(pos);
+        }
+        Ok(index)
+    }
+
+    /// Writes the index, or the path of a mapped image, as a snapshot and
+    /// empties the log. Does nothing unless `persist_index` was called.
+    pub fn checkpoint(&mut self) -> Result<(), Error> {
+        let durability = match self.durability {
+            Some(ref mut durability) => durability,
+            None => return Ok(()),
+        };
+
+        match self.index.key_type() {
+            Some(key_type) => {
+                let stored = match self.index {
+                    Index::Mapped(ref image, _) => Stored::Image(fs::canonicalize(image.path())?),
+                    Index::Hashed(_) => Stored::Hashed(self.index.entries()?),
+                    Index::Lsm(ref mut index) => {
+                        index.engine_mut().flush()?;
+                        Stored::Lsm(fs::canonicalize(index.engine().dir())?)
                     }
-                    pos += 1;
+                    Index::Covering(ref index) => Stored::Covered(
+                        index
+                            .range(..)
+                            .map(|(key, hits)| (*key, hits.clone()))
+                            .collect(),
+                    ),
+                    _ => Stored::Entries(self.index.entries()?),
+                };
+                let snapshot = Snapshot {
+                    key_type,
+                    len: self.len as u64,
+                    stored,
+                };
+                wal::write_snapshot(&durability.snapshot, &snapshot)?;
+            }
+            None => {
+                if durability.snapshot.exists() {
+                    fs::remove_file(&durability.snapshot)?;
                 }
-                self.index = Index::Indexed(index, key_type);
             }
         }
-        Ok(())
+        durability.logged = 0;
+        durability.wal.truncate()
     }
 
-    pub fn search_unindexed(
+    /// Puts a Bloom filter with false positive rate `rate` at `expected`
+    /// records in front of searches by `key_type`.
+    pub fn add_bloom_filter(
         &mut self,
-        key: Key,
-        which_post_index: Option<From>,
-    ) -> Option<Vec<u64>> {
+        key_type: T::Field,
+        expected: usize,
+        rate: f64,
+    ) -> Result<(), Error> {
+        let mut filter =
+            BloomFilter::with_rate(expected.max(self.len), rate).ok_or(Error::UnexpectedError)?;
+        self.file.seek_to_start()?;
+        while let Ok(data) = self.file.read::<T>(None) {
+            filter.insert(&key_type.key(&data));
+        }
+
+        self.filters.retain(|(filtered, _)| *filtered != key_type);
+        self.filters.push((key_type, filter));
+        Ok(())
+    }
+
+    pub fn remove_bloom_filter(&mut self, key_type: T::Field) {
+        self.filters.retain(|(filtered, _)| *filtered != key_type);
+    }
+
+    /// `false` only if a filter knows that no record has `key` in `field`.
+    fn might_contain(&self, field: T::Field, key: T::Key) -> bool {
+        self.filters
+            .iter()
+            .find(|(filtered, _)| *filtered == field)
+            .is_none_or(|(_, filter)| filter.contains(&key))
+    }
+
+    /// Positions under `key` in the index, which must be on `field`. A
+    /// mapped index is read from disk, so the filter gets asked first.
+    fn indexed_positions(&self, field: T::Field, key: T::Key) -> Option<Vec<u64>> {
+        if matches!(self.index, Index::Mapped(..)) && !self.might_contain(field, key) {
+            return None;
+        }
+        self.index.positions(key)
+    }
+
+    /// Positions of the records whose `field` is `key`, read by a full scan
+    /// unless a filter rules `key` out.
+    pub fn scan(&mut self, field: T::Field, key: T::Key) -> Option<Vec<u64>> {
+        if !self.might_contain(field, key) {
+            return None;
+        }
         self.file.seek_to_start().ok()?;
         let mut poss: Vec<u64> = vec![];
         let mut pos: u64 = 0;
+        while let Ok(data) = self.file.read::<T>(None) {
+            if field.key(&data) == key {
+                poss.push(pos);
+            }
+            pos += 1;
+        }
 
-        match key {
-            Key::GoodsID(id) => {
-                while let Ok(data) = self.file.read::<Crate>(None) {
-                    if data.goods_id == id {
-                        poss.push(pos);
-                    }
-                    pos += 1;
+        if poss.is_empty() {
+            None
+        } else {
+            Some(poss)
+        }
+    }
+
+    /// Positions of the records whose `field` is `key`, from the index if it
+    /// is on `field` and by a scan otherwise.
+    pub fn search(&mut self, field: T::Field, key: T::Key) -> Option<Vec<u64>> {
+        if self.indexed_by() == Some(field) {
+            self.indexed_positions(field, key)
+        } else {
+            self.scan(field, key)
+        }
+    }
+
+    /// Positions of the records whose `field` is `key` with their summaries,
+    /// from a covering index on `field`. `None` if there is none or no such
+    /// record.
+    pub fn search_covered(&self, field: T::Field, key: T::Key) -> Option<Hits<T::Summary>> {
+        match self.index {
+            Index::Covering(ref index) if *index.extractor() == field => {
+                index.hits(key).map(<[_]>::to_vec)
+            }
+            _ => None,
+        }
+    }
+
+    /// Positions of the records whose `field` is in `range`, in key order.
+    /// `None` unless an index that keeps its keys in order is on `field`.
+    pub fn search_range<R>(&self, field: T::Field, range: R) -> Option<Vec<u64>>
+    where
+        R: RangeBounds<T::Key>,
+    {
+        if self.indexed_by() == Some(field) {
+            self.index.range_positions(range)
+        } else {
+            None
+        }
+    }
+
+    /// Keys indexed here but not in `other`. `None` unless both are indexed by
+    /// the same key type.
+    pub fn keys_missing_from<'b>(
+        &'b self,
+        other: &'b DataBase<'_, T>,
+    ) -> Option<impl Iterator<Item = &'b T::Key>> {
+        match (&self.index, &other.index) {
+            (Index::Indexed(lhs), Index::Indexed(rhs)) if lhs.extractor() == rhs.extractor() => {
+                Some(lhs.tree().difference(rhs.tree()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Appends `data` to the file and the index. If either write fails,
+    /// neither is kept.
+    pub fn add_record(&mut self, data: T) -> Result<(), Error> {
+        self.add_records(std::iter::once(data))
+    }
+
+    /// Appends `records` with one log sync, keeping those before a failed write.
+    /// Fails with `ErrorSerializing` if one does not encode to `T::SIZE` bytes.
+    pub fn add_records<I>(&mut self, records: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let records: Vec<T> = records.into_iter().collect();
+        for data in &records {
+            if bincode::serialized_size(data).ok() != Some(T::SIZE as u64) {
+                return Err(Error::ErrorSerializing);
+            }
+        }
+        self.index.materialize()?;
+        // A key in a filter whose record then fails to be written only costs
+        // a wasted search later.
+        for (key_type, filter) in &mut self.filters {
+            for data in &records {
+                filter.insert(&key_type.key(data));
+            }
+        }
+        let key_type = self.indexed_by();
+        let keys: Vec<Option<T::Key>> = records
+            .iter()
+            .map(|data| key_type.map(|key_type| key_type.key(data)))
+            .collect();
+        let logged = match (key_type, &mut self.durability) {
+            (Some(_), Some(durability)) => {
+                let entries: Vec<Logged<T::Key>> = keys
+                    .iter()
+                    .zip(self.len as u64..)
+                    .filter_map(|(key, pos)| key.map(|key| Logged { key, pos }))
+                    .collect();
+                let len = durability.wal.append_all(&entries)?;
+                durability.logged += entries.len();
+                Some((len, entries.len()))
+            }
+            _ => None,
+        };
+
+        let mut added = vec![];
+        let mut result = Ok(());
+        for (data, key) in records.into_iter().zip(keys) {
+            let pos = self.len as u64;
+            let (file, record) = (&mut self.file, &data);
+            let mut write = move || {
+                file.seek_to_end()?;
+                file.write(record, None)
+            };
+            let written = match (&mut self.index, key) {
+                (Index::Indexed(index), Some(key)) => index.insert_then(key, pos, write),
+                (Index::Hashed(index), Some(key)) => index.insert_then(key, pos, write),
+                (Index::Lsm(index), Some(key)) => index.insert_then(key, pos, write),
+                (Index::Covering(index), Some(_)) => index.add_then(&data, pos, write),
+                _ => write(),
+            };
+            if let Err(error) = written {
+                // The index rolled itself back; the log entries and whatever
+                // part of the record made it to the file go too.
+                if let (Some((len, entries)), Some(durability)) = (logged, &mut self.durability) {
+                    durability.wal.cut(len)?;
+                    durability.logged -= entries;
                 }
+                self.file.truncate(pos * T::SIZE as u64)?;
+                result = Err(error);
+                break;
             }
-            Key::PostIndex(index) => {
+            self.len += 1;
+            added.extend(key.map(|key| (key, pos)));
+        }
+        self.publish(&added);
+        result?;
+
+        match self.durability {
+            Some(ref durability) if durability.logged >= CHECKPOINT_EVERY => self.checkpoint(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Versioned copy of the index that reader threads `snapshot`. It follows
+    /// every change to the index.
+    pub fn share_index(&mut self) -> Result<SharedIndex<T::Key>, Error> {
+        if let Some(ref shared) = self.shared {
+            return Ok(shared.clone());
+        }
+        let tree = MvccBTree::with(DEGREE_OF_TREE).ok_or(Error::UnexpectedError)?;
+        let shared = SharedMvcc::new(tree);
+        self.shared = Some(shared.clone());
+        self.publish_all()?;
+        Ok(shared)
+    }
+
+    fn set_index(&mut self, index: Index<T>) -> Result<(), Error> {
+        self.index = index;
+        self.publish_all()
+    }
+
+    /// Adds the `(key, position)` entries of records just added to the
+    /// shared index as one new version.
+    fn publish(&mut self, added: &[(T::Key, u64)]) {
+        let shared = match self.shared {
+            Some(ref shared) if !added.is_empty() => shared,
+            _ => return,
+        };
+        shared.write(|tree| {
+            for entry in added {
+                // Putting never fails, it only adds a version.
+                let _ = tree.put(*entry, ());
+            }
+            Self::commit_shared(tree, added.len());
+        });
+    }
+
+    /// Brings the whole shared index in line with the index as one new
+    /// version.
+    fn publish_all(&mut self) -> Result<(), Error> {
+        let shared = match self.shared {
+            Some(ref shared) => shared,
+            None => return Ok(()),
+        };
+        let mut kept: HashSet<(T::Key, u64)> = self
+            .index
+            .entries()?
+            .into_iter()
+            .flat_map(|(key, pos_vec)| pos_vec.into_iter().map(move |pos| (key, pos)))
+            .collect();
+        shared.write(|tree| {
+            let version = tree.version();
+            let stale: Vec<(T::Key, u64)> = tree
+                .range(version, ..)
+                .map(|(entry, _)| *entry)
+                .filter(|entry| !kept.remove(entry))
+                .collect();
+            for entry in &stale {
+                tree.delete(*entry)?;
+            }
+            for entry in &kept {
+                tree.put(*entry, ())?;
+            }
+            Self::commit_shared(tree, stale.len() + kept.len());
+            Ok(())
+        })
+    }
+
+    /// Commits and vacuums twice as many old values as keys changed.
+    fn commit_shared(tree: &mut MvccBTree<(T::Key, u64), (), Natural>, changed: usize) {
+        tree.commit();
+        tree.vacuum(2 * changed);
+    }
+
+    pub fn delete_record(&mut self, pos: u64) -> Result<T, Error> {
+        let file_len = self.len as u64;
+        if file_len <= pos {
+            return Err(Error::OutOfBounds);
+        }
+
+        let deleted = self.file.read::<T>(Some(pos * T::SIZE as u64))?;
+
+        // Every later position shifts down by one, so the stored index goes
+        // before the data file is touched. A covering index follows the
+        // shift in memory and comes back once the file is done.
+        let covering = match std::mem::replace(&mut self.index, Index::NotIndexed) {
+            Index::Covering(mut index) => {
+                index.delete(pos)?;
+                Some(index)
+            }
+            _ => None,
+        };
+        self.set_index(Index::NotIndexed)?;
+        self.checkpoint()?;
+
+        self.file.seek_to_start()?;
+        self.file.seek((pos * T::SIZE as u64) as i64)?;
+
+        let (mut curr, mut next) = (pos, pos + 1);
+
+        while next < file_len {
+            let next_data = self.file.read::<T>(Some(next * T::SIZE as u64))?;
+            self.file.write(next_data, Some(curr * T::SIZE as u64))?;
+            curr += 1;
+            next += 1;
+        }
+
+        self.len -= 1;
+        self.file.truncate((file_len - 1) * T::SIZE as u64)?;
+
+        if let Some(index) = covering {
+            self.set_index(Index::Covering(index))?;
+            self.checkpoint()?;
+        }
+        Ok(deleted)
+    }
+}
+
+#[allow(dead_code)]
+impl<'a> DataBase<'a, Crate> {
+    pub fn search_unindexed(
+        &mut self,
+        key: Key,
+        which_post_index: Option<From>,
+    ) -> Option<Vec<u64>> {
+        let key_type = match key {
+            Key::GoodsID(_) => KeyType::GoodsID,
+            Key::PostIndex(_) => {
                 assert!(
                     which_post_index.is_some(),
                     "For this query this must be true"
                 );
-                match which_post_index.unwrap() {
-                    From::Sender => {
-                        while let Ok(data) = self.file.read::<Crate>(None) {
-                            if data.sender.post_index == index {
-                                poss.push(pos);
-                            }
-                            pos += 1;
-                        }
-                    }
-                    From::Receiver => {
-                        while let Ok(data) = self.file.read::<Crate>(None) {
-                            if data.receiver.post_index == index {
-                                poss.push(pos);
-                            }
-                            pos += 1;
-                        }
-                    }
-                }
+                KeyType::PostIndex(which_post_index.unwrap())
             }
         };
-
-        if poss.is_empty() {
-            None
-        } else {
-            Some(poss)
-        }
+        self.scan(key_type, key)
     }
 
     pub fn search_indexed(&mut self, key: Key, which_post_index: Option<From>) -> Option<Vec<u64>> {
-        if let Index::Indexed(ref index, ref key_type) = self.index {
+        if let Some(key_type) = self.index.key_type() {
             match key_type {
                 KeyType::GoodsID => {
                     assert!(key.is_goods_id(), "For this query this must be true");
-                    Some(index.search(key).ok()?.borrow().clone())
+                    self.indexed_positions(key_type, key)
                 }
                 KeyType::PostIndex(From::Sender) => {
                     assert!(key.is_post_index(), "For this query this must be true");
@@ -257,7 +1140,7 @@ impl<'a> DataBase<'a, Crate> {
                         which_post_index.unwrap().is_sender(),
                         "For this query this must be true"
                     );
-                    Some(index.search(key).ok()?.borrow().clone())
+                    self.indexed_positions(key_type, key)
                 }
                 KeyType::PostIndex(From::Receiver) => {
                     assert!(key.is_post_index(), "For this query this must be true");
@@ -269,83 +1152,238 @@ impl<'a> DataBase<'a, Crate> {
                         which_post_index.unwrap().is_receiver(),
                         "For this query this must be true"
                     );
-                    Some(index.search(key).ok()?.borrow().clone())
+                    self.indexed_positions(key_type, key)
                 }
             }
         } else {
             self.search_unindexed(key, which_post_index)
         }
     }
+}
 
-    pub fn add_record(&mut self, data: Crate) -> Result<(), Error> {
-        if let Index::Indexed(ref mut index, key_type) = self.index {
-            let end_index = self.len as u64;
-            match key_type {
-                KeyType::GoodsID => {
-                    let pos_vec = index
-                        .search(Key::GoodsID(data.goods_id))
-                        .unwrap_or(&RefCell::new(vec![]))
-                        .clone();
-                    pos_vec.borrow_mut().push(end_index);
-                    let pos_vec_clone = pos_vec.clone();
-                    std::mem::drop(pos_vec);
-                    index.insert(Key::GoodsID(data.goods_id), pos_vec_clone)?;
-                }
-                KeyType::PostIndex(From::Sender) => {
-                    let pos_vec = index
-                        .search(Key::PostIndex(data.sender.post_index))
-                        .unwrap_or(&RefCell::new(vec![]))
-                        .clone();
-                    pos_vec.borrow_mut().push(end_index);
-                    let pos_vec_clone = pos_vec.clone();
-                    std::mem::drop(pos_vec);
-                    index.insert(Key::PostIndex(data.sender.post_index), pos_vec_clone)?;
-                }
-                KeyType::PostIndex(From::Receiver) => {
-                    let pos_vec = index
-                        .search(Key::PostIndex(data.receiver.post_index))
-                        .unwrap_or(&RefCell::new(vec![]))
-                        .clone();
-                    pos_vec.borrow_mut().push(end_index);
-                    let pos_vec_clone = pos_vec.clone();
-                    std::mem::drop(pos_vec);
-                    index.insert(Key::PostIndex(data.receiver.post_index), pos_vec_clone)?;
-                }
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::btree::mvcc::MvccSnapshot;
+    use person::{Person, PersonField};
+    use std::io::Write;
 
-        self.file.seek_to_end()?;
-        self.file.write::<Crate>(data, None)?;
-        self.len += 1;
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lab-db-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
 
-        Ok(())
+    fn person(post_index: u32) -> Person {
+        let name = Fixed::from("Ivan");
+        Person::new(name, Fixed::from("Petrov"), name, post_index)
     }
 
-    pub fn delete_record(&mut self, pos: u64) -> Result<Crate, Error> {
-        let file_len = self.len as u64;
-        if file_len <= pos {
-            return Err(Error::OutOfBounds);
-        }
+    #[test]
+    fn reopen_drops_a_torn_record() {
+        let dir = dir("torn");
+        let path = dir.join("people");
+        fs::File::create(&path).unwrap();
+        let mut db = DataBase::<Person>::new(FileHandler::new(&path)).unwrap();
+        db.index(PersonField::PostIndex).unwrap();
+        db.persist_index(&dir.join("index")).unwrap();
+        db.add_records((0..10).map(person)).unwrap();
+        drop(db);
 
-        let deleted = self.file.read::<Crate>(Some(pos * STRUCT_SIZE as u64))?;
+        let torn = bincode::serialize(&person(99)).unwrap();
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&torn[..Person::SIZE / 2]).unwrap();
+        drop(file);
 
-        self.file.seek_to_start()?;
-        self.file.seek((pos * STRUCT_SIZE as u64) as i64)?;
+        let mut db = DataBase::<Person>::new(FileHandler::new(&path)).unwrap();
+        assert_eq!(db.len(), 10);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 10 * Person::SIZE as u64);
+        db.persist_index(&dir.join("index")).unwrap();
+        db.add_record(person(7)).unwrap();
+        assert_eq!(db.peek(10).unwrap(), person(7));
+        assert_eq!(db.search(PersonField::PostIndex, 7), Some(vec![7, 10]));
+        assert_eq!(db.search(PersonField::PostIndex, 99), None);
+    }
 
-        let (mut curr, mut next) = (pos, pos + 1);
+    #[test]
+    fn shared_index_gets_each_added_position() {
+        let dir = dir("shared");
+        let path = dir.join("people");
+        fs::File::create(&path).unwrap();
+        let mut db = DataBase::<Person>::new(FileHandler::new(&path)).unwrap();
+        db.add_records((0..4).map(|pos| person(pos % 2))).unwrap();
+        db.index(PersonField::PostIndex).unwrap();
+        let shared = db.share_index().unwrap();
+        let before = shared.snapshot();
 
-        while next < file_len {
-            let next_data = self.file.read::<Crate>(Some(next * STRUCT_SIZE as u64))?;
-            self.file
-                .write(next_data, Some(curr * STRUCT_SIZE as u64))?;
-            curr += 1;
-            next += 1;
-        }
+        db.add_records([person(1), person(1), person(5)]).unwrap();
+        let after = shared.snapshot();
+        let positions = |snapshot: &MvccSnapshot<(u32, u64), (), Natural>, key| {
+            let entries = snapshot.range((key, 0)..=(key, u64::MAX));
+            entries
+                .into_iter()
+                .map(|((_, pos), _)| pos)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(positions(&before, 1), vec![1, 3]);
+        assert_eq!(positions(&after, 1), vec![1, 3, 4, 5]);
+        assert_eq!(positions(&before, 5), vec![]);
+        assert_eq!(positions(&after, 5), vec![6]);
 
-        self.len -= 1;
-        self.file.truncate((file_len - 1) * STRUCT_SIZE as u64)?;
-        self.index = Index::NotIndexed;
+        // Deleting drops the index, and with it every shared position.
+        db.delete_record(0).unwrap();
+        assert_eq!(positions(&shared.snapshot(), 1), vec![]);
+        assert_eq!(positions(&after, 0), vec![0, 2]);
+    }
 
-        Ok(deleted)
+    #[test]
+    fn persisted_hash_index_comes_back_as_one() {
+        let dir = dir("hashed");
+        let path = dir.join("people");
+        fs::File::create(&path).unwrap();
+        let mut db = DataBase::<Person>::new(FileHandler::new(&path)).unwrap();
+        db.add_records((0..6).map(|pos| person(pos % 3))).unwrap();
+        db.hash_index(PersonField::PostIndex).unwrap();
+        db.persist_index(&dir.join("index")).unwrap();
+        db.add_record(person(2)).unwrap();
+        drop(db);
+
+        let mut db = DataBase::<Person>::new(FileHandler::new(&path)).unwrap();
+        db.persist_index(&dir.join("index")).unwrap();
+        assert!(matches!(db.index, Index::Hashed(_)));
+        assert_eq!(db.search_range(PersonField::PostIndex, ..), None);
+        assert_eq!(db.search(PersonField::PostIndex, 2), Some(vec![2, 5, 6]));
+
+        db.checkpoint().unwrap();
+        drop(db);
+        let mut db = DataBase::<Person>::new(FileHandler::new(&path)).unwrap();
+        db.persist_index(&dir.join("index")).unwrap();
+        assert!(matches!(db.index, Index::Hashed(_)));
+        assert_eq!(db.search(PersonField::PostIndex, 0), Some(vec![0, 3]));
+    }
+
+    #[test]
+    fn lsm_index_answers_like_a_tree() {
+        let dir = dir("lsm");
+        let path = dir.join("people");
+        fs::File::create(&path).unwrap();
+        let mut db = DataBase::<Person>::new(FileHandler::new(&path)).unwrap();
+        db.add_records((0..6).map(|pos| person(pos % 3))).unwrap();
+        db.lsm_index(PersonField::PostIndex, &dir.join("runs"))
+            .unwrap();
+        db.add_records((6..9).map(|pos| person(pos % 3))).unwrap();
+
+        assert_eq!(db.search(PersonField::PostIndex, 1), Some(vec![1, 4, 7]));
+        assert_eq!(
+            db.search_range(PersonField::PostIndex, 1..=2),
+            Some(vec![1, 4, 7, 2, 5, 8])
+        );
+        assert_eq!(db.verify_index().unwrap(), vec![]);
+
+        db.clean().unwrap();
+        assert!(matches!(db.index, Index::Lsm(_)));
+        assert_eq!(db.search(PersonField::PostIndex, 1), None);
+    }
+
+    #[test]
+    fn persisted_lsm_index_reopens_its_runs() {
+        let dir = dir("lsm-persisted");
+        let path = dir.join("people");
+        fs::File::create(&path).unwrap();
+        let mut db = DataBase::<Person>::new(FileHandler::new(&path)).unwrap();
+        db.add_records((0..6).map(|pos| person(pos % 3))).unwrap();
+        db.lsm_index(PersonField::PostIndex, &dir.join("runs"))
+            .unwrap();
+        db.persist_index(&dir.join("index")).unwrap();
+        db.add_record(person(1)).unwrap();
+        db.add_record(person(2)).unwrap();
+        drop(db);
+        // The last record never made it to the disk, though its index entry
+        // was flushed.
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(7 * Person::SIZE as u64)
+            .unwrap();
+
+        let mut db = DataBase::<Person>::new(FileHandler::new(&path)).unwrap();
+        db.persist_index(&dir.join("index")).unwrap();
+        assert!(matches!(db.index, Index::Lsm(_)));
+        assert_eq!(db.search(PersonField::PostIndex, 1), Some(vec![1, 4, 6]));
+        assert_eq!(db.search(PersonField::PostIndex, 2), Some(vec![2, 5]));
+        assert_eq!(db.verify_index().unwrap(), vec![]);
+    }
+
+    fn positions<S>(hits: Option<Hits<S>>) -> Option<Vec<u64>> {
+        hits.map(|hits| hits.into_iter().map(|(pos, _)| pos).collect())
+    }
+
+    #[test]
+    fn covering_index_follows_added_and_deleted_records() {
+        let dir = dir("covering");
+        let path = dir.join("people");
+        fs::File::create(&path).unwrap();
+        let mut db = DataBase::<Person>::new(FileHandler::new(&path)).unwrap();
+        db.add_records((0..6).map(|pos| person(pos % 3))).unwrap();
+        db.covering_index(PersonField::PostIndex).unwrap();
+
+        let name = Fixed::from("Ivan");
+        db.add_record(Person::new(name, Fixed::from("Sidorov"), name, 1))
+            .unwrap();
+        db.add_records((7..9).map(|pos| person(pos % 3))).unwrap();
+        assert_eq!(
+            db.search_covered(PersonField::PostIndex, 1),
+            Some(vec![
+                (1, Fixed::from("Petrov")),
+                (4, Fixed::from("Petrov")),
+                (6, Fixed::from("Sidorov")),
+                (7, Fixed::from("Petrov")),
+            ])
+        );
+        assert_eq!(db.search(PersonField::PostIndex, 2), Some(vec![2, 5, 8]));
+        assert_eq!(
+            db.search_range(PersonField::PostIndex, 1..),
+            Some(vec![1, 4, 6, 7, 2, 5, 8])
+        );
+
+        db.delete_record(4).unwrap();
+        assert!(matches!(db.index, Index::Covering(_)));
+        assert_eq!(
+            positions(db.search_covered(PersonField::PostIndex, 1)),
+            Some(vec![1, 5, 6])
+        );
+        assert_eq!(db.verify_index().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn persisted_covering_index_keeps_its_summaries() {
+        let dir = dir("covering-persisted");
+        let path = dir.join("people");
+        fs::File::create(&path).unwrap();
+        let mut db = DataBase::<Person>::new(FileHandler::new(&path)).unwrap();
+        db.add_records((0..6).map(|pos| person(pos % 3))).unwrap();
+        db.covering_index(PersonField::PostIndex).unwrap();
+        db.persist_index(&dir.join("index")).unwrap();
+        db.add_record(person(2)).unwrap();
+        drop(db);
+
+        let mut db = DataBase::<Person>::new(FileHandler::new(&path)).unwrap();
+        db.persist_index(&dir.join("index")).unwrap();
+        assert!(matches!(db.index, Index::Covering(_)));
+        assert_eq!(
+            positions(db.search_covered(PersonField::PostIndex, 2)),
+            Some(vec![2, 5, 6])
+        );
+
+        db.checkpoint().unwrap();
+        drop(db);
+        let mut db = DataBase::<Person>::new(FileHandler::new(&path)).unwrap();
+        db.persist_index(&dir.join("index")).unwrap();
+        assert_eq!(
+            db.search_covered(PersonField::PostIndex, 0),
+            Some(vec![(0, Fixed::from("Petrov")), (3, Fixed::from("Petrov"))])
+        );
     }
 }
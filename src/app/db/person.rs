@@ -1,4 +1,7 @@
+use crate::app::btree::interval::IntervalTree;
 use crate::app::db::fixed_str::Fixed;
+use crate::app::db::record::Record;
+use crate::app::db::record_index::KeyExtractor;
 use serde::{Deserialize, Serialize};
 
 use super::Random;
@@ -22,6 +25,62 @@ impl Person {
             post_index,
         }
     }
+
+    /// Values of every region whose post index range covers this person.
+    pub fn regions<V: Clone>(&self, regions: &IntervalTree<u32, V>) -> Vec<V> {
+        regions
+            .stab(self.post_index)
+            .into_iter()
+            .map(|(_, region)| region)
+            .collect()
+    }
+}
+
+/// Fields a table of [`Person`]s can be indexed by.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum PersonField {
+    PostIndex,
+}
+
+impl KeyExtractor<Person> for PersonField {
+    type Key = u32;
+
+    fn key(&self, person: &Person) -> u32 {
+        match self {
+            PersonField::PostIndex => person.post_index,
+        }
+    }
+}
+
+impl Record for Person {
+    /// Three names of 25 bytes and the post index.
+    const SIZE: usize = 79;
+
+    type Field = PersonField;
+    type Key = u32;
+    type Summary = Fixed;
+
+    fn tag(field: PersonField) -> u64 {
+        match field {
+            PersonField::PostIndex => 0,
+        }
+    }
+
+    fn from_tag(tag: u64) -> Option<PersonField> {
+        match tag {
+            0 => Some(PersonField::PostIndex),
+            _ => None,
+        }
+    }
+
+    fn unpacked(packed: u64, _field: PersonField) -> u32 {
+        packed as u32
+    }
+
+    /// Surname.
+    fn summary(&self) -> Fixed {
+        self.surname
+    }
 }
 
 pub fn choose<T>(vec: &Vec<T>) -> &T {
@@ -0,0 +1,45 @@
+use std::fmt::Debug;
+use std::hash::Hash;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::app::btree::search::PackedKey;
+use crate::app::db::record_index::KeyExtractor;
+
+/// Type a [`DataBase`](super::DataBase) can store. Every record must encode
+/// to exactly `SIZE` bytes; `Field` says what it can be indexed by.
+pub trait Record: Debug + Clone + Serialize + DeserializeOwned {
+    /// Bytes of one encoded record.
+    const SIZE: usize;
+
+    type Field: Debug
+        + Copy
+        + Eq
+        + Serialize
+        + DeserializeOwned
+        + KeyExtractor<Self, Key = Self::Key>;
+    type Key: Debug
+        + Copy
+        + Ord
+        + Hash
+        + PackedKey
+        + Serialize
+        + DeserializeOwned
+        + Send
+        + Sync
+        + 'static;
+    /// Fields a covering index keeps next to each position, so that lists
+    /// of hits are read without touching the file.
+    type Summary: Debug + Clone + Serialize + DeserializeOwned;
+
+    /// Number standing for `field` in index images.
+    fn tag(field: Self::Field) -> u64;
+
+    /// Inverse of `tag`.
+    fn from_tag(tag: u64) -> Option<Self::Field>;
+
+    /// Key of `field` whose `PackedKey::packed` image is `packed`.
+    fn unpacked(packed: u64, field: Self::Field) -> Self::Key;
+
+    fn summary(&self) -> Self::Summary;
+}
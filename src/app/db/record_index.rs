@@ -0,0 +1,529 @@
+use std::borrow::Cow;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::app::btree::{compare::Natural, composite::Prefix, iter::Range, BTree};
+use crate::app::db::record::Record;
+use crate::app::db::DEGREE_OF_TREE;
+use crate::app::hash::LinearHash;
+use crate::app::lsm::Lsm;
+use crate::Error;
+
+/// How to get the index key out of a record of type `T`. Any
+/// `Fn(&T) -> K` is one, e.g. `|data: &Crate| data.receiver.post_index`.
+pub trait KeyExtractor<T> {
+    type Key: Copy + Ord;
+
+    fn key(&self, record: &T) -> Self::Key;
+}
+
+impl<T, K, F> KeyExtractor<T> for F
+where
+    K: Copy + Ord,
+    F: Fn(&T) -> K,
+{
+    type Key = K;
+
+    fn key(&self, record: &T) -> K {
+        self(record)
+    }
+}
+
+/// Fields of a record that a [`CoveringIndex`] keeps next to its position.
+/// Any `Fn(&T) -> O` is one, e.g. `|data: &Crate| data.goods_name`.
+pub trait Projection<T> {
+    type Output: Clone;
+
+    fn project(&self, record: &T) -> Self::Output;
+}
+
+impl<T, O, F> Projection<T> for F
+where
+    O: Clone,
+    F: Fn(&T) -> O,
+{
+    type Output = O;
+
+    fn project(&self, record: &T) -> O {
+        self(record)
+    }
+}
+
+/// Structure a [`RecordIndex`] keeps its positions in, keyed by `K`.
+pub trait IndexEngine<K>: Sized {
+    fn create() -> Result<Self, Error>;
+
+    /// Number of keys. Fails if the engine has to read them from disk and
+    /// cannot.
+    fn len(&self) -> Result<usize, Error>;
+
+    fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Positions under `key`, borrowed from engines that keep them in
+    /// memory. Fails with `KeyWasNotFound` if there are none.
+    fn positions(&self, key: K) -> Result<Cow<'_, [u64]>, Error>;
+
+    fn insert(&mut self, key: K, pos: u64) -> Result<(), Error>;
+
+    /// Stores `pos` under `key`, then runs `f`. If `f` fails, `pos` is taken
+    /// out again.
+    fn insert_then<F, R>(&mut self, key: K, pos: u64, f: F) -> Result<R, Error>
+    where
+        F: FnOnce() -> Result<R, Error>;
+
+    /// Positions under the keys in `range`, in key order. `None` if the
+    /// engine keeps its keys in no order and so cannot tell.
+    fn range_positions<R>(&self, range: R) -> Result<Option<Vec<u64>>, Error>
+    where
+        R: RangeBounds<K>;
+}
+
+impl<K> IndexEngine<K> for BTree<K, Vec<u64>, Natural>
+where
+    K: Copy + Ord,
+{
+    fn create() -> Result<Self, Error> {
+        BTree::with(DEGREE_OF_TREE).ok_or(Error::UnexpectedError)
+    }
+
+    fn len(&self) -> Result<usize, Error> {
+        Ok(BTree::len(self))
+    }
+
+    fn positions(&self, key: K) -> Result<Cow<'_, [u64]>, Error> {
+        self.search(key)
+            .map(|pos_vec| Cow::Borrowed(pos_vec.as_slice()))
+    }
+
+    fn insert(&mut self, key: K, pos: u64) -> Result<(), Error> {
+        match self.search_mut(key) {
+            Ok(pos_vec) => {
+                pos_vec.push(pos);
+                Ok(())
+            }
+            Err(_) => BTree::insert(self, key, vec![pos]),
+        }
+    }
+
+    fn insert_then<F, R>(&mut self, key: K, pos: u64, f: F) -> Result<R, Error>
+    where
+        F: FnOnce() -> Result<R, Error>,
+    {
+        self.transaction(|tx| {
+            if tx.contains(key) {
+                tx.push(key, pos)?;
+            } else {
+                tx.insert(key, vec![pos])?;
+            }
+            f()
+        })
+    }
+
+    fn range_positions<R>(&self, range: R) -> Result<Option<Vec<u64>>, Error>
+    where
+        R: RangeBounds<K>,
+    {
+        Ok(Some(
+            self.range(range)
+                .flat_map(|(_, pos_vec)| pos_vec.iter().copied())
+                .collect(),
+        ))
+    }
+}
+
+impl<K> IndexEngine<K> for LinearHash<K, Vec<u64>>
+where
+    K: Copy + Eq + Hash,
+{
+    fn create() -> Result<Self, Error> {
+        Ok(LinearHash::new())
+    }
+
+    fn len(&self) -> Result<usize, Error> {
+        Ok(LinearHash::len(self))
+    }
+
+    fn positions(&self, key: K) -> Result<Cow<'_, [u64]>, Error> {
+        self.search(&key)
+            .map(|pos_vec| Cow::Borrowed(pos_vec.as_slice()))
+    }
+
+    fn insert(&mut self, key: K, pos: u64) -> Result<(), Error> {
+        match self.search_mut(&key) {
+            Ok(pos_vec) => {
+                pos_vec.push(pos);
+                Ok(())
+            }
+            Err(_) => LinearHash::insert(self, key, vec![pos]),
+        }
+    }
+
+    fn insert_then<F, R>(&mut self, key: K, pos: u64, f: F) -> Result<R, Error>
+    where
+        F: FnOnce() -> Result<R, Error>,
+    {
+        IndexEngine::insert(self, key, pos)?;
+        let result = f();
+        if result.is_err() {
+            if let Ok(pos_vec) = self.search_mut(&key) {
+                pos_vec.pop();
+                if pos_vec.is_empty() {
+                    self.remove(&key)?;
+                }
+            }
+        }
+        result
+    }
+
+    fn range_positions<R>(&self, _range: R) -> Result<Option<Vec<u64>>, Error>
+    where
+        R: RangeBounds<K>,
+    {
+        Ok(None)
+    }
+}
+
+/// Positions live in the key, so inserts are blind writes. `create` fails, as
+/// the runs need a directory: use `Lsm::open` and `RecordIndex::with`.
+impl<K> IndexEngine<K> for Lsm<(K, u64), ()>
+where
+    K: Copy + Ord + Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    fn create() -> Result<Self, Error> {
+        Err(Error::UnexpectedError)
+    }
+
+    /// Reads every run.
+    fn len(&self) -> Result<usize, Error> {
+        let mut len = 0;
+        let mut last = None;
+        for entry in self.range(..)? {
+            let ((key, _), _) = entry?;
+            if last != Some(key) {
+                len += 1;
+                last = Some(key);
+            }
+        }
+        Ok(len)
+    }
+
+    fn positions(&self, key: K) -> Result<Cow<'_, [u64]>, Error> {
+        let pos_vec: Vec<u64> = self
+            .range((key, u64::MIN)..=(key, u64::MAX))?
+            .map(|entry| entry.map(|((_, pos), _)| pos))
+            .collect::<Result<_, _>>()?;
+        if pos_vec.is_empty() {
+            Err(Error::KeyWasNotFound)
+        } else {
+            Ok(Cow::Owned(pos_vec))
+        }
+    }
+
+    fn insert(&mut self, key: K, pos: u64) -> Result<(), Error> {
+        self.put((key, pos), ())
+    }
+
+    fn insert_then<F, R>(&mut self, key: K, pos: u64, f: F) -> Result<R, Error>
+    where
+        F: FnOnce() -> Result<R, Error>,
+    {
+        self.put((key, pos), ())?;
+        let result = f();
+        if result.is_err() {
+            self.delete((key, pos))?;
+        }
+        result
+    }
+
+    fn range_positions<R>(&self, range: R) -> Result<Option<Vec<u64>>, Error>
+    where
+        R: RangeBounds<K>,
+    {
+        let start = match range.start_bound() {
+            Bound::Included(key) => Bound::Included((*key, u64::MIN)),
+            Bound::Excluded(key) => Bound::Excluded((*key, u64::MAX)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => Bound::Included((*key, u64::MAX)),
+            Bound::Excluded(key) => Bound::Excluded((*key, u64::MIN)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        self.range((start, end))?
+            .map(|entry| entry.map(|((_, pos), _)| pos))
+            .collect::<Result<_, _>>()
+            .map(Some)
+    }
+}
+
+/// Positions of the records of a file, grouped under the key `E` extracts
+/// from each of them and kept in the engine `I`, a [`BTree`] by default.
+#[derive(Debug, Clone)]
+pub struct RecordIndex<T, E, I = BTree<<E as KeyExtractor<T>>::Key, Vec<u64>, Natural>>
+where
+    E: KeyExtractor<T>,
+{
+    engine: I,
+    extractor: E,
+    record: PhantomData<T>,
+}
+
+/// [`RecordIndex`] kept in a hash table: point lookups only.
+pub type HashIndex<T, E> = RecordIndex<T, E, LinearHash<<E as KeyExtractor<T>>::Key, Vec<u64>>>;
+
+/// [`RecordIndex`] kept in an [`Lsm`], for ingest faster than the disk
+/// can take random writes.
+pub type LsmIndex<T, E> = RecordIndex<T, E, Lsm<(<E as KeyExtractor<T>>::Key, u64), ()>>;
+
+#[allow(dead_code)]
+impl<T, E, I> RecordIndex<T, E, I>
+where
+    E: KeyExtractor<T>,
+    I: IndexEngine<E::Key>,
+{
+    pub fn new(extractor: E) -> Result<Self, Error> {
+        Ok(Self::with(extractor, I::create()?))
+    }
+
+    /// Index kept in `engine`, which may already hold positions.
+    pub fn with(extractor: E, engine: I) -> Self {
+        RecordIndex {
+            engine,
+            extractor,
+            record: PhantomData,
+        }
+    }
+
+    pub fn extractor(&self) -> &E {
+        &self.extractor
+    }
+
+    pub fn engine(&self) -> &I {
+        &self.engine
+    }
+
+    pub fn engine_mut(&mut self) -> &mut I {
+        &mut self.engine
+    }
+
+    pub fn len(&self) -> Result<usize, Error> {
+        self.engine.len()
+    }
+
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        self.engine.is_empty()
+    }
+
+    pub fn key(&self, record: &T) -> E::Key {
+        self.extractor.key(record)
+    }
+
+    pub fn positions(&self, key: E::Key) -> Result<Cow<'_, [u64]>, Error> {
+        self.engine.positions(key)
+    }
+
+    /// Positions under the keys in `range`, in key order. `None` if the
+    /// engine cannot serve ranges.
+    pub fn range_positions<R>(&self, range: R) -> Result<Option<Vec<u64>>, Error>
+    where
+        R: RangeBounds<E::Key>,
+    {
+        self.engine.range_positions(range)
+    }
+
+    pub fn insert(&mut self, key: E::Key, pos: u64) -> Result<(), Error> {
+        self.engine.insert(key, pos)
+    }
+
+    /// Stores `pos` under the key of `record`.
+    pub fn add(&mut self, record: &T, pos: u64) -> Result<(), Error> {
+        self.insert(self.key(record), pos)
+    }
+
+    /// Stores `pos` under `key`, then runs `f`, taking `pos` out again if `f`
+    /// fails.
+    pub fn insert_then<F, R>(&mut self, key: E::Key, pos: u64, f: F) -> Result<R, Error>
+    where
+        F: FnOnce() -> Result<R, Error>,
+    {
+        self.engine.insert_then(key, pos, f)
+    }
+}
+
+#[allow(dead_code)]
+impl<T, E> RecordIndex<T, E>
+where
+    E: KeyExtractor<T>,
+{
+    pub fn tree(&self) -> &BTree<E::Key, Vec<u64>, Natural> {
+        &self.engine
+    }
+
+    /// Keys in `range` with their positions, in key order.
+    pub fn range<R>(&self, range: R) -> Range<'_, E::Key, Vec<u64>, Natural>
+    where
+        R: RangeBounds<E::Key>,
+    {
+        self.engine.range(range)
+    }
+
+    /// Keys starting with `prefix` with their positions, for indexes keyed
+    /// by several fields.
+    pub fn prefix<P>(&self, prefix: P) -> Range<'_, E::Key, Vec<u64>, Natural>
+    where
+        P: Copy,
+        E::Key: Prefix<P>,
+    {
+        self.engine.prefix(prefix)
+    }
+}
+
+/// Positions under one key of a [`CoveringIndex`], each with the projection
+/// of its record.
+pub type Hits<O> = Vec<(u64, O)>;
+
+/// Index that keeps what `P` projects out of each record next to its
+/// position.
+#[derive(Debug, Clone)]
+pub struct CoveringIndex<T, E, P>
+where
+    E: KeyExtractor<T>,
+    P: Projection<T>,
+{
+    tree: BTree<E::Key, Hits<P::Output>, Natural>,
+    extractor: E,
+    projection: P,
+    record: PhantomData<T>,
+}
+
+/// [`CoveringIndex`] keeping the [`Record::summary`] of each record, the
+/// kind a `DataBase` keeps up to date itself.
+pub type SummaryIndex<T, E> = CoveringIndex<T, E, fn(&T) -> <T as Record>::Summary>;
+
+#[allow(dead_code)]
+impl<T, E, P> CoveringIndex<T, E, P>
+where
+    E: KeyExtractor<T>,
+    P: Projection<T>,
+{
+    pub fn new(extractor: E, projection: P) -> Result<Self, Error> {
+        Ok(CoveringIndex {
+            tree: BTree::with(DEGREE_OF_TREE).ok_or(Error::UnexpectedError)?,
+            extractor,
+            projection,
+            record: PhantomData,
+        })
+    }
+
+    pub fn tree(&self) -> &BTree<E::Key, Hits<P::Output>, Natural> {
+        &self.tree
+    }
+
+    pub fn extractor(&self) -> &E {
+        &self.extractor
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Positions under `key` with what was projected out of their records.
+    pub fn hits(&self, key: E::Key) -> Option<&[(u64, P::Output)]> {
+        self.tree.search(key).ok().map(Vec::as_slice)
+    }
+
+    /// Number of records under `key`.
+    pub fn count(&self, key: E::Key) -> usize {
+        self.hits(key).map_or(0, <[_]>::len)
+    }
+
+    /// Keys in `range` with their hits, in key order.
+    pub fn range<R>(&self, range: R) -> Range<'_, E::Key, Hits<P::Output>, Natural>
+    where
+        R: RangeBounds<E::Key>,
+    {
+        self.tree.range(range)
+    }
+
+    /// Positions under the keys in `range`, in key order.
+    pub fn range_positions<R>(&self, range: R) -> Vec<u64>
+    where
+        R: RangeBounds<E::Key>,
+    {
+        self.range(range)
+            .flat_map(|(_, hits)| hits.iter().map(|(pos, _)| *pos))
+            .collect()
+    }
+
+    /// Stores `pos` with `output` under `key`.
+    pub fn insert(&mut self, key: E::Key, pos: u64, output: P::Output) -> Result<(), Error> {
+        match self.tree.search_mut(key) {
+            Ok(hits) => {
+                hits.push((pos, output));
+                Ok(())
+            }
+            Err(_) => self.tree.insert(key, vec![(pos, output)]),
+        }
+    }
+
+    /// Stores `pos` with the projection of `record` under its key.
+    pub fn add(&mut self, record: &T, pos: u64) -> Result<(), Error> {
+        self.insert(
+            self.extractor.key(record),
+            pos,
+            self.projection.project(record),
+        )
+    }
+
+    /// Stores `pos` like `add`, then runs `f`, taking `pos` out again if `f`
+    /// fails.
+    pub fn add_then<F, R>(&mut self, record: &T, pos: u64, f: F) -> Result<R, Error>
+    where
+        F: FnOnce() -> Result<R, Error>,
+    {
+        let (key, hit) = (
+            self.extractor.key(record),
+            (pos, self.projection.project(record)),
+        );
+        self.tree.transaction(|tx| {
+            if tx.contains(key) {
+                tx.push(key, hit)?;
+            } else {
+                tx.insert(key, vec![hit])?;
+            }
+            f()
+        })
+    }
+
+    /// Takes out the hit at `pos` and moves every later one down by one, as
+    /// deleting that record from the file does.
+    pub fn delete(&mut self, pos: u64) -> Result<(), Error> {
+        let mut tree = BTree::with(DEGREE_OF_TREE).ok_or(Error::UnexpectedError)?;
+        for (key, hits) in self.tree.iter() {
+            let hits: Hits<P::Output> = hits
+                .iter()
+                .filter(|(at, _)| *at != pos)
+                .map(|(at, output)| (if *at > pos { at - 1 } else { *at }, output.clone()))
+                .collect();
+            if !hits.is_empty() {
+                tree.insert(*key, hits)?;
+            }
+        }
+        self.tree = tree;
+        Ok(())
+    }
+
+    pub fn clear(&mut self) -> Result<(), Error> {
+        self.tree = BTree::with(DEGREE_OF_TREE).ok_or(Error::UnexpectedError)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use bincode::{deserialize, serialize};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::Error;
+
+/// Bytes in front of every framed payload: its length and its CRC-32, both
+/// little-endian `u32`.
+const HEADER_SIZE: usize = 8;
+
+/// Append-only log of records, each framed with its length and checksum so
+/// that a write torn by a crash is recognised and dropped on replay.
+#[derive(Debug)]
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+}
+
+#[allow(dead_code)]
+impl Wal {
+    /// Opens the log at `path`, creating an empty one if there is none.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        Ok(Wal {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes `record` and waits for the disk. Returns the length the log had
+    /// before, for `cut`.
+    pub fn append<T: Serialize>(&mut self, record: &T) -> Result<u64, Error> {
+        self.append_all(std::slice::from_ref(record))
+    }
+
+    /// Like `append`, but waits for the disk once for all of `records`.
+    pub fn append_all<T: Serialize>(&mut self, records: &[T]) -> Result<u64, Error> {
+        let mut frames = vec![];
+        for record in records {
+            frames.extend(frame(record)?);
+        }
+        let len = self.file.metadata()?.len();
+        self.file.write_all(&frames)?;
+        self.file.sync_data()?;
+        Ok(len)
+    }
+
+    /// Drops every record appended since the log was `len` bytes long, e.g.
+    /// one whose change could not be applied after all.
+    pub fn cut(&mut self, len: u64) -> Result<(), Error> {
+        self.file.set_len(len)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Every intact record in order, cutting off the first torn or corrupt frame
+    /// and everything after it.
+    pub fn replay<T: DeserializeOwned>(&mut self) -> Result<Vec<T>, Error> {
+        let mut bytes = vec![];
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut bytes)?;
+
+        let mut records = vec![];
+        let mut at = 0;
+        while let Some((record, size)) = unframe(&bytes[at..]) {
+            records.push(record);
+            at += size;
+        }
+
+        if at < bytes.len() {
+            self.file.set_len(at as u64)?;
+            self.file.sync_data()?;
+        }
+        Ok(records)
+    }
+
+    /// Drops every record, e.g. once they are covered by a snapshot.
+    pub fn truncate(&mut self) -> Result<(), Error> {
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Replaces the snapshot at `path` with `data` by renaming a synced copy
+/// over it.
+pub fn write_snapshot<T: Serialize>(path: &Path, data: &T) -> Result<(), Error> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(&frame(data)?)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// The snapshot at `path`, or `None` if there is none yet.
+pub fn read_snapshot<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let bytes = fs::read(path)?;
+    match unframe(&bytes) {
+        Some((data, size)) if size == bytes.len() => Ok(Some(data)),
+        _ => Err(Error::ErrorDeserializing),
+    }
+}
+
+fn frame<T: Serialize>(record: &T) -> Result<Vec<u8>, Error> {
+    let payload = serialize(record).or(Err(Error::ErrorSerializing))?;
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Decodes the frame at the start of `bytes` and returns it together with
+/// its size, or `None` if it is incomplete or fails its checksum.
+fn unframe<T: DeserializeOwned>(bytes: &[u8]) -> Option<(T, usize)> {
+    let len = u32::from_le_bytes(bytes.get(0..4)?.try_into().ok()?) as usize;
+    let crc = u32::from_le_bytes(bytes.get(4..HEADER_SIZE)?.try_into().ok()?);
+    let payload = bytes.get(HEADER_SIZE..HEADER_SIZE + len)?;
+    if crc32fast::hash(payload) != crc {
+        return None;
+    }
+    Some((deserialize(payload).ok()?, HEADER_SIZE + len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lab-wal-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn replay_returns_records_in_order() {
+        let path = dir("order").join("log");
+        let mut wal = Wal::open(&path).unwrap();
+        wal.append(&(1u64, 10u64)).unwrap();
+        wal.append_all(&[(2u64, 20u64), (3, 30)]).unwrap();
+        drop(wal);
+
+        let mut wal = Wal::open(&path).unwrap();
+        let records: Vec<(u64, u64)> = wal.replay().unwrap();
+        assert_eq!(records, vec![(1, 10), (2, 20), (3, 30)]);
+    }
+
+    #[test]
+    fn replay_cuts_torn_tail() {
+        let path = dir("torn").join("log");
+        let mut wal = Wal::open(&path).unwrap();
+        wal.append(&7u64).unwrap();
+        let len = wal.append(&8u64).unwrap();
+        drop(wal);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+        drop(file);
+
+        let mut wal = Wal::open(&path).unwrap();
+        assert_eq!(wal.replay::<u64>().unwrap(), vec![7, 8]);
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            len + (HEADER_SIZE + 8) as u64
+        );
+    }
+
+    #[test]
+    fn replay_stops_at_corrupt_frame() {
+        let path = dir("corrupt").join("log");
+        let mut wal = Wal::open(&path).unwrap();
+        wal.append(&1u64).unwrap();
+        let second = wal.append(&2u64).unwrap();
+        wal.append(&3u64).unwrap();
+        drop(wal);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[second as usize + HEADER_SIZE] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+
+        let mut wal = Wal::open(&path).unwrap();
+        assert_eq!(wal.replay::<u64>().unwrap(), vec![1]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), second);
+    }
+
+    #[test]
+    fn cut_drops_records_appended_since() {
+        let path = dir("cut").join("log");
+        let mut wal = Wal::open(&path).unwrap();
+        wal.append(&1u64).unwrap();
+        let len = wal.append_all(&[2u64, 3]).unwrap();
+        wal.cut(len).unwrap();
+        wal.append(&4u64).unwrap();
+        assert_eq!(wal.replay::<u64>().unwrap(), vec![1, 4]);
+
+        wal.truncate().unwrap();
+        assert!(wal.replay::<u64>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let path = dir("snapshot").join("snapshot");
+        assert_eq!(read_snapshot::<Vec<u64>>(&path).unwrap(), None);
+        write_snapshot(&path, &vec![1u64, 2, 3]).unwrap();
+        write_snapshot(&path, &vec![4u64]).unwrap();
+        assert_eq!(read_snapshot::<Vec<u64>>(&path).unwrap(), Some(vec![4]));
+
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(read_snapshot::<Vec<u64>>(&path).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use std::f64::consts::LN_2;
+use std::hash::{Hash, Hasher};
+
+use crate::app::hash::Fnv;
+
+/// Set that answers "maybe" or "certainly not". Items cannot be taken out.
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    /// Number of bits in use.
+    len: u64,
+    hashes: u32,
+}
+
+#[allow(dead_code)]
+impl BloomFilter {
+    /// Filter that says "maybe" for a missing item with probability `rate` when
+    /// holding `expected` items. `None` unless `0 < rate < 1`.
+    pub fn with_rate(expected: usize, rate: f64) -> Option<Self> {
+        if !(rate > 0. && rate < 1.) {
+            return None;
+        }
+        let expected = expected.max(1) as f64;
+        let len = (-expected * rate.ln() / (LN_2 * LN_2)).ceil().max(64.) as u64;
+        let hashes = ((len as f64 / expected) * LN_2).round().max(1.) as u32;
+
+        Some(BloomFilter {
+            bits: vec![0; len.div_ceil(64) as usize],
+            len,
+            hashes,
+        })
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.len
+    }
+
+    pub fn hashes(&self) -> u32 {
+        self.hashes
+    }
+
+    pub fn insert<T: Hash>(&mut self, item: &T) {
+        for bit in self.positions(item) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// `false` only if `item` was never inserted.
+    pub fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.positions(item)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    pub fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|word| *word = 0);
+    }
+
+    /// Bits of `item`, from two hashes combined as `h1 + i * h2`.
+    fn positions<T: Hash>(&self, item: &T) -> impl Iterator<Item = u64> {
+        let mut hasher = Fnv::default();
+        item.hash(&mut hasher);
+        let h1 = hasher.finish();
+        hasher.write_u8(0xff);
+        let h2 = hasher.finish() | 1;
+
+        let len = self.len;
+        (0..self.hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn never_turns_down_an_inserted_item() {
+        let mut filter = BloomFilter::with_rate(1000, 0.01).unwrap();
+        for item in 0..1000u64 {
+            filter.insert(&item);
+        }
+        assert!((0..1000u64).all(|item| filter.contains(&item)));
+
+        filter.clear();
+        assert!(!(0..1000u64).any(|item| filter.contains(&item)));
+    }
+
+    #[test]
+    fn false_positives_stay_near_the_rate() {
+        let mut filter = BloomFilter::with_rate(10_000, 0.01).unwrap();
+        for item in 0..10_000u64 {
+            filter.insert(&item);
+        }
+        let false_positives = (10_000..110_000u64)
+            .filter(|item| filter.contains(item))
+            .count();
+        assert!(false_positives < 2_000, "{}", false_positives);
+    }
+
+    #[test]
+    fn rejects_rates_outside_zero_to_one() {
+        assert!(BloomFilter::with_rate(10, 0.).is_none());
+        assert!(BloomFilter::with_rate(10, 1.).is_none());
+        assert!(BloomFilter::with_rate(10, f64::NAN).is_none());
+        assert!(BloomFilter::with_rate(0, 0.5).is_some());
+    }
+}
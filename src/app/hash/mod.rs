@@ -0,0 +1,241 @@
+pub mod bloom;
+
+use std::hash::{Hash, Hasher};
+
+use crate::Error;
+
+/// Buckets a table starts with unless told otherwise.
+const DEFAULT_BUCKETS: usize = 16;
+/// Average entries per bucket above which the next bucket is split.
+const MAX_LOAD: usize = 4;
+
+/// 64-bit FNV-1a. Unlike `DefaultHasher` its output is fixed, so hashes can
+/// be stored and compared across runs and machines.
+#[derive(Debug, Clone, Copy)]
+pub struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// Hash table grown by linear hashing, one bucket split at a time, so no
+/// insert rehashes the whole table. No range queries.
+#[derive(Debug, Clone)]
+pub struct LinearHash<K, V> {
+    buckets: Vec<Vec<(K, V)>>,
+    /// Buckets the table started with.
+    initial: usize,
+    /// Number of times every bucket of the table has been split.
+    level: u32,
+    /// Next bucket to split.
+    split: usize,
+    len: usize,
+}
+
+#[allow(dead_code)]
+impl<K, V> LinearHash<K, V>
+where
+    K: Eq + Hash,
+{
+    pub fn new() -> Self {
+        Self::with(DEFAULT_BUCKETS).unwrap()
+    }
+
+    /// Table that starts with `buckets` buckets. `None` for zero.
+    pub fn with(buckets: usize) -> Option<Self> {
+        if buckets == 0 {
+            return None;
+        }
+        Some(LinearHash {
+            buckets: (0..buckets).map(|_| vec![]).collect(),
+            initial: buckets,
+            level: 0,
+            split: 0,
+            len: 0,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn buckets(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn search(&self, key: &K) -> Result<&V, Error> {
+        self.buckets[self.bucket_of(key)]
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value)
+            .ok_or(Error::KeyWasNotFound)
+    }
+
+    pub fn search_mut(&mut self, key: &K) -> Result<&mut V, Error> {
+        let bucket = self.bucket_of(key);
+        self.buckets[bucket]
+            .iter_mut()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value)
+            .ok_or(Error::KeyWasNotFound)
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.search(key).is_ok()
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Result<(), Error> {
+        let bucket = self.bucket_of(&key);
+        if self.buckets[bucket].iter().any(|(k, _)| *k == key) {
+            return Err(Error::KeyAlreadyExists);
+        }
+        self.buckets[bucket].push((key, value));
+        self.len += 1;
+
+        if self.len > self.buckets.len() * MAX_LOAD {
+            self.split_next();
+        }
+        Ok(())
+    }
+
+    /// Takes `key` out. Buckets are never merged back.
+    pub fn remove(&mut self, key: &K) -> Result<V, Error> {
+        let bucket = self.bucket_of(key);
+        let index = self.buckets[bucket]
+            .iter()
+            .position(|(k, _)| k == key)
+            .ok_or(Error::KeyWasNotFound)?;
+        self.len -= 1;
+        Ok(self.buckets[bucket].swap_remove(index).1)
+    }
+
+    /// Every entry, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.buckets
+            .iter()
+            .flatten()
+            .map(|(key, value)| (key, value))
+    }
+
+    fn hash(key: &K) -> usize {
+        let mut hasher = Fnv::default();
+        key.hash(&mut hasher);
+        hasher.finish() as usize
+    }
+
+    /// Buckets as of the start of the current level.
+    fn round(&self) -> usize {
+        self.initial << self.level
+    }
+
+    fn bucket_of(&self, key: &K) -> usize {
+        let hash = Self::hash(key);
+        let bucket = hash % self.round();
+        // Buckets before `split` were already split this round and share
+        // their entries with the ones past the end of the round.
+        if bucket < self.split {
+            hash % (self.round() * 2)
+        } else {
+            bucket
+        }
+    }
+
+    /// Splits bucket `split` in two by one more bit of the hash.
+    fn split_next(&mut self) {
+        let next = self.round() * 2;
+        let entries = std::mem::take(&mut self.buckets[self.split]);
+        self.buckets.push(vec![]);
+        for (key, value) in entries {
+            let bucket = Self::hash(&key) % next;
+            self.buckets[bucket].push((key, value));
+        }
+
+        self.split += 1;
+        if self.split == self.round() {
+            self.level += 1;
+            self.split = 0;
+        }
+    }
+}
+
+impl<K, V> Default for LinearHash<K, V>
+where
+    K: Eq + Hash,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn behaves_like_a_hash_map() {
+        let mut table = LinearHash::with(2).unwrap();
+        let mut model = HashMap::new();
+        for i in 0..20_000u64 {
+            let key = i.wrapping_mul(0x9e37_79b9_7f4a_7c15) % 5_000;
+            if i % 3 == 0 {
+                assert_eq!(table.remove(&key).ok(), model.remove(&key));
+            } else {
+                assert_eq!(table.insert(key, i).is_ok(), !model.contains_key(&key));
+                model.entry(key).or_insert(i);
+            }
+        }
+
+        assert_eq!(table.len(), model.len());
+        for key in 0..5_000 {
+            assert_eq!(table.search(&key).ok(), model.get(&key));
+        }
+        let mut entries: Vec<(u64, u64)> = table.iter().map(|(k, v)| (*k, *v)).collect();
+        entries.sort_unstable();
+        let mut expected: Vec<(u64, u64)> = model.into_iter().collect();
+        expected.sort_unstable();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn splits_one_bucket_at_a_time() {
+        let mut table = LinearHash::with(4).unwrap();
+        let mut buckets = table.buckets();
+        for key in 0..1_000u32 {
+            table.insert(key, ()).unwrap();
+            assert!(table.buckets() - buckets <= 1);
+            assert!(table.len() <= table.buckets() * MAX_LOAD);
+            buckets = table.buckets();
+        }
+        assert!((0..1_000).all(|key| table.contains(&key)));
+    }
+
+    #[test]
+    fn search_mut_changes_the_stored_value() {
+        let mut table = LinearHash::new();
+        table.insert("key", vec![1]).unwrap();
+        table.search_mut(&"key").unwrap().push(2);
+        assert_eq!(table.search(&"key").ok(), Some(&vec![1, 2]));
+        assert!(table.search_mut(&"other").is_err());
+        assert!(LinearHash::<u32, ()>::with(0).is_none());
+    }
+}
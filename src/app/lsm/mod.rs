@@ -0,0 +1,401 @@
+mod run;
+
+use std::fs;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::app::btree::{compare::Natural, composite::Prefix, BTree};
+use crate::app::db::DEGREE_OF_TREE;
+use crate::Error;
+use run::{Merge, Run, Sources};
+
+/// Entries the memtable takes before it is flushed, unless told otherwise.
+const DEFAULT_MEMTABLE: usize = 64 * 1024;
+/// Runs on disk that start a compaction.
+const COMPACT_AT: usize = 4;
+
+type Runs<K, V> = Arc<Mutex<Vec<Arc<Run<K, V>>>>>;
+
+/// Log-structured merge tree: writes go to an in-memory [`BTree`] flushed as
+/// sorted run files into `dir`, which a background thread merges once enough
+/// pile up. The memtable is flushed on drop.
+#[derive(Debug)]
+pub struct Lsm<K, V>
+where
+    K: Copy + Ord + Serialize + DeserializeOwned + Send + Sync + 'static,
+    V: Clone + Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    dir: PathBuf,
+    /// `None` marks a deleted key that may still be in a run.
+    memtable: BTree<K, Option<V>, Natural>,
+    memtable_limit: usize,
+    /// Oldest first.
+    runs: Runs<K, V>,
+    next_flush: u64,
+    compaction: Option<JoinHandle<Result<(), Error>>>,
+}
+
+#[allow(dead_code)]
+impl<K, V> Lsm<K, V>
+where
+    K: Copy + Ord + Serialize + DeserializeOwned + Send + Sync + 'static,
+    V: Clone + Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    pub fn open(dir: &Path) -> Result<Self, Error> {
+        Self::with(dir, DEFAULT_MEMTABLE)
+    }
+
+    /// Opens the tree in `dir` with a memtable of `memtable_limit` entries,
+    /// deleting runs a compaction left behind.
+    pub fn with(dir: &Path, memtable_limit: usize) -> Result<Self, Error> {
+        fs::create_dir_all(dir)?;
+
+        let mut found = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            match Run::<K, V>::covers(&path) {
+                Some((first, last)) => found.push((first, last, path)),
+                None if path.extension().is_some_and(|ext| ext == "tmp") => fs::remove_file(&path)?,
+                None => {}
+            }
+        }
+        // A merged run covers a span of flushes that any run left over from
+        // before the merge falls inside of.
+        found.sort_by_key(|(first, last, _)| (*first, std::cmp::Reverse(*last)));
+
+        let mut runs: Vec<Arc<Run<K, V>>> = vec![];
+        for (first, _, path) in found {
+            if runs.last().is_some_and(|run| first <= run.last()) {
+                fs::remove_file(&path)?;
+            } else {
+                runs.push(Arc::new(Run::open(&path)?));
+            }
+        }
+
+        Ok(Lsm {
+            dir: dir.to_path_buf(),
+            memtable: BTree::with(DEGREE_OF_TREE).ok_or(Error::UnexpectedError)?,
+            memtable_limit,
+            next_flush: runs.last().map_or(0, |run| run.last() + 1),
+            runs: Arc::new(Mutex::new(runs)),
+            compaction: None,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn runs(&self) -> usize {
+        self.snapshot().len()
+    }
+
+    pub fn put(&mut self, key: K, value: V) -> Result<(), Error> {
+        self.write(key, Some(value))
+    }
+
+    pub fn delete(&mut self, key: K) -> Result<(), Error> {
+        self.write(key, None)
+    }
+
+    pub fn get(&self, key: K) -> Result<Option<V>, Error> {
+        if let Ok(value) = self.memtable.search(key) {
+            return Ok(value.clone());
+        }
+        for run in self.snapshot().iter().rev() {
+            if let Some(value) = run.get(key)? {
+                return Ok(value);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Entries with keys in `range`, in key order, newer runs taking
+    /// precedence. The memtable and runs are merged as entries are asked for.
+    pub fn range<'a, R>(
+        &'a self,
+        range: R,
+    ) -> Result<impl Iterator<Item = Result<(K, V), Error>> + 'a, Error>
+    where
+        R: RangeBounds<K> + Clone + 'a,
+    {
+        let mut sources: Sources<'a, K, V> = vec![];
+        for run in self.snapshot() {
+            sources.push(Box::new(run.range(range.clone())?));
+        }
+        sources.push(Box::new(
+            self.memtable
+                .range(range)
+                .map(|(key, value)| Ok((*key, value.clone()))),
+        ));
+
+        Ok(Merge::new(sources)
+            .filter_map(|entry| entry.map(|(key, value)| Some((key, value?))).transpose()))
+    }
+
+    /// Entries whose composite key starts with `prefix`.
+    pub fn prefix<'a, P>(
+        &'a self,
+        prefix: P,
+    ) -> Result<impl Iterator<Item = Result<(K, V), Error>> + 'a, Error>
+    where
+        P: Copy,
+        K: Prefix<P>,
+    {
+        self.range(K::first(prefix)..=K::last(prefix))
+    }
+
+    /// Writes the memtable out as a new run and starts a compaction if
+    /// enough runs piled up and none is running.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+
+        // The memtable is only dropped once its run is on disk.
+        let run = Run::write(
+            &self.dir,
+            self.next_flush,
+            self.next_flush,
+            self.memtable
+                .iter()
+                .map(|(key, value)| Ok((*key, value.clone()))),
+        )?;
+        self.memtable = BTree::with(DEGREE_OF_TREE).ok_or(Error::UnexpectedError)?;
+        self.next_flush += 1;
+        self.runs
+            .lock()
+            .or(Err(Error::UnexpectedError))?
+            .push(Arc::new(run));
+
+        self.compact()
+    }
+
+    /// Drops every entry. Runs are deleted once no reader holds them.
+    pub fn clear(&mut self) -> Result<(), Error> {
+        // A failed compaction left its inputs alone, and they go anyway.
+        let _ = self.wait();
+        self.memtable = BTree::with(DEGREE_OF_TREE).ok_or(Error::UnexpectedError)?;
+        for run in self.runs.lock().or(Err(Error::UnexpectedError))?.drain(..) {
+            run.retire();
+        }
+        Ok(())
+    }
+
+    /// Waits for the running compaction, if any.
+    pub fn wait(&mut self) -> Result<(), Error> {
+        match self.compaction.take() {
+            Some(handle) => handle.join().or(Err(Error::UnexpectedError))?,
+            None => Ok(()),
+        }
+    }
+
+    fn write(&mut self, key: K, value: Option<V>) -> Result<(), Error> {
+        match self.memtable.search_mut(key) {
+            Ok(current) => *current = value,
+            Err(_) => self.memtable.insert(key, value)?,
+        }
+        if self.memtable.len() >= self.memtable_limit {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Vec<Arc<Run<K, V>>> {
+        self.runs
+            .lock()
+            .map(|runs| runs.clone())
+            .unwrap_or_default()
+    }
+
+    /// Merges every run there is into one on a background thread. Runs
+    /// flushed in the meantime are left alone.
+    fn compact(&mut self) -> Result<(), Error> {
+        if self
+            .compaction
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished())
+        {
+            return Ok(());
+        }
+        self.wait()?;
+
+        let inputs = self.snapshot();
+        if inputs.len() < COMPACT_AT {
+            return Ok(());
+        }
+
+        let (dir, runs) = (self.dir.clone(), Arc::clone(&self.runs));
+        self.compaction = Some(thread::spawn(move || {
+            let merged = Arc::new(run::merge(&dir, &inputs, true)?);
+            let mut runs = runs.lock().or(Err(Error::UnexpectedError))?;
+            // Only compactions take runs out and one runs at a time, so the
+            // inputs are still the oldest runs.
+            for run in runs.drain(..inputs.len()) {
+                run.retire();
+            }
+            runs.insert(0, merged);
+            Ok(())
+        }));
+        Ok(())
+    }
+}
+
+impl<K, V> Drop for Lsm<K, V>
+where
+    K: Copy + Ord + Serialize + DeserializeOwned + Send + Sync + 'static,
+    V: Clone + Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    /// Flushes the memtable and waits for the compaction. Errors cannot be
+    /// reported from here: call `flush` and `wait` first to see them.
+    fn drop(&mut self) {
+        let _ = self.flush();
+        let _ = self.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::collections::BTreeMap;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lab-lsm-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn reopening_finds_flushed_entries() {
+        let dir = dir("reopen");
+        {
+            let mut lsm: Lsm<u64, u64> = Lsm::with(&dir, 10).unwrap();
+            for key in 0..100 {
+                lsm.put(key, key * 2).unwrap();
+            }
+            for key in (0..100).step_by(3) {
+                lsm.delete(key).unwrap();
+            }
+            lsm.flush().unwrap();
+        }
+
+        let lsm: Lsm<u64, u64> = Lsm::with(&dir, 10).unwrap();
+        assert_eq!(files(&dir).len(), lsm.runs());
+        for key in 0..100 {
+            let expected = (key % 3 != 0).then_some(key * 2);
+            assert_eq!(lsm.get(key).unwrap(), expected);
+        }
+        assert_eq!(lsm.range(..).unwrap().count(), 66);
+    }
+
+    #[test]
+    fn dropping_flushes_the_memtable() {
+        let dir = dir("drop");
+        {
+            let mut lsm: Lsm<u64, u64> = Lsm::with(&dir, 1_000).unwrap();
+            for key in 0..10 {
+                lsm.put(key, key).unwrap();
+            }
+            assert_eq!(lsm.runs(), 0);
+        }
+
+        let lsm: Lsm<u64, u64> = Lsm::with(&dir, 1_000).unwrap();
+        assert_eq!(lsm.runs(), 1);
+        assert_eq!(lsm.get(7).unwrap(), Some(7));
+    }
+
+    #[test]
+    fn range_matches_a_map_across_runs() {
+        let mut rng = StdRng::seed_from_u64(19);
+        let dir = dir("range");
+        let mut lsm: Lsm<u64, u64> = Lsm::with(&dir, 50).unwrap();
+        let mut model = BTreeMap::new();
+        for round in 0..3_000 {
+            let key = rng.gen_range(0..500);
+            if rng.gen_bool(0.7) {
+                lsm.put(key, round).unwrap();
+                model.insert(key, round);
+            } else {
+                lsm.delete(key).unwrap();
+                model.remove(&key);
+            }
+        }
+
+        for _ in 0..50 {
+            let lo = rng.gen_range(0..520);
+            let hi = lo + rng.gen_range(0..100);
+            let found: Vec<_> = lsm.range(lo..hi).unwrap().map(Result::unwrap).collect();
+            let expected: Vec<_> = model.range(lo..hi).map(|(k, v)| (*k, *v)).collect();
+            assert_eq!(found, expected);
+        }
+        let all: Vec<_> = lsm.range(..).unwrap().map(Result::unwrap).collect();
+        assert_eq!(all, model.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reopening_drops_leftovers_of_a_compaction() {
+        let dir = dir("leftovers");
+        {
+            let mut lsm: Lsm<u64, u64> = Lsm::with(&dir, 10).unwrap();
+            for key in 0..50 {
+                lsm.put(key, key).unwrap();
+            }
+            lsm.wait().unwrap();
+        }
+        // A run the compaction merged but did not get to remove, and a merge
+        // that never finished.
+        let merged = Run::<u64, u64>::path_of(&dir, 1, 1);
+        fs::write(&merged, b"stale").unwrap();
+        fs::write(dir.join("run-x.tmp"), b"torn").unwrap();
+
+        let lsm: Lsm<u64, u64> = Lsm::with(&dir, 10).unwrap();
+        assert!(!merged.exists());
+        assert!(files(&dir).iter().all(|name| name.ends_with(".lsm")));
+        for key in 0..50 {
+            assert_eq!(lsm.get(key).unwrap(), Some(key));
+        }
+    }
+
+    #[test]
+    fn failed_compaction_leaves_runs_alone() {
+        let dir = dir("failed");
+        let mut lsm: Lsm<u64, u64> = Lsm::with(&dir, 10).unwrap();
+        for key in 0..30 {
+            lsm.put(key, key).unwrap();
+        }
+        let before = files(&dir);
+        fs::OpenOptions::new()
+            .write(true)
+            .open(Run::<u64, u64>::path_of(&dir, 0, 0))
+            .unwrap()
+            .set_len(5)
+            .unwrap();
+
+        for key in 30..40 {
+            lsm.put(key, key).unwrap();
+        }
+        assert!(lsm.wait().is_err());
+        let mut after = before.clone();
+        after.push(
+            Run::<u64, u64>::path_of(Path::new(""), 3, 3)
+                .display()
+                .to_string(),
+        );
+        assert_eq!(files(&dir), after);
+        assert_eq!(lsm.runs(), 4);
+    }
+}
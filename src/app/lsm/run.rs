@@ -0,0 +1,354 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Seek, SeekFrom};
+use std::iter::Peekable;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use bincode::{deserialize_from, serialize_into, serialized_size};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::Error;
+
+/// Entries between two keys of the sparse index.
+const BLOCK: usize = 64;
+
+/// Immutable sorted file of entries, `None` marking a deleted key, with every
+/// `BLOCK`-th key kept in memory. Covers the flushes `first..=last`.
+#[derive(Debug)]
+pub(crate) struct Run<K, V> {
+    path: PathBuf,
+    first: u64,
+    last: u64,
+    len: usize,
+    sparse: Vec<(K, u64)>,
+    /// Set once the run is merged away; its file goes with the last reader.
+    obsolete: AtomicBool,
+    value: PhantomData<V>,
+}
+
+#[allow(dead_code)]
+impl<K, V> Run<K, V>
+where
+    K: Copy + Ord + Serialize + DeserializeOwned,
+    V: Clone + Serialize + DeserializeOwned,
+{
+    pub(crate) fn path_of(dir: &Path, first: u64, last: u64) -> PathBuf {
+        dir.join(format!("run-{:020}-{:020}.lsm", first, last))
+    }
+
+    /// The flushes covered by the run at `path`, from its name.
+    pub(crate) fn covers(path: &Path) -> Option<(u64, u64)> {
+        let name = path.file_name()?.to_str()?;
+        let (first, last) = name
+            .strip_prefix("run-")?
+            .strip_suffix(".lsm")?
+            .split_once('-')?;
+        Some((first.parse().ok()?, last.parse().ok()?))
+    }
+
+    /// Writes sorted `entries` as the run covering `first..=last`. The file
+    /// shows up only once complete.
+    pub(crate) fn write<I>(dir: &Path, first: u64, last: u64, entries: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = Result<(K, Option<V>), Error>>,
+    {
+        let path = Self::path_of(dir, first, last);
+        let tmp = path.with_extension("tmp");
+        let written = Self::write_tmp(&tmp, entries);
+        let (len, sparse) = match written {
+            Ok(written) => written,
+            Err(error) => {
+                let _ = fs::remove_file(&tmp);
+                return Err(error);
+            }
+        };
+        fs::rename(&tmp, &path)?;
+
+        Ok(Run {
+            path,
+            first,
+            last,
+            len,
+            sparse,
+            obsolete: AtomicBool::new(false),
+            value: PhantomData,
+        })
+    }
+
+    /// Writes `entries` to `tmp` and waits for the disk. Returns how many
+    /// there were and the sparse index over them.
+    fn write_tmp<I>(tmp: &Path, entries: I) -> Result<(usize, Vec<(K, u64)>), Error>
+    where
+        I: IntoIterator<Item = Result<(K, Option<V>), Error>>,
+    {
+        let mut out = BufWriter::new(File::create(tmp)?);
+        let (mut len, mut offset, mut sparse) = (0, 0, vec![]);
+        for entry in entries {
+            let entry = entry?;
+            if len % BLOCK == 0 {
+                sparse.push((entry.0, offset));
+            }
+            offset += serialized_size(&entry).or(Err(Error::ErrorSerializing))?;
+            serialize_into(&mut out, &entry).or(Err(Error::ErrorSerializing))?;
+            len += 1;
+        }
+
+        let file = out.into_inner().or(Err(Error::UnexpectedError))?;
+        file.sync_all()?;
+        Ok((len, sparse))
+    }
+
+    /// Loads the sparse index of the run at `path`.
+    pub(crate) fn open(path: &Path) -> Result<Self, Error> {
+        let (first, last) = Self::covers(path).ok_or(Error::ErrorDeserializing)?;
+        let size = fs::metadata(path)?.len();
+        let mut input = BufReader::new(File::open(path)?);
+
+        let (mut len, mut offset, mut sparse) = (0, 0, vec![]);
+        while offset < size {
+            let entry: (K, Option<V>) =
+                deserialize_from(&mut input).or(Err(Error::ErrorDeserializing))?;
+            if len % BLOCK == 0 {
+                sparse.push((entry.0, offset));
+            }
+            offset += serialized_size(&entry).or(Err(Error::ErrorSerializing))?;
+            len += 1;
+        }
+
+        Ok(Run {
+            path: path.to_path_buf(),
+            first,
+            last,
+            len,
+            sparse,
+            obsolete: AtomicBool::new(false),
+            value: PhantomData,
+        })
+    }
+
+    pub(crate) fn first(&self) -> u64 {
+        self.first
+    }
+
+    pub(crate) fn last(&self) -> u64 {
+        self.last
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Deletes the file once nobody reads the run anymore.
+    pub(crate) fn retire(&self) {
+        self.obsolete.store(true, Ordering::SeqCst);
+    }
+
+    /// `Some(None)` if the run records `key` as deleted.
+    pub(crate) fn get(&self, key: K) -> Result<Option<Option<V>>, Error> {
+        match self.range(key..=key)?.next() {
+            Some(entry) => entry.map(|(_, value)| Some(value)),
+            None => Ok(None),
+        }
+    }
+
+    /// Entries with keys in `range`, in key order, read from the file as
+    /// they are asked for.
+    pub(crate) fn range<R>(&self, range: R) -> Result<RunRange<K, V, R>, Error>
+    where
+        R: RangeBounds<K>,
+    {
+        let block = match range.start_bound() {
+            Bound::Included(start) | Bound::Excluded(start) => self
+                .sparse
+                .partition_point(|(key, _)| key <= start)
+                .saturating_sub(1),
+            Bound::Unbounded => 0,
+        };
+        let mut input = BufReader::new(File::open(&self.path)?);
+        let left = match self.sparse.get(block) {
+            Some((_, offset)) => {
+                input.seek(SeekFrom::Start(*offset))?;
+                self.len - block * BLOCK
+            }
+            None => 0,
+        };
+        Ok(RunRange {
+            entries: RunEntries {
+                input,
+                left,
+                entry: PhantomData,
+            },
+            range,
+        })
+    }
+
+    /// Every entry, in key order.
+    pub(crate) fn entries(&self) -> Result<RunEntries<K, V>, Error> {
+        Ok(RunEntries {
+            input: BufReader::new(File::open(&self.path)?),
+            left: self.len,
+            entry: PhantomData,
+        })
+    }
+}
+
+impl<K, V> Drop for Run<K, V> {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::SeqCst) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Whether `key` is not past the end of `range`.
+fn before_end<K: Ord, R: RangeBounds<K>>(range: &R, key: &K) -> bool {
+    match range.end_bound() {
+        Bound::Included(end) => key <= end,
+        Bound::Excluded(end) => key < end,
+        Bound::Unbounded => true,
+    }
+}
+
+/// Entries of a run read front to back. Created by `Run::entries`.
+pub(crate) struct RunEntries<K, V> {
+    input: BufReader<File>,
+    left: usize,
+    entry: PhantomData<(K, V)>,
+}
+
+impl<K, V> Iterator for RunEntries<K, V>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+{
+    type Item = Result<(K, Option<V>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+        Some(deserialize_from(&mut self.input).or(Err(Error::ErrorDeserializing)))
+    }
+}
+
+/// Entries of a run with keys in a range. Created by `Run::range`.
+pub(crate) struct RunRange<K, V, R> {
+    entries: RunEntries<K, V>,
+    range: R,
+}
+
+impl<K, V, R> Iterator for RunRange<K, V, R>
+where
+    K: Ord + DeserializeOwned,
+    V: DeserializeOwned,
+    R: RangeBounds<K>,
+{
+    type Item = Result<(K, Option<V>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, value) = match self.entries.next()? {
+                Ok(entry) => entry,
+                Err(error) => {
+                    self.entries.left = 0;
+                    return Some(Err(error));
+                }
+            };
+            if self.range.contains(&key) {
+                return Some(Ok((key, value)));
+            }
+            if !before_end(&self.range, &key) {
+                self.entries.left = 0;
+                return None;
+            }
+        }
+    }
+}
+
+/// Sorted entries to be merged, `None` marking a deleted key.
+pub(crate) type Source<'a, K, V> = Box<dyn Iterator<Item = Result<(K, Option<V>), Error>> + 'a>;
+
+/// Sources to merge, oldest first.
+pub(crate) type Sources<'a, K, V> = Vec<Source<'a, K, V>>;
+
+/// Entries of several sorted sources in key order, the newest source's
+/// entry winning for keys in more than one. Stops after the first error.
+pub(crate) struct Merge<'a, K, V> {
+    inputs: Vec<Peekable<Source<'a, K, V>>>,
+    failed: bool,
+}
+
+impl<'a, K, V> Merge<'a, K, V> {
+    pub(crate) fn new(sources: Sources<'a, K, V>) -> Self {
+        Merge {
+            inputs: sources.into_iter().map(Iterator::peekable).collect(),
+            failed: false,
+        }
+    }
+}
+
+impl<K, V> Iterator for Merge<'_, K, V>
+where
+    K: Copy + Ord,
+{
+    type Item = Result<(K, Option<V>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        // Smallest key; on ties the newest source, which comes last, wins.
+        let mut next: Option<(usize, K)> = None;
+        for (i, input) in self.inputs.iter_mut().enumerate() {
+            match input.peek() {
+                Some(Ok((key, _))) if next.is_none_or(|(_, min)| *key <= min) => {
+                    next = Some((i, *key))
+                }
+                Some(Err(_)) => {
+                    self.failed = true;
+                    return input.next();
+                }
+                _ => {}
+            }
+        }
+        let (newest, key) = next?;
+
+        let mut value = None;
+        for (i, input) in self.inputs.iter_mut().enumerate() {
+            if matches!(input.peek(), Some(Ok((k, _))) if *k == key) {
+                if let Some(Ok((_, v))) = input.next() {
+                    if i == newest {
+                        value = Some(v);
+                    }
+                }
+            }
+        }
+        value.map(|value| Ok((key, value)))
+    }
+}
+
+/// Merges `runs`, oldest first, into one run keeping the newest entry of each
+/// key. Deletions are dropped too if `oldest`. Nothing is left behind on error.
+pub(crate) fn merge<K, V>(
+    dir: &Path,
+    runs: &[Arc<Run<K, V>>],
+    oldest: bool,
+) -> Result<Run<K, V>, Error>
+where
+    K: Copy + Ord + Serialize + DeserializeOwned,
+    V: Clone + Serialize + DeserializeOwned,
+{
+    let mut sources: Sources<'_, K, V> = vec![];
+    for run in runs {
+        sources.push(Box::new(run.entries()?));
+    }
+    let merged = Merge::new(sources).filter(|entry| !(oldest && matches!(entry, Ok((_, None)))));
+
+    let first = runs.first().map_or(0, |run| run.first());
+    let last = runs.last().map_or(0, |run| run.last());
+    Run::write(dir, first, last, merged)
+}
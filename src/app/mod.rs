@@ -1,5 +1,7 @@
-mod btree;
+pub mod btree;
 pub mod db;
+pub mod hash;
+pub mod lsm;
 
 pub use db::{
     goods::{Crate, Person},
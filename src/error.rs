@@ -12,6 +12,7 @@ pub enum Error {
     ErrorDeserializing,
     ErrorSerializing,
     OutOfBounds,
+    KeyOutOfOrder,
 }
 
 impl std::convert::From<std::io::Error> for Error {
@@ -1,9 +1,7 @@
 use eframe::epaint::Vec2;
+use lab::app::*;
 use lab::error::Error;
 
-mod app;
-use app::*;
-
 fn main() -> Result<(), Error> {
     let mut native_options = eframe::NativeOptions::default();
     native_options.initial_window_size = Some(Vec2::new(800., 600.));